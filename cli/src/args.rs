@@ -1,16 +1,27 @@
 // Copyright (C) 2024 Daniel Mueller <deso@posteo.net>
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+use std::ffi::OsString;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 
 use clap::ArgAction;
 use clap::Parser;
+use clap::Subcommand;
+use clap::ValueEnum;
 
 
 /// A program for sending emails.
+///
+/// When invoked as `sendmail` or with `--sendmail` as the first
+/// argument, a sendmail(8) compatible command line interface is
+/// provided instead, reading the message including its headers from
+/// standard input and honoring the `-t`, `-i`, `-f`, and `-F` options.
 #[derive(Debug, Parser)]
-#[clap(version = env!("VERSION"))]
+#[clap(version = env!("VERSION"), args_conflicts_with_subcommands = true)]
 pub(crate) struct Args {
+  #[command(subcommand)]
+  pub command: Option<Command>,
   /// The message to send.
   ///
   /// If not specified it will be read from standard input.
@@ -24,10 +35,200 @@ pub(crate) struct Args {
   /// See https://www.iana.org/assignments/media-types/media-types.xhtml
   #[clap(long)]
   pub content_type: Option<String>,
-  /// The path to the configuration file.
-  #[clap(short, long)]
+  /// A recipient to send the email to instead of the configured ones
+  /// (can be supplied multiple times).
+  ///
+  /// Either an email address or the name of an alias or recipient group
+  /// defined in the configuration.
+  #[clap(short, long, value_name = "RECIPIENT", conflicts_with = "raw")]
+  pub to: Vec<String>,
+  /// Send the message as-is, treating it as a complete RFC 5322 message
+  /// including its headers.
+  ///
+  /// Recipients are taken from the message's 'To', 'Cc', and 'Bcc'
+  /// headers. Filters are not applied and the message is not queued if
+  /// it could not be sent.
+  #[clap(long, conflicts_with_all = ["subject", "content_type"])]
+  pub raw: bool,
+  /// Do not send the email, but print it as it would be sent or, if a
+  /// path is provided, write it to the given file.
+  ///
+  /// Filters are applied and the email is built as usual, including
+  /// PGP encryption, using the first configured account.
+  #[clap(long, value_name = "FILE", num_args = 0..=1, require_equals = true, default_missing_value = "-")]
+  pub dry_run: Option<PathBuf>,
+  /// The path to a configuration file.
+  ///
+  /// The file is layered on top of the system-wide and the user's
+  /// configuration, taking the place of the one referenced by the
+  /// `MAILY_CONFIG` environment variable. Attributes can further be
+  /// overridden via `MAILY_*` environment variables.
+  ///
+  /// Files ending in `.toml` or `.yaml` are parsed as TOML or YAML,
+  /// respectively, all others as JSON.
+  #[clap(short, long, global = true)]
   pub config: Option<PathBuf>,
+  /// The name of the configuration profile to use.
+  ///
+  /// A profile overrides the recipients, accounts, PGP settings, subject
+  /// prefix, and filters of the configuration. If not provided, the
+  /// profile named by the `MAILY_PROFILE` environment variable is used,
+  /// if set.
+  #[clap(short, long, global = true)]
+  pub profile: Option<String>,
   /// Increase verbosity (can be supplied multiple times).
   #[clap(short = 'v', long = "verbose", global = true, action = ArgAction::Count, default_value = None)]
   pub verbosity: u8,
 }
+
+
+/// Parse file permissions in octal notation.
+fn parse_mode(mode: &str) -> Result<u32, String> {
+  u32::from_str_radix(mode, 8)
+    .ok()
+    .filter(|mode| *mode <= 0o777)
+    .ok_or_else(|| format!("`{mode}` is not a valid octal file mode"))
+}
+
+
+/// A command to run instead of sending an email.
+#[derive(Debug, Subcommand)]
+pub(crate) enum Command {
+  /// Manage the queue of emails awaiting delivery.
+  #[command(subcommand)]
+  Queue(QueueCommand),
+  /// Run as a daemon, periodically attempting to send queued emails
+  /// and, optionally, accepting emails submitted via a Unix domain
+  /// socket.
+  ///
+  /// The configuration is reloaded on SIGHUP. On SIGTERM or SIGINT the
+  /// daemon shuts down once in-flight deliveries concluded.
+  Daemon {
+    /// The number of seconds to wait between two runs over the queue.
+    #[clap(short, long, default_value_t = 60)]
+    interval: u64,
+    /// The path to a Unix domain socket to accept email submissions
+    /// on.
+    #[clap(long)]
+    socket: Option<PathBuf>,
+    /// The permissions (in octal) to create the submission socket with.
+    #[clap(long, default_value = "660", value_parser = parse_mode)]
+    socket_mode: u32,
+  },
+  /// Manage the configuration.
+  #[command(subcommand)]
+  Config(ConfigCommand),
+  /// Check the configuration for problems.
+  ///
+  /// All email addresses are parsed and, if PGP encryption is
+  /// configured, the keybox is checked for usable encryption keys for
+  /// each recipient. The result is reported per account.
+  Check {
+    /// Also connect (and authenticate) to each account, without
+    /// sending anything.
+    #[clap(long)]
+    connect: bool,
+  },
+  /// Accept emails via SMTP and forward them through the configured
+  /// accounts.
+  ///
+  /// Envelope recipients without a domain (e.g., `root`) are replaced
+  /// with the configured default recipients. If the configuration
+  /// contains relay credentials, clients have to authenticate before
+  /// submitting emails.
+  Relay {
+    /// The address to listen on.
+    #[clap(short, long, default_value = "127.0.0.1:25")]
+    listen: SocketAddr,
+  },
+  /// Run a command and send a report about its execution.
+  ///
+  /// The command's output is captured and included in the report, which
+  /// is only sent under the requested condition. The program exits with
+  /// the command's exit status.
+  Exec {
+    /// The condition under which to send a report.
+    #[clap(long, value_enum, default_value_t = ReportCondition::Failure)]
+    on: ReportCondition,
+    /// The subject to use for the report instead of a generated one.
+    #[clap(short, long)]
+    subject: Option<String>,
+    /// The command to run, along with its arguments.
+    #[clap(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+    command: Vec<OsString>,
+  },
+}
+
+
+/// The condition under which to send a report about a command's
+/// execution.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub(crate) enum ReportCondition {
+  /// Send a report if the command exited with a non-zero status or got
+  /// terminated by a signal.
+  Failure,
+  /// Always send a report.
+  Always,
+  /// Send a report if the command produced any output.
+  Output,
+}
+
+
+/// A command operating on the configuration.
+#[derive(Debug, Subcommand)]
+pub(crate) enum ConfigCommand {
+  /// Interactively create a configuration file.
+  ///
+  /// The file is written to the path provided via `--config` or the
+  /// system-wide configuration path and is only accessible by the
+  /// current user. Its format (JSON, TOML, or YAML) is chosen based on
+  /// the file's extension.
+  Init {
+    /// Overwrite an existing configuration file.
+    #[clap(short, long)]
+    force: bool,
+    /// Do not test the entered accounts by connecting to them.
+    #[clap(long)]
+    no_check: bool,
+  },
+  /// Migrate a configuration file to the current schema version.
+  ///
+  /// The file provided via `--config` or the system-wide configuration
+  /// file is rewritten in place, retaining attributes unknown to the
+  /// program as well as the order of attributes. Included files and
+  /// fragments are not migrated.
+  Migrate {
+    /// Print the migrated configuration instead of writing it back.
+    #[clap(long)]
+    dry_run: bool,
+    /// Rewrite TOML and YAML files, even though comments contained in
+    /// them are lost.
+    #[clap(short, long)]
+    force: bool,
+  },
+}
+
+
+/// A command operating on the queue of emails awaiting delivery.
+#[derive(Debug, Subcommand)]
+pub(crate) enum QueueCommand {
+  /// List all queued emails.
+  List,
+  /// Attempt to send queued emails that are due.
+  Flush {
+    /// Attempt to send all queued emails, not just those that are due.
+    #[clap(short, long)]
+    force: bool,
+  },
+  /// Show the metadata and message of a queued email.
+  Inspect {
+    /// The ID of the email to show.
+    id: String,
+  },
+  /// Delete queued emails.
+  Delete {
+    /// The IDs of the emails to delete.
+    #[clap(required = true)]
+    ids: Vec<String>,
+  },
+}
@@ -0,0 +1,119 @@
+// Copyright (C) 2024 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::path::Path;
+
+use anyhow::ensure;
+use anyhow::Result;
+
+use maily::ValidationReport;
+
+use crate::config::profile_filters;
+use crate::config::Config;
+
+
+/// Format the per-account results of a validation as a table.
+fn format_table(report: &ValidationReport) -> String {
+  let rows = report
+    .accounts
+    .iter()
+    .map(|account| {
+      let status = if !account.errors.is_empty() {
+        account
+          .errors
+          .iter()
+          .map(|err| format!("{err:#}"))
+          .collect::<Vec<_>>()
+          .join("; ")
+      } else if account.connected {
+        "ok".to_string()
+      } else {
+        "valid".to_string()
+      };
+      [account.from.clone(), account.target.clone(), status]
+    })
+    .collect::<Vec<_>>();
+
+  let header = ["ACCOUNT", "TARGET", "STATUS"].map(str::to_string);
+  let widths = rows.iter().chain([&header]).fold([0; 2], |widths, row| {
+    [widths[0].max(row[0].len()), widths[1].max(row[1].len())]
+  });
+
+  [&header]
+    .into_iter()
+    .chain(&rows)
+    .map(|[account, target, status]| {
+      format!(
+        "{account:<width0$}  {target:<width1$}  {status}\n",
+        width0 = widths[0],
+        width1 = widths[1]
+      )
+    })
+    .collect()
+}
+
+
+/// Validate the configuration and print a report about it.
+pub(crate) async fn run(connect: bool, path: &Path, config: Config) -> Result<()> {
+  let Config { maily, .. } = config;
+  let mut report = maily.validate(connect).await;
+  // Filters provided by profiles are not known to the library and so
+  // we have to validate them ourselves.
+  for (name, profile) in &maily.profiles {
+    if let Err(err) = profile_filters(profile) {
+      let () = report
+        .errors
+        .push(err.context(format!("profile `{name}` is invalid")));
+    }
+  }
+
+  if !report.accounts.is_empty() {
+    print!("{}", format_table(&report));
+  }
+  for err in &report.errors {
+    eprintln!("{err:#}");
+  }
+
+  let problems = report.errors.len()
+    + report
+      .accounts
+      .iter()
+      .map(|account| account.errors.len())
+      .sum::<usize>();
+  ensure!(
+    report.is_ok(),
+    "found {problems} problem(s) in configuration `{}`",
+    path.display()
+  );
+  Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use serde_json::from_str as from_json;
+
+
+  /// Check that validation results are formatted as expected.
+  #[tokio::test]
+  async fn table_formatting() {
+    let config = r#"{
+      "accounts": [
+        {"mailbox": "/", "mailbox_format": "maildir", "from": "Host <host@example.com>"},
+        {"mailbox": "/", "mailbox_format": "mbox", "from": "invalid"}
+      ],
+      "recipients": ["admin@example.com"]
+    }"#;
+    let config = from_json::<maily::Config>(config).unwrap();
+    let report = config.validate(false).await;
+
+    let expected = "\
+ACCOUNT                  TARGET     STATUS
+Host <host@example.com>  maildir:/  valid
+invalid                  mbox:/     failed to parse 'From' specification: `invalid`: Invalid input
+";
+    assert_eq!(format_table(&report), expected);
+  }
+}
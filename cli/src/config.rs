@@ -1,22 +1,72 @@
 // Copyright (C) 2024 Daniel Mueller <deso@posteo.net>
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+use anyhow::Context as _;
+use anyhow::Result;
+
 use serde::Deserialize;
+use serde::Serialize;
 
 
 /// The program's configuration.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub(crate) struct Config {
   #[serde(flatten)]
   pub maily: maily::Config,
   /// The filters to use when sending an email.
-  #[serde(default)]
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
   pub filters: Vec<Filter>,
+  /// The credentials clients of the SMTP relay have to authenticate
+  /// with. If not present, no authentication is required.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub relay: Option<RelayConfig>,
+}
+
+impl Config {
+  /// Override attributes of the configuration with those of the
+  /// profile with the given name.
+  ///
+  /// All profiles are removed afterwards, as they refer to the
+  /// configuration as it was before.
+  pub fn apply_profile(&mut self, name: &str) -> Result<()> {
+    let profile = self
+      .maily
+      .profiles
+      .remove(name)
+      .with_context(|| format!("profile `{name}` is not defined"))?;
+    let () = self.maily.profiles.clear();
+    let () = self.maily.apply_profile(&profile)?;
+    if let Some(filters) = profile_filters(&profile)? {
+      self.filters = filters;
+    }
+    Ok(())
+  }
+}
+
+
+/// Retrieve the filters to use instead of the configured ones from a
+/// profile, if it provides any.
+pub(crate) fn profile_filters(profile: &maily::Profile) -> Result<Option<Vec<Filter>>> {
+  profile
+    .extensions
+    .get("filters")
+    .map(|filters| Vec::<Filter>::deserialize(filters).context("invalid `filters` attribute"))
+    .transpose()
+}
+
+
+/// The configuration of the SMTP relay.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct RelayConfig {
+  /// The user clients have to log in as.
+  pub user: String,
+  /// The password clients have to log in with.
+  pub password: String,
 }
 
 
 /// A "filter" for an email.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub(crate) struct Filter {
   /// The command to use for filtering emails.
   pub command: String,
@@ -29,3 +79,40 @@ impl From<Filter> for (String, Vec<String>) {
     (filter.command, filter.args)
   }
 }
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use serde_json::from_str as from_json;
+
+
+  /// Check that profiles can override the configured filters.
+  #[test]
+  fn profile_application() {
+    let config = r#"{
+      "accounts": [],
+      "recipients": ["admin@example.com"],
+      "filters": [{"command": "cat", "args": []}],
+      "profiles": {
+        "plain": {"recipients": ["oncall@example.com"]},
+        "filtered": {"filters": [{"command": "tr", "args": ["a", "b"]}]}
+      }
+    }"#;
+    let mut config = from_json::<Config>(config).unwrap();
+    assert_eq!(config.maily.profiles.len(), 2);
+
+    let () = config.apply_profile("filtered").unwrap();
+    assert_eq!(config.filters.len(), 1);
+    assert_eq!(config.filters[0].command, "tr");
+    assert!(config.maily.profiles.is_empty());
+
+    let mut config = from_json::<Config>(
+      r#"{"accounts": [], "recipients": [], "profiles": {"broken": {"filters": "tr"}}}"#,
+    )
+    .unwrap();
+    let err = config.apply_profile("broken").unwrap_err();
+    assert_eq!(err.to_string(), "invalid `filters` attribute");
+  }
+}
@@ -0,0 +1,319 @@
+// Copyright (C) 2024 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::fs::remove_dir;
+use std::fs::remove_file;
+use std::fs::rename;
+use std::fs::set_permissions;
+use std::fs::DirBuilder;
+use std::fs::Permissions;
+use std::future::pending;
+use std::io::ErrorKind;
+use std::os::unix::fs::DirBuilderExt as _;
+use std::os::unix::fs::FileTypeExt as _;
+use std::os::unix::fs::PermissionsExt as _;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::ensure;
+use anyhow::Context as _;
+use anyhow::Result;
+
+use maily::send_email;
+use maily::Account;
+use maily::EmailOpts;
+use maily::Queue;
+use maily::Submission;
+use maily::SubmissionRequest;
+
+use tokio::net::UnixListener;
+use tokio::net::UnixStream;
+use tokio::select;
+use tokio::signal::unix::signal;
+use tokio::signal::unix::SignalKind;
+use tokio::task::JoinSet;
+use tokio::time::interval as interval_timer;
+use tokio::time::MissedTickBehavior;
+
+use tracing::debug;
+use tracing::error;
+use tracing::info;
+use tracing::warn;
+
+use crate::config::Config;
+use crate::config::Filter;
+use crate::load_config;
+use crate::util::pipeline;
+
+
+/// The state the daemon derives from the configuration.
+struct Runner {
+  queue: Option<Queue>,
+  accounts: Vec<Account<'static>>,
+  recipients: Vec<String>,
+  opts: EmailOpts<'static>,
+  filters: Vec<(String, Vec<String>)>,
+}
+
+impl Runner {
+  fn new(path: &Path, config: Config, submissions: bool) -> Result<Self> {
+    let Config {
+      maily,
+      filters,
+      relay: _,
+    } = config;
+    ensure!(
+      !maily.accounts.is_empty(),
+      "no email accounts configured in `{}`",
+      path.display()
+    );
+    let queue = maily.queue.as_deref().map(Queue::new);
+    ensure!(
+      queue.is_some() || submissions,
+      "no queue configured in `{}`",
+      path.display()
+    );
+    let (accounts, recipients, opts) = maily.into_inputs()?;
+
+    let slf = Self {
+      queue,
+      accounts,
+      recipients,
+      opts,
+      filters: filters.into_iter().map(Filter::into).collect(),
+    };
+    Ok(slf)
+  }
+
+  async fn load(config: Option<&Path>, profile: Option<&str>, submissions: bool) -> Result<Self> {
+    let (path, config) = load_config(config.map(Path::to_path_buf), profile).await?;
+    Self::new(&path, config, submissions)
+  }
+
+  /// Attempt to send all due emails in the queue.
+  async fn drain(&self) {
+    let Some(queue) = &self.queue else { return };
+
+    match queue.flush(&self.accounts, &self.opts, false).await {
+      Ok(report) => {
+        for id in report.sent {
+          info!("sent queued email {id}");
+        }
+        for id in report.expired {
+          warn!("dropped expired email {id}");
+        }
+        for (id, err) in report.failed {
+          warn!("failed to send queued email {id}: {err:#}");
+        }
+      },
+      Err(err) => error!("failed to process queue: {err:#}"),
+    }
+  }
+
+  /// Send a submitted email.
+  async fn send(&self, submission: &Submission<'_>) -> Result<()> {
+    let Submission {
+      subject,
+      body,
+      content_type,
+      recipients,
+      attachments,
+    } = submission;
+
+    let message = pipeline(body.as_bytes(), self.filters.iter().cloned())
+      .await
+      .context("failed to apply filters to message")?;
+
+    let recipients = if recipients.is_empty() {
+      self.recipients.iter().map(String::as_str).collect::<Vec<_>>()
+    } else {
+      recipients.iter().map(AsRef::as_ref).collect::<Vec<_>>()
+    };
+    let opts = EmailOpts {
+      attachments: attachments.clone(),
+      ..self.opts.clone()
+    };
+
+    send_email(
+      self.accounts.iter(),
+      subject,
+      &message,
+      content_type.as_deref(),
+      recipients.iter(),
+      &opts,
+    )
+    .await
+  }
+}
+
+
+/// Handle a client connected to the submission socket.
+async fn handle(runner: Arc<Runner>, stream: UnixStream) {
+  let request = match SubmissionRequest::receive(stream).await {
+    Ok(request) => request,
+    Err(err) => {
+      warn!("{err:#}");
+      return
+    },
+  };
+
+  let subject = &request.submission.subject;
+  let result = runner.send(&request.submission).await;
+  match &result {
+    Ok(()) => info!(subject = %subject, "sent submitted email"),
+    Err(err) => warn!(subject = %subject, "failed to send submitted email: {err:#}"),
+  }
+
+  if let Err(err) = request.reply(&result).await {
+    warn!("{err:#}");
+  }
+}
+
+
+/// Create a listener for submissions on the socket at `path`.
+fn listen(path: &Path, mode: u32) -> Result<UnixListener> {
+  // Remove a stale socket left behind by a previous instance, but be
+  // careful not to remove anything else.
+  match path.symlink_metadata() {
+    Ok(metadata) if metadata.file_type().is_socket() => {
+      let () = remove_file(path)
+        .with_context(|| format!("failed to remove stale socket `{}`", path.display()))?;
+    },
+    Ok(_) => (),
+    Err(err) if err.kind() == ErrorKind::NotFound => (),
+    Err(err) => {
+      return Err(err).with_context(|| format!("failed to stat `{}`", path.display()))
+    },
+  }
+
+  // The socket is created with permissions as per the umask. To
+  // prevent others from connecting before we adjusted them, we bind
+  // inside a directory only accessible by us and move the socket into
+  // place afterwards.
+  let name = path
+    .file_name()
+    .with_context(|| format!("`{}` is not a valid socket path", path.display()))?;
+  let dir = path.with_file_name(format!(
+    ".{}.{}",
+    name.to_string_lossy(),
+    process::id()
+  ));
+  let () = DirBuilder::new()
+    .mode(0o700)
+    .create(&dir)
+    .with_context(|| format!("failed to create directory `{}`", dir.display()))?;
+
+  let tmp = dir.join("socket");
+  let result = UnixListener::bind(&tmp)
+    .with_context(|| format!("failed to bind to `{}`", tmp.display()))
+    .and_then(|listener| {
+      let () = set_permissions(&tmp, Permissions::from_mode(mode))
+        .with_context(|| format!("failed to set permissions of `{}`", tmp.display()))?;
+      let () = rename(&tmp, path)
+        .with_context(|| format!("failed to move `{}` to `{}`", tmp.display(), path.display()))?;
+      Ok(listener)
+    });
+  let _result = remove_file(&tmp);
+  let _result = remove_dir(&dir);
+  result
+}
+
+
+/// Run the daemon until asked to terminate.
+pub(crate) async fn run(
+  config: Option<PathBuf>,
+  profile: Option<&str>,
+  interval: Duration,
+  socket: Option<PathBuf>,
+  socket_mode: u32,
+) -> Result<()> {
+  let mut hangup = signal(SignalKind::hangup()).context("failed to register SIGHUP handler")?;
+  let mut terminate =
+    signal(SignalKind::terminate()).context("failed to register SIGTERM handler")?;
+  let mut interrupt =
+    signal(SignalKind::interrupt()).context("failed to register SIGINT handler")?;
+
+  let submissions = socket.is_some();
+  let mut runner = Arc::new(Runner::load(config.as_deref(), profile, submissions).await?);
+  let listener = socket
+    .as_deref()
+    .map(|socket| listen(socket, socket_mode))
+    .transpose()?;
+  let mut tasks = JoinSet::new();
+  // The queue is drained in a task of its own, so that we can accept
+  // submissions and react to signals in the meantime.
+  let mut drains = JoinSet::new();
+  let mut timer = interval_timer(interval);
+  let () = timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+  if let Some(queue) = &runner.queue {
+    info!("processing queue `{}`", queue.path().display());
+  }
+  if let Some(socket) = &socket {
+    info!("accepting submissions on `{}`", socket.display());
+  }
+
+  loop {
+    let accept = async {
+      match &listener {
+        Some(listener) => listener.accept().await,
+        None => pending().await,
+      }
+    };
+
+    select! {
+      biased;
+
+      _ = terminate.recv() => break,
+      _ = interrupt.recv() => break,
+      _ = hangup.recv() => {
+        match Runner::load(config.as_deref(), profile, submissions).await {
+          Ok(new) => {
+            runner = Arc::new(new);
+            info!("reloaded configuration");
+          },
+          Err(err) => error!("failed to reload configuration; keeping previous one: {err:#}"),
+        }
+      },
+      result = accept => match result {
+        Ok((stream, _addr)) => {
+          let _handle = tasks.spawn(handle(runner.clone(), stream));
+        },
+        Err(err) => error!("failed to accept connection: {err}"),
+      },
+      Some(_result) = tasks.join_next(), if !tasks.is_empty() => (),
+      Some(_result) = drains.join_next(), if !drains.is_empty() => (),
+      _instant = timer.tick() => {
+        // A previous run may still be in progress, in which case there
+        // is no point in starting another one.
+        if drains.is_empty() {
+          debug!("processing queue");
+          let runner = runner.clone();
+          let _handle = drains.spawn(async move { runner.drain().await });
+        }
+      },
+    }
+  }
+
+  info!("shutting down");
+  // Stop accepting submissions right away, so that clients don't end
+  // up waiting for a connection we won't handle.
+  let () = drop(listener);
+  if let Some(socket) = &socket {
+    let _result = remove_file(socket);
+  }
+
+  // In-flight deliveries always conclude before we exit.
+  if !drains.is_empty() {
+    info!("waiting for queue processing to finish");
+    let _results = drains.join_all().await;
+  }
+  if !tasks.is_empty() {
+    info!("waiting for {} in-flight submission(s)", tasks.len());
+    let _results = tasks.join_all().await;
+  }
+  Ok(())
+}
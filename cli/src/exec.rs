@@ -0,0 +1,333 @@
+// Copyright (C) 2024 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::borrow::Cow;
+use std::ffi::OsString;
+use std::io::ErrorKind;
+use std::os::unix::process::ExitStatusExt as _;
+use std::path::Path;
+use std::process::ExitStatus;
+use std::process::Stdio;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::ensure;
+use anyhow::Context as _;
+use anyhow::Result;
+
+use maily::send_email;
+use maily::Attachment;
+
+use tokio::io::AsyncRead;
+use tokio::io::AsyncReadExt as _;
+use tokio::process::Command;
+use tokio::select;
+
+use crate::args::ReportCondition;
+use crate::config::Config;
+use crate::config::Filter;
+use crate::util::format_command;
+use crate::util::hostname;
+use crate::util::pipeline;
+
+
+/// The captured execution of a command.
+#[derive(Debug)]
+struct Execution {
+  /// The command's standard output and error, interleaved in the order
+  /// in which they were read.
+  output: Vec<u8>,
+  /// The command's standard output.
+  stdout: Vec<u8>,
+  /// The command's standard error.
+  stderr: Vec<u8>,
+  /// The command's exit status.
+  status: ExitStatus,
+  /// The time it took the command to run.
+  duration: Duration,
+  /// The reason the command could not be started, if it failed to.
+  error: Option<String>,
+}
+
+impl Execution {
+  /// Check whether a report should be sent under the given condition.
+  fn should_report(&self, condition: ReportCondition) -> bool {
+    match condition {
+      ReportCondition::Failure => !self.status.success(),
+      ReportCondition::Always => true,
+      ReportCondition::Output => !self.output.is_empty() || self.error.is_some(),
+    }
+  }
+
+  /// Retrieve the exit code to report for the command, following shell
+  /// conventions for commands terminated by a signal.
+  fn exit_code(&self) -> i32 {
+    match (self.status.code(), self.status.signal()) {
+      (Some(code), _) => code,
+      (None, Some(signal)) => 128 + signal,
+      (None, None) => 1,
+    }
+  }
+
+  /// Describe how the command exited.
+  fn describe_status(&self) -> String {
+    if let Some(error) = &self.error {
+      return format!("failed to start: {error}")
+    }
+
+    match (self.status.code(), self.status.signal()) {
+      (Some(code), _) => format!("exit status {code}"),
+      (None, Some(signal)) => format!("signal {signal}"),
+      (None, None) => "unknown status".to_string(),
+    }
+  }
+}
+
+
+/// Read from both readers until they are exhausted, capturing the data
+/// separately as well as interleaved.
+async fn capture<O, E>(mut stdout: O, mut stderr: E) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>)>
+where
+  O: AsyncRead + Unpin,
+  E: AsyncRead + Unpin,
+{
+  let mut output = Vec::new();
+  let mut out = Vec::new();
+  let mut err = Vec::new();
+  let mut out_buffer = [0; 4096];
+  let mut err_buffer = [0; 4096];
+  let mut out_done = false;
+  let mut err_done = false;
+
+  while !(out_done && err_done) {
+    select! {
+      result = stdout.read(&mut out_buffer), if !out_done => {
+        let count = result.context("failed to read standard output")?;
+        let () = out.extend_from_slice(&out_buffer[..count]);
+        let () = output.extend_from_slice(&out_buffer[..count]);
+        out_done = count == 0;
+      },
+      result = stderr.read(&mut err_buffer), if !err_done => {
+        let count = result.context("failed to read standard error")?;
+        let () = err.extend_from_slice(&err_buffer[..count]);
+        let () = output.extend_from_slice(&err_buffer[..count]);
+        err_done = count == 0;
+      },
+    }
+  }
+  Ok((output, out, err))
+}
+
+
+/// Run a command, capturing its output.
+async fn execute(command: &[OsString]) -> Result<Execution> {
+  // SANITY: clap ensures that at least the command itself is present.
+  let (program, args) = command.split_first().unwrap();
+  let start = Instant::now();
+  let result = Command::new(program)
+    .args(args)
+    .stdin(Stdio::inherit())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn();
+  let mut child = match result {
+    Ok(child) => child,
+    Err(err) => {
+      // Report the command as failed, with the exit code a shell would
+      // use, so that the failure can be reported like any other.
+      let code = if err.kind() == ErrorKind::NotFound {
+        127
+      } else {
+        126
+      };
+      let execution = Execution {
+        output: Vec::new(),
+        stdout: Vec::new(),
+        stderr: Vec::new(),
+        status: ExitStatus::from_raw(code << 8),
+        duration: start.elapsed(),
+        error: Some(err.to_string()),
+      };
+      return Ok(execution)
+    },
+  };
+
+  // SANITY: Both streams are guaranteed to be present, as we
+  //         requested them to be piped.
+  let stdout = child.stdout.take().unwrap();
+  let stderr = child.stderr.take().unwrap();
+  let (output, stdout, stderr) = capture(stdout, stderr).await?;
+  let status = child
+    .wait()
+    .await
+    .with_context(|| format!("failed to wait for `{}`", format_command(program, args)))?;
+
+  let execution = Execution {
+    output,
+    stdout,
+    stderr,
+    status,
+    duration: start.elapsed(),
+    error: None,
+  };
+  Ok(execution)
+}
+
+
+/// Compose the subject and body of the report about an execution.
+fn report(command: &str, hostname: &str, execution: &Execution) -> (String, String) {
+  let outcome = if execution.status.success() {
+    "succeeded"
+  } else {
+    "failed"
+  };
+  let subject = format!("[{hostname}] `{command}` {outcome}");
+
+  let mut body = format!(
+    "Command:  {command}\nHost:     {hostname}\nStatus:   {}\nDuration: {:.3}s\n",
+    execution.describe_status(),
+    execution.duration.as_secs_f64(),
+  );
+  if execution.output.is_empty() {
+    let () = body.push_str("\nThe command did not produce any output.\n");
+  } else {
+    let () = body.push_str("\nOutput:\n\n");
+    let () = body.push_str(&String::from_utf8_lossy(&execution.output));
+  }
+  (subject, body)
+}
+
+
+/// Run a command and send a report about its execution under the
+/// requested condition, returning the exit code to exit with.
+///
+/// Failure to send the report is printed, but does not change the exit
+/// code, which is always that of the command.
+pub(crate) async fn run(
+  command: Vec<OsString>,
+  condition: ReportCondition,
+  subject: Option<String>,
+  path: &Path,
+  config: Config,
+) -> Result<i32> {
+  let Config {
+    maily,
+    filters,
+    relay: _,
+  } = config;
+  ensure!(
+    !maily.accounts.is_empty(),
+    "no email accounts configured in `{}`",
+    path.display()
+  );
+
+  // SANITY: clap ensures that at least the command itself is present.
+  let (program, args) = command.split_first().unwrap();
+  let formatted = format_command(program, args);
+  let execution = execute(&command).await?;
+  if let Some(error) = &execution.error {
+    eprintln!("failed to run `{formatted}`: {error}");
+  }
+  if !execution.should_report(condition) {
+    return Ok(execution.exit_code())
+  }
+
+  let (generated, body) = report(&formatted, &hostname(), &execution);
+  let subject = subject.unwrap_or(generated);
+  let body = pipeline(body.as_bytes(), filters.into_iter().map(Filter::into))
+    .await
+    .context("failed to apply filters to report")?;
+
+  let code = execution.exit_code();
+  let (accounts, recipients, mut opts) = maily.into_inputs()?;
+  // The separate streams are attached verbatim, as they may not be
+  // valid UTF-8 and should not be altered in any way.
+  for (name, data) in [("stdout.txt", execution.stdout), ("stderr.txt", execution.stderr)] {
+    if !data.is_empty() {
+      let () = opts.attachments.push(Attachment {
+        name: Cow::Borrowed(name),
+        content_type: Some(Cow::Borrowed("text/plain")),
+        data: Cow::Owned(data),
+      });
+    }
+  }
+
+  let result = send_email(
+    accounts.iter(),
+    &subject,
+    &body,
+    None,
+    recipients.iter(),
+    &opts,
+  )
+  .await;
+  if let Err(err) = result {
+    eprintln!("failed to send report: {err:#}");
+  }
+  Ok(code)
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use tokio::test;
+
+
+  /// Check that we capture the output and status of a command.
+  #[test]
+  async fn command_execution() {
+    let command = ["sh", "-c", "echo out; echo err >&2; exit 3"].map(OsString::from);
+    let execution = execute(&command).await.unwrap();
+    assert_eq!(execution.stdout, b"out\n");
+    assert_eq!(execution.stderr, b"err\n");
+    assert_eq!(execution.output.len(), 8);
+    assert_eq!(execution.exit_code(), 3);
+    assert!(execution.should_report(ReportCondition::Failure));
+    assert!(execution.should_report(ReportCondition::Output));
+
+    let command = ["sh", "-c", "kill -9 $$"].map(OsString::from);
+    let execution = execute(&command).await.unwrap();
+    assert_eq!(execution.exit_code(), 137);
+    assert_eq!(execution.describe_status(), "signal 9");
+
+    let command = ["true"].map(OsString::from);
+    let execution = execute(&command).await.unwrap();
+    assert_eq!(execution.exit_code(), 0);
+    assert!(!execution.should_report(ReportCondition::Failure));
+    assert!(!execution.should_report(ReportCondition::Output));
+    assert!(execution.should_report(ReportCondition::Always));
+
+    let command = ["/nonexistent/command"].map(OsString::from);
+    let execution = execute(&command).await.unwrap();
+    assert_eq!(execution.exit_code(), 127);
+    assert!(execution.should_report(ReportCondition::Failure));
+    assert!(execution.should_report(ReportCondition::Output));
+    assert!(
+      execution.describe_status().starts_with("failed to start: "),
+      "{}",
+      execution.describe_status()
+    );
+  }
+
+  /// Check that we compose reports as expected.
+  #[test]
+  async fn report_composition() {
+    let execution = Execution {
+      output: b"line\n".to_vec(),
+      stdout: b"line\n".to_vec(),
+      stderr: Vec::new(),
+      status: ExitStatus::from_raw(2 << 8),
+      duration: Duration::from_millis(1500),
+      error: None,
+    };
+    let (subject, body) = report("backup --all", "host", &execution);
+    assert_eq!(subject, "[host] `backup --all` failed");
+    assert_eq!(
+      body,
+      "Command:  backup --all\nHost:     host\nStatus:   exit status 2\n\
+Duration: 1.500s\n\nOutput:\n\nline\n"
+    );
+  }
+}
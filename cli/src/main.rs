@@ -9,15 +9,32 @@
 )]
 
 mod args;
+mod check;
 mod config;
+mod daemon;
+mod exec;
+mod message;
+mod relay;
+mod sendmail;
 mod util;
+mod wizard;
 
 use std::borrow::Cow;
 use std::env::args_os;
+use std::env::var;
 use std::env::var_os;
 use std::ffi::OsString;
+use std::fs::Permissions;
 use std::io;
+use std::io::stdout;
 use std::io::IsTerminal as _;
+use std::io::Write as _;
+use std::os::unix::fs::PermissionsExt as _;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+use std::time::Duration;
+use std::time::SystemTime;
 
 use clap::Parser as _;
 
@@ -25,14 +42,26 @@ use anyhow::ensure;
 use anyhow::Context as _;
 use anyhow::Result;
 
+use maily::layered_config;
+use maily::migrate_config;
+use maily::preview_email;
+use maily::preview_raw_email;
 use maily::send_email;
+use maily::send_raw_email;
+use maily::serialize_config;
 use maily::system_config_path;
+use maily::Queue;
+use maily::CONFIG_VERSION;
 
-use serde_json::from_slice as from_json;
-
+use tokio::fs::create_dir_all;
 use tokio::fs::read;
+use tokio::fs::write;
+use tokio::fs::OpenOptions;
 use tokio::io::stdin;
+use tokio::io::stdout as async_stdout;
 use tokio::io::AsyncReadExt as _;
+use tokio::io::AsyncWriteExt as _;
+use tokio::io::BufReader;
 
 use tracing::subscriber::set_global_default as set_global_subscriber;
 use tracing_subscriber::filter::EnvFilter;
@@ -41,31 +70,89 @@ use tracing_subscriber::fmt::time::ChronoLocal;
 use tracing_subscriber::FmtSubscriber;
 
 use crate::args::Args;
+use crate::args::Command;
+use crate::args::ConfigCommand;
+use crate::args::QueueCommand;
 use crate::config::Config;
 use crate::config::Filter;
 use crate::util::pipeline;
+use crate::util::write_atomically;
+use crate::wizard::Prompter;
 
 
-async fn run_impl(args: Args) -> Result<()> {
-  let Args {
-    message,
-    subject,
-    content_type,
-    config,
-    verbosity: _,
-  } = args;
+/// The environment variable naming the configuration profile to use,
+/// if none is provided explicitly.
+const ENV_PROFILE: &str = "MAILY_PROFILE";
 
-  let path = if let Some(config) = config {
-    Cow::Owned(config)
+
+/// Load the program's configuration, layering the provided file on top
+/// of the system-wide and user configurations, and apply the profile
+/// with the given name or, if not provided, the one named by the
+/// `MAILY_PROFILE` environment variable, if any.
+///
+/// The returned path is the one of the most specific configuration
+/// file read.
+async fn load_config(
+  config: Option<PathBuf>,
+  profile: Option<&str>,
+) -> Result<(Cow<'static, Path>, Config)> {
+  let (mut paths, mut config) = layered_config::<Config>(config.as_deref()).await?;
+  // SANITY: `layered_config` fails if no configuration file exists.
+  let path = paths.pop().unwrap();
+  let profile = profile
+    .map(Cow::Borrowed)
+    .or_else(|| var(ENV_PROFILE).ok().map(Cow::Owned));
+  if let Some(profile) = profile {
+    let () = config
+      .apply_profile(&profile)
+      .with_context(|| format!("failed to apply profile of `{}`", path.display()))?;
+  }
+  Ok((Cow::Owned(path), config))
+}
+
+
+/// Format a point in time relative to now, in a human readable way.
+fn format_relative(time: SystemTime) -> String {
+  match time.duration_since(SystemTime::now()) {
+    Ok(duration) => format!("in {}s", duration.as_secs()),
+    Err(_) => "now".to_string(),
+  }
+}
+
+
+/// Write a previewed email to the given file or, if it is `-`, to
+/// standard output.
+async fn write_preview(output: &Path, email: &[u8]) -> Result<()> {
+  if output == Path::new("-") {
+    let mut stdout = stdout().lock();
+    let () = stdout
+      .write_all(email)
+      .and_then(|()| stdout.write_all(b"\n"))
+      .and_then(|()| stdout.flush())
+      .context("failed to write email to stdout")?;
   } else {
-    system_config_path()?
-  };
-  let data = read(&path)
-    .await
-    .with_context(|| format!("failed to read configuration file `{}`", path.display()))?;
-  let config = from_json::<Config>(&data)
-    .with_context(|| format!("failed to parse `{}` contents as JSON", path.display()))?;
-  let Config { maily, filters } = config;
+    let () = write(output, email)
+      .await
+      .with_context(|| format!("failed to write email to `{}`", output.display()))?;
+  }
+  Ok(())
+}
+
+
+async fn send(
+  message: Option<String>,
+  subject: Option<String>,
+  content_type: Option<String>,
+  raw: bool,
+  dry_run: Option<PathBuf>,
+  path: &Path,
+  config: Config,
+) -> Result<()> {
+  let Config {
+    maily,
+    filters,
+    relay: _,
+  } = config;
 
   ensure!(
     !maily.accounts.is_empty(),
@@ -90,11 +177,34 @@ async fn run_impl(args: Args) -> Result<()> {
     data
   };
 
+  if raw {
+    let (accounts, _recipients, opts) = maily.into_inputs()?;
+    // Recipients are taken from the message itself.
+    let recipients: &[&str] = &[];
+    if let Some(output) = dry_run {
+      let email = preview_raw_email(&accounts[0], &message, recipients, &opts)?;
+      return write_preview(&output, &email).await
+    }
+    return send_raw_email(accounts.iter(), &message, recipients, &opts).await
+  }
+
   let message = pipeline(&message, filters.into_iter().map(Filter::into))
     .await
     .context("failed to apply filters to message")?;
   let subject = subject.as_deref().unwrap_or("");
-  let (accounts, recipients, opts) = maily.into_inputs();
+  let (accounts, recipients, opts) = maily.into_inputs()?;
+
+  if let Some(output) = dry_run {
+    let email = preview_email(
+      &accounts[0],
+      subject,
+      &message,
+      content_type.as_deref(),
+      recipients.iter(),
+      &opts,
+    )?;
+    return write_preview(&output, &email).await
+  }
 
   send_email(
     accounts.iter(),
@@ -107,6 +217,222 @@ async fn run_impl(args: Args) -> Result<()> {
   .await
 }
 
+
+async fn queue(command: QueueCommand, path: &Path, config: Config) -> Result<()> {
+  let Config { maily, .. } = config;
+  let dir = maily
+    .queue
+    .as_deref()
+    .with_context(|| format!("no queue configured in `{}`", path.display()))?;
+  let queue = Queue::new(dir);
+
+  match command {
+    QueueCommand::List => {
+      for email in queue.list().await? {
+        println!(
+          "{}  attempts: {}  next attempt: {}  to: {}  subject: {}",
+          email.id,
+          email.attempts,
+          format_relative(email.next_attempt),
+          email.recipients.join(", "),
+          email.subject,
+        );
+      }
+      Ok(())
+    },
+    QueueCommand::Flush { force } => {
+      ensure!(
+        !maily.accounts.is_empty(),
+        "no email accounts configured in `{}`",
+        path.display()
+      );
+
+      let (accounts, _recipients, opts) = maily.into_inputs()?;
+      let report = queue.flush(&accounts, &opts, force).await?;
+      for id in &report.sent {
+        println!("{id}: sent");
+      }
+      for id in &report.expired {
+        println!("{id}: expired");
+      }
+      for (id, err) in &report.failed {
+        eprintln!("{id}: {err:#}");
+      }
+      ensure!(
+        report.failed.is_empty(),
+        "failed to send {} queued email(s)",
+        report.failed.len()
+      );
+      Ok(())
+    },
+    QueueCommand::Inspect { id } => {
+      let (email, message) = queue.get(&id).await?;
+      println!("Id: {}", email.id);
+      println!("Subject: {}", email.subject);
+      println!("To: {}", email.recipients.join(", "));
+      if let Some(content_type) = &email.content_type {
+        println!("Content-Type: {content_type}");
+      }
+      println!("Attempts: {}", email.attempts);
+      println!("Next-Attempt: {}", format_relative(email.next_attempt));
+      if let Some(expires) = email.expires {
+        println!("Expires: {}", format_relative(expires));
+      }
+      if let Some(err) = &email.last_error {
+        println!("Last-Error: {err}");
+      }
+      println!();
+
+      let mut stdout = stdout();
+      let () = stdout
+        .write_all(&message)
+        .and_then(|()| stdout.flush())
+        .context("failed to write message to stdout")?;
+      Ok(())
+    },
+    QueueCommand::Delete { ids } => {
+      for id in ids {
+        let () = queue.delete(&id).await?;
+      }
+      Ok(())
+    },
+  }
+}
+
+
+async fn configure(command: ConfigCommand, config: Option<PathBuf>) -> Result<()> {
+  let path = if let Some(config) = config {
+    Cow::Owned(config)
+  } else {
+    system_config_path()?
+  };
+
+  match command {
+    ConfigCommand::Init { force, no_check } => {
+      ensure!(
+        force || !path.exists(),
+        "configuration file `{}` already exists; use --force to overwrite it",
+        path.display()
+      );
+
+      let terminal = io::stdin().is_terminal();
+      let mut prompter = Prompter::new(BufReader::new(stdin()), async_stdout(), terminal);
+      let config = wizard::run(&mut prompter, !no_check).await?;
+      let data = serialize_config(&path, &config)?;
+
+      if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        let () = create_dir_all(dir)
+          .await
+          .with_context(|| format!("failed to create directory `{}`", dir.display()))?;
+      }
+      let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&path)
+        .await
+        .with_context(|| format!("failed to open `{}`", path.display()))?;
+      // The file may have existed before, with more permissive
+      // permissions.
+      let () = file
+        .set_permissions(Permissions::from_mode(0o600))
+        .await
+        .with_context(|| format!("failed to set permissions of `{}`", path.display()))?;
+      let () = file
+        .write_all(&data)
+        .await
+        .with_context(|| format!("failed to write configuration to `{}`", path.display()))?;
+      println!("Wrote configuration to `{}`", path.display());
+      Ok(())
+    },
+    ConfigCommand::Migrate { dry_run, force } => {
+      let data = read(&path)
+        .await
+        .with_context(|| format!("failed to read configuration file `{}`", path.display()))?;
+      match migrate_config(&path, &data)? {
+        None => println!(
+          "Configuration `{}` already is of version {CONFIG_VERSION}",
+          path.display()
+        ),
+        Some(data) if dry_run => {
+          let () = async_stdout()
+            .write_all(&data)
+            .await
+            .context("failed to write configuration to stdout")?;
+        },
+        Some(data) => {
+          let extension = path.extension().and_then(|extension| extension.to_str());
+          ensure!(
+            force || !matches!(extension, Some("toml" | "yaml" | "yml")),
+            "migrating `{}` would discard its comments; use --force to migrate it anyway",
+            path.display()
+          );
+          let () = write_atomically(&path, &data)
+            .await
+            .with_context(|| format!("failed to write configuration to `{}`", path.display()))?;
+          println!(
+            "Migrated configuration `{}` to version {CONFIG_VERSION}",
+            path.display()
+          );
+        },
+      }
+      Ok(())
+    },
+  }
+}
+
+
+async fn run_impl(args: Args) -> Result<()> {
+  let Args {
+    command,
+    message,
+    subject,
+    content_type,
+    to,
+    raw,
+    dry_run,
+    config,
+    profile,
+    verbosity: _,
+  } = args;
+  let profile = profile.as_deref();
+
+  match command {
+    None => {
+      let (path, mut config) = load_config(config, profile).await?;
+      if !to.is_empty() {
+        config.maily.recipients = to;
+      }
+      send(message, subject, content_type, raw, dry_run, &path, config).await
+    },
+    Some(Command::Queue(command)) => {
+      let (path, config) = load_config(config, profile).await?;
+      queue(command, &path, config).await
+    },
+    Some(Command::Daemon {
+      interval,
+      socket,
+      socket_mode,
+    }) => daemon::run(config, profile, Duration::from_secs(interval), socket, socket_mode).await,
+    Some(Command::Config(command)) => configure(command, config).await,
+    Some(Command::Check { connect }) => {
+      let (path, config) = load_config(config, profile).await?;
+      check::run(connect, &path, config).await
+    },
+    Some(Command::Relay { listen }) => relay::run(config, profile, listen).await,
+    Some(Command::Exec {
+      on,
+      subject,
+      command,
+    }) => {
+      let (path, config) = load_config(config, profile).await?;
+      let code = exec::run(command, on, subject, &path, config).await?;
+      process::exit(code)
+    },
+  }
+}
+
 fn setup_tracing(verbosity: u8) -> Result<()> {
   let builder =
     FmtSubscriber::builder().with_timer(ChronoLocal::new("%Y-%m-%dT%H:%M:%S%.3f%:z".to_string()));
@@ -141,6 +467,19 @@ where
   A: IntoIterator<Item = T>,
   T: Into<OsString> + Clone,
 {
+  let mut args = args.into_iter().map(Into::into).collect::<Vec<OsString>>();
+  let as_sendmail = args
+    .first()
+    .and_then(|arg0| Path::new(arg0).file_name())
+    .is_some_and(|name| name == "sendmail");
+  if as_sendmail || args.get(1).is_some_and(|arg| arg == "--sendmail") {
+    if !as_sendmail {
+      let _arg = args.remove(1);
+    }
+    let () = setup_tracing(0)?;
+    return sendmail::run(args.into_iter().skip(1)).await
+  }
+
   let args = match Args::try_parse_from(args) {
     Ok(args) => args,
     Err(err) => match err.kind() {
@@ -0,0 +1,305 @@
+// Copyright (C) 2024 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// Minimal parsing of RFC 5322 messages, as handed to us by legacy
+// tools expecting a mail transfer agent.
+
+use anyhow::Result;
+
+use maily::AddressBook;
+
+use crate::util::decode_base64;
+
+
+/// The parts of an RFC 5322 message that we forward.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Email {
+  /// The (unfolded) headers of the message.
+  pub headers: Vec<(String, String)>,
+  pub subject: String,
+  pub content_type: Option<String>,
+  /// The body, with any transfer encoding undone.
+  pub body: Vec<u8>,
+}
+
+impl Email {
+  /// Retrieve the addresses contained in all headers with the given
+  /// names.
+  pub fn addresses(&self, names: &[&str]) -> Vec<String> {
+    self
+      .headers
+      .iter()
+      .filter(|(key, _value)| names.iter().any(|name| key.eq_ignore_ascii_case(name)))
+      .flat_map(|(_key, value)| parse_addresses(value))
+      .collect()
+  }
+}
+
+
+/// Decode quoted-printable data (RFC 2045, section 6.7).
+fn decode_quoted_printable(data: &[u8]) -> Vec<u8> {
+  fn hex(byte: u8) -> Option<u8> {
+    char::from(byte).to_digit(16).map(|digit| digit as u8)
+  }
+
+  let mut decoded = Vec::with_capacity(data.len());
+  let mut i = 0;
+  while i < data.len() {
+    match &data[i..] {
+      [b'=', b'\r', b'\n', ..] => i += 3,
+      [b'=', b'\n', ..] => i += 2,
+      [b'=', high, low, ..] if hex(*high).is_some() && hex(*low).is_some() => {
+        // SANITY: We checked above that both are valid hex digits.
+        let () = decoded.push(hex(*high).unwrap() << 4 | hex(*low).unwrap());
+        i += 3;
+      },
+      [byte, ..] => {
+        let () = decoded.push(*byte);
+        i += 1;
+      },
+      [] => unreachable!(),
+    }
+  }
+  decoded
+}
+
+
+/// Split a message into its (unfolded) headers and body.
+fn split_message(data: &[u8]) -> (Vec<(String, String)>, &[u8]) {
+  let mut headers = Vec::<(String, String)>::new();
+  let mut rest = data;
+
+  loop {
+    let (line, remainder) = match rest.iter().position(|byte| *byte == b'\n') {
+      Some(idx) => (&rest[..idx], &rest[idx + 1..]),
+      None => (rest, &rest[rest.len()..]),
+    };
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    if line.is_empty() {
+      break (headers, remainder)
+    }
+    rest = remainder;
+
+    let line = String::from_utf8_lossy(line);
+    if line.starts_with([' ', '\t']) {
+      if let Some((_name, value)) = headers.last_mut() {
+        let () = value.push(' ');
+        let () = value.push_str(line.trim());
+      }
+    } else if let Some((name, value)) = line.split_once(':') {
+      let () = headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+
+    if rest.is_empty() {
+      break (headers, rest)
+    }
+  }
+}
+
+
+/// Extract the parts of a message that we forward.
+pub(crate) fn parse_email(data: &[u8]) -> Email {
+  let (headers, body) = split_message(data);
+  let header = |name: &str| {
+    headers
+      .iter()
+      .find(|(key, _value)| key.eq_ignore_ascii_case(name))
+      .map(|(_key, value)| value.clone())
+  };
+
+  let encoding = header("Content-Transfer-Encoding").unwrap_or_default();
+  let body = if encoding.eq_ignore_ascii_case("base64") {
+    decode_base64(body)
+  } else if encoding.eq_ignore_ascii_case("quoted-printable") {
+    decode_quoted_printable(body)
+  } else {
+    body.to_vec()
+  };
+
+  Email {
+    subject: header("Subject").unwrap_or_default(),
+    content_type: header("Content-Type"),
+    body,
+    headers,
+  }
+}
+
+
+/// Replace the body of a message with `body`, retaining all its header
+/// fields.
+///
+/// The new body is expected to not be transfer encoded and so any
+/// `Content-Transfer-Encoding` header field is removed.
+pub(crate) fn replace_body(data: &[u8], body: &[u8]) -> Vec<u8> {
+  let mut message = Vec::with_capacity(data.len() + body.len());
+  let mut skip = false;
+  let mut separated = false;
+
+  for line in data.split_inclusive(|byte| *byte == b'\n') {
+    if line == b"\r\n" || line == b"\n" {
+      let () = message.extend_from_slice(line);
+      separated = true;
+      break
+    }
+    if !line.starts_with(b" ") && !line.starts_with(b"\t") {
+      let name = line.split(|byte| *byte == b':').next().unwrap_or(line);
+      skip = name
+        .trim_ascii()
+        .eq_ignore_ascii_case(b"Content-Transfer-Encoding");
+    }
+    if !skip {
+      let () = message.extend_from_slice(line);
+    }
+  }
+
+  if !separated {
+    let () = message.extend_from_slice(b"\r\n");
+  }
+  let () = message.extend_from_slice(body);
+  message
+}
+
+
+/// Parse the addresses from an address list header value such as
+/// `Jane <jane@example.com>, "Doe, John" <john@example.com>, root`.
+fn parse_addresses(value: &str) -> Vec<String> {
+  let mut addresses = Vec::new();
+  let mut current = String::new();
+  let mut quoted = false;
+  let mut angle = false;
+
+  for c in value.chars().chain([',']) {
+    match c {
+      '"' if !angle => quoted = !quoted,
+      '<' if !quoted => {
+        angle = true;
+        let () = current.clear();
+      },
+      '>' if !quoted => angle = false,
+      ',' if !quoted && !angle => {
+        let address = current.trim();
+        if !address.is_empty() {
+          let () = addresses.push(address.to_string());
+        }
+        let () = current.clear();
+      },
+      // Group syntax (`undisclosed-recipients:;`) carries no
+      // addresses outside of angle brackets we care about.
+      ':' | ';' if !quoted && !angle => current.clear(),
+      c if !quoted => current.push(c),
+      _ => (),
+    }
+  }
+  addresses
+}
+
+
+/// Rewrite the recipients of an email.
+///
+/// Names of aliases and recipient groups contained in `book` are
+/// resolved. Other local recipients, i.e., those without a domain (such
+/// as `root`, as commonly used by cron), are replaced with the
+/// configured default recipients. The same happens if no recipients
+/// were provided at all.
+pub(crate) fn rewrite_recipients(
+  recipients: &[String],
+  defaults: &[String],
+  book: &AddressBook,
+) -> Result<Vec<String>> {
+  let mut rewritten = Vec::new();
+  if recipients.is_empty() {
+    let () = rewritten.extend(defaults.iter().cloned());
+  }
+
+  for recipient in recipients {
+    if book.contains(recipient) {
+      let () = rewritten.extend(book.resolve([recipient])?);
+    } else if recipient.contains('@') {
+      let () = rewritten.push(recipient.clone());
+    } else {
+      let () = rewritten.extend(defaults.iter().cloned());
+    }
+  }
+  let () = rewritten.sort();
+  let () = rewritten.dedup();
+  Ok(rewritten)
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+
+  /// Check that we can decode quoted-printable data.
+  #[test]
+  fn quoted_printable_decoding() {
+    let decoded = decode_quoted_printable(b"caf=C3=A9 =3D soft=\r\nbreak =ZZ");
+    assert_eq!(decoded, "café = softbreak =ZZ".as_bytes());
+  }
+
+  /// Check that we extract the relevant parts of a message.
+  #[test]
+  fn email_parsing() {
+    let message = b"From: cron@host\r\nSubject: Cron <root@host>\r\n  output\r\n\
+Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: base64\r\n\r\naGVsbG8K\r\n";
+    let email = parse_email(message);
+    assert_eq!(email.subject, "Cron <root@host> output");
+    assert_eq!(email.content_type.as_deref(), Some("text/plain; charset=utf-8"));
+    assert_eq!(email.body, b"hello\n");
+    assert_eq!(email.headers.len(), 4);
+
+    let email = parse_email(b"Subject: test\n\nbody\n..dots\n");
+    assert_eq!(email.subject, "test");
+    assert_eq!(email.content_type, None);
+    assert_eq!(email.body, b"body\n..dots\n");
+  }
+
+  /// Check that we can replace the body of a message.
+  #[test]
+  fn body_replacement() {
+    let message = b"From: cron@host\r\nContent-Transfer-Encoding:\r\n base64\r\n\
+Subject: test\r\n\r\naGVsbG8K\r\n";
+    let replaced = replace_body(message, b"HELLO\n");
+    assert_eq!(replaced, b"From: cron@host\r\nSubject: test\r\n\r\nHELLO\n");
+
+    let replaced = replace_body(b"Subject: test\n", b"body");
+    assert_eq!(replaced, b"Subject: test\n\r\nbody");
+  }
+
+  /// Check that we extract addresses from address list headers.
+  #[test]
+  fn address_parsing() {
+    let email = parse_email(
+      b"To: Jane <jane@example.com>, \"Doe, John\" <john@example.com>,\r\n root\r\n\
+Cc: undisclosed-recipients:;\r\nBcc: a@example.com\r\n\r\n",
+    );
+    assert_eq!(
+      email.addresses(&["To", "Cc", "Bcc"]),
+      ["jane@example.com", "john@example.com", "root", "a@example.com"]
+    );
+  }
+
+  /// Check that local recipients get rewritten.
+  #[test]
+  fn recipient_rewriting() {
+    let defaults = ["admin@example.com".to_string()];
+    let mut book = AddressBook::default();
+    let recipients = ["root".to_string(), "x@example.com".to_string(), "cron".to_string()];
+    assert_eq!(
+      rewrite_recipients(&recipients, &defaults, &book).unwrap(),
+      ["admin@example.com", "x@example.com"]
+    );
+    assert_eq!(rewrite_recipients(&[], &defaults, &book).unwrap(), defaults);
+
+    let _members = book.groups.insert(
+      "dba".to_string(),
+      vec!["a@example.com".to_string(), "x@example.com".to_string()],
+    );
+    let recipients = ["dba".to_string(), "x@example.com".to_string()];
+    assert_eq!(
+      rewrite_recipients(&recipients, &defaults, &book).unwrap(),
+      ["a@example.com", "x@example.com"]
+    );
+  }
+}
@@ -0,0 +1,548 @@
+// Copyright (C) 2024 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::net::SocketAddr;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context as _;
+use anyhow::Result;
+
+use maily::send_raw_email;
+use maily::Account;
+use maily::AddressBook;
+use maily::EmailOpts;
+
+use tokio::io::AsyncBufRead;
+use tokio::io::AsyncBufReadExt as _;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncReadExt as _;
+use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt as _;
+use tokio::io::BufReader;
+use tokio::net::TcpListener;
+use tokio::select;
+use tokio::signal::unix::signal;
+use tokio::signal::unix::SignalKind;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+
+use tracing::debug;
+use tracing::error;
+use tracing::info;
+use tracing::warn;
+
+use crate::config::Config;
+use crate::config::Filter;
+use crate::config::RelayConfig;
+use crate::load_config;
+use crate::message::parse_email;
+use crate::message::replace_body;
+use crate::message::rewrite_recipients;
+use crate::util::decode_base64;
+use crate::util::pipeline;
+
+
+/// The maximum size of a message we accept.
+const MAX_MESSAGE_SIZE: usize = 32 * 1024 * 1024;
+/// The maximum length of a line we accept.
+const MAX_LINE_LENGTH: u64 = 8192;
+/// The time we wait for a client to send the next line.
+const TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Extract the address from a `MAIL FROM:<...>` or `RCPT TO:<...>`
+/// command argument, given the expected prefix.
+fn parse_path<'arg>(arg: &'arg str, prefix: &str) -> Option<&'arg str> {
+  let (head, rest) = arg.split_at_checked(prefix.len())?;
+  if !head.eq_ignore_ascii_case(prefix) {
+    return None
+  }
+  let rest = rest.trim_start();
+  let path = match rest.strip_prefix('<') {
+    Some(rest) => rest.split_once('>')?.0,
+    None => rest.split_whitespace().next().unwrap_or(""),
+  };
+  Some(path)
+}
+
+
+/// The state shared by all connections to the relay.
+struct Relay {
+  accounts: Vec<Account<'static>>,
+  recipients: Vec<String>,
+  address_book: AddressBook,
+  opts: EmailOpts<'static>,
+  filters: Vec<(String, Vec<String>)>,
+  credentials: Option<RelayConfig>,
+}
+
+impl Relay {
+  fn new(path: &Path, config: Config) -> Result<Self> {
+    let Config {
+      maily,
+      filters,
+      relay,
+    } = config;
+    ensure!(
+      !maily.accounts.is_empty(),
+      "no email accounts configured in `{}`",
+      path.display()
+    );
+    let address_book = maily.address_book.clone();
+    let (accounts, recipients, opts) = maily.into_inputs()?;
+
+    let slf = Self {
+      accounts,
+      recipients,
+      address_book,
+      opts,
+      filters: filters.into_iter().map(Filter::into).collect(),
+      credentials: relay,
+    };
+    Ok(slf)
+  }
+
+  /// Check the provided credentials against the configured ones.
+  fn authenticate(&self, user: &str, password: &str) -> bool {
+    self
+      .credentials
+      .as_ref()
+      .is_some_and(|creds| creds.user == user && creds.password == password)
+  }
+
+  /// Forward a received message to the given recipients.
+  ///
+  /// The message is relayed as-is, unless filters are configured, in
+  /// which case its body is replaced with the filtered one.
+  async fn forward(&self, data: &[u8], recipients: &[String]) -> Result<()> {
+    let recipients = rewrite_recipients(recipients, &self.recipients, &self.address_book)?;
+    ensure!(!recipients.is_empty(), "no recipients left after rewriting");
+
+    let filtered;
+    let message = if self.filters.is_empty() {
+      data
+    } else {
+      let email = parse_email(data);
+      let body = pipeline(&email.body, self.filters.iter().cloned())
+        .await
+        .context("failed to apply filters to message")?;
+      filtered = replace_body(data, &body);
+      &filtered
+    };
+
+    send_raw_email(self.accounts.iter(), message, recipients.iter(), &self.opts).await
+  }
+}
+
+
+/// The state of a single SMTP session.
+#[derive(Debug, Default)]
+struct Session {
+  authenticated: bool,
+  from: Option<String>,
+  to: Vec<String>,
+}
+
+
+/// Read a line from the client, returning `None` once the connection
+/// got closed.
+///
+/// Lines exceeding [`MAX_LINE_LENGTH`] are rejected and the connection
+/// is terminated, as splitting them could change their meaning (e.g.,
+/// with respect to dot stuffing).
+async fn read_line<S>(stream: &mut S) -> Result<Option<Vec<u8>>>
+where
+  S: AsyncBufRead + AsyncWrite + Unpin,
+{
+  let mut line = Vec::new();
+  let count = timeout(
+    TIMEOUT,
+    (&mut *stream).take(MAX_LINE_LENGTH).read_until(b'\n', &mut line),
+  )
+  .await
+  .context("timed out waiting for client")?
+  .context("failed to read from client")?;
+  if count == 0 {
+    return Ok(None)
+  }
+
+  if count as u64 == MAX_LINE_LENGTH && !line.ends_with(b"\n") {
+    let () = reply(stream, "500 line too long").await?;
+    let () = stream.flush().await.context("failed to write to client")?;
+    bail!("client sent line exceeding {MAX_LINE_LENGTH} bytes")
+  }
+  Ok(Some(line))
+}
+
+
+async fn reply<W>(writer: &mut W, reply: &str) -> Result<()>
+where
+  W: AsyncWrite + Unpin,
+{
+  let () = writer
+    .write_all(format!("{reply}\r\n").as_bytes())
+    .await
+    .context("failed to write to client")?;
+  Ok(())
+}
+
+
+/// Read the response to an authentication challenge and decode it.
+async fn read_auth_response<S>(stream: &mut S) -> Result<Option<String>>
+where
+  S: AsyncBufRead + AsyncWrite + Unpin,
+{
+  let line = read_line(stream).await?;
+  Ok(line.map(|line| String::from_utf8_lossy(&decode_base64(&line)).to_string()))
+}
+
+
+/// Read the message data following a `DATA` command, undoing dot
+/// stuffing. `None` is returned if the message exceeded the maximum
+/// size.
+async fn read_data<S>(stream: &mut S) -> Result<Option<Vec<u8>>>
+where
+  S: AsyncBufRead + AsyncWrite + Unpin,
+{
+  let mut data = Vec::new();
+  let mut oversized = false;
+
+  loop {
+    let line = read_line(stream)
+      .await?
+      .context("connection closed while receiving message")?;
+    if line == b".\r\n" || line == b".\n" {
+      break
+    }
+    let line = line.strip_prefix(b".").unwrap_or(&line);
+    if data.len() + line.len() > MAX_MESSAGE_SIZE {
+      oversized = true;
+    } else {
+      let () = data.extend_from_slice(line);
+    }
+  }
+
+  Ok((!oversized).then_some(data))
+}
+
+
+/// Handle a single SMTP client connection.
+async fn serve<S>(relay: Arc<Relay>, stream: S) -> Result<()>
+where
+  S: AsyncRead + AsyncWrite + Unpin,
+{
+  let mut stream = BufReader::new(stream);
+  let mut session = Session::default();
+
+  let () = reply(&mut stream, "220 localhost ESMTP mail-message relay").await?;
+
+  while let Some(line) = read_line(&mut stream).await? {
+    let line = String::from_utf8_lossy(&line);
+    let line = line.trim_end();
+    let (verb, arg) = line.split_once(' ').unwrap_or((line, ""));
+
+    match verb.to_ascii_uppercase().as_str() {
+      "HELO" => reply(&mut stream, "250 localhost").await?,
+      "EHLO" => {
+        let () = reply(&mut stream, "250-localhost").await?;
+        let () = reply(&mut stream, "250-8BITMIME").await?;
+        if relay.credentials.is_some() {
+          let () = reply(&mut stream, "250-AUTH PLAIN LOGIN").await?;
+        }
+        let () = reply(&mut stream, &format!("250 SIZE {MAX_MESSAGE_SIZE}")).await?;
+      },
+      "AUTH" => {
+        if relay.credentials.is_none() {
+          let () = reply(&mut stream, "503 authentication not enabled").await?;
+          continue
+        }
+
+        let (mechanism, initial) = arg.split_once(' ').unwrap_or((arg, ""));
+        let creds = match mechanism.to_ascii_uppercase().as_str() {
+          "PLAIN" => {
+            let response = if initial.is_empty() {
+              let () = reply(&mut stream, "334 ").await?;
+              read_auth_response(&mut stream).await?
+            } else {
+              Some(String::from_utf8_lossy(&decode_base64(initial.as_bytes())).to_string())
+            };
+            response.and_then(|response| {
+              let mut parts = response.split('\0').skip(1);
+              Some((parts.next()?.to_string(), parts.next()?.to_string()))
+            })
+          },
+          "LOGIN" => {
+            // "Username:" and "Password:", base64 encoded.
+            let () = reply(&mut stream, "334 VXNlcm5hbWU6").await?;
+            let user = read_auth_response(&mut stream).await?;
+            let () = reply(&mut stream, "334 UGFzc3dvcmQ6").await?;
+            let password = read_auth_response(&mut stream).await?;
+            user.zip(password)
+          },
+          _ => {
+            let () = reply(&mut stream, "504 unrecognized authentication mechanism").await?;
+            continue
+          },
+        };
+
+        match creds {
+          Some((user, password)) if relay.authenticate(&user, &password) => {
+            session.authenticated = true;
+            let () = reply(&mut stream, "235 authentication successful").await?;
+          },
+          _ => {
+            let () = reply(&mut stream, "535 authentication credentials invalid").await?;
+          },
+        }
+      },
+      "MAIL" => {
+        if relay.credentials.is_some() && !session.authenticated {
+          let () = reply(&mut stream, "530 authentication required").await?;
+        } else if let Some(path) = parse_path(arg, "FROM:") {
+          session.from = Some(path.to_string());
+          session.to.clear();
+          let () = reply(&mut stream, "250 ok").await?;
+        } else {
+          let () = reply(&mut stream, "501 syntax error in MAIL command").await?;
+        }
+      },
+      "RCPT" => {
+        if session.from.is_none() {
+          let () = reply(&mut stream, "503 need MAIL command first").await?;
+        } else if let Some(path) = parse_path(arg, "TO:").filter(|path| !path.is_empty()) {
+          let () = session.to.push(path.to_string());
+          let () = reply(&mut stream, "250 ok").await?;
+        } else {
+          let () = reply(&mut stream, "501 syntax error in RCPT command").await?;
+        }
+      },
+      "DATA" => {
+        if session.to.is_empty() {
+          let () = reply(&mut stream, "503 need RCPT command first").await?;
+          continue
+        }
+
+        let () = reply(&mut stream, "354 end data with <CR><LF>.<CR><LF>").await?;
+        let Some(data) = read_data(&mut stream).await? else {
+          let () = reply(&mut stream, "552 message exceeds maximum size").await?;
+          session = Session {
+            authenticated: session.authenticated,
+            ..Default::default()
+          };
+          continue
+        };
+
+        match relay.forward(&data, &session.to).await {
+          Ok(()) => {
+            info!(recipients = ?session.to, "relayed email");
+            let () = reply(&mut stream, "250 ok: message relayed").await?;
+          },
+          Err(err) => {
+            warn!(recipients = ?session.to, "failed to relay email: {err:#}");
+            // Report a temporary failure, so that the client may try
+            // again later.
+            let () = reply(&mut stream, "451 failed to relay message").await?;
+          },
+        }
+        session = Session {
+          authenticated: session.authenticated,
+          ..Default::default()
+        };
+      },
+      "RSET" => {
+        session = Session {
+          authenticated: session.authenticated,
+          ..Default::default()
+        };
+        let () = reply(&mut stream, "250 ok").await?;
+      },
+      "NOOP" => reply(&mut stream, "250 ok").await?,
+      "VRFY" => reply(&mut stream, "252 cannot verify user").await?,
+      "QUIT" => {
+        let () = reply(&mut stream, "221 bye").await?;
+        break
+      },
+      _ => reply(&mut stream, "502 command not recognized").await?,
+    }
+    let () = stream.flush().await.context("failed to write to client")?;
+  }
+  let () = stream.flush().await.context("failed to write to client")?;
+  Ok(())
+}
+
+
+/// Run the SMTP relay on the given address until asked to terminate.
+pub(crate) async fn run(
+  config: Option<PathBuf>,
+  profile: Option<&str>,
+  addr: SocketAddr,
+) -> Result<()> {
+  let mut terminate =
+    signal(SignalKind::terminate()).context("failed to register SIGTERM handler")?;
+  let mut interrupt =
+    signal(SignalKind::interrupt()).context("failed to register SIGINT handler")?;
+
+  let (path, config) = load_config(config, profile).await?;
+  let relay = Arc::new(Relay::new(&path, config)?);
+  let listener = TcpListener::bind(addr)
+    .await
+    .with_context(|| format!("failed to bind to {addr}"))?;
+  let mut tasks = JoinSet::new();
+  info!("relaying emails received on {addr}");
+
+  loop {
+    select! {
+      biased;
+
+      _ = terminate.recv() => break,
+      _ = interrupt.recv() => break,
+      result = listener.accept() => match result {
+        Ok((stream, peer)) => {
+          debug!("accepted connection from {peer}");
+          let relay = relay.clone();
+          let _handle = tasks.spawn(async move {
+            if let Err(err) = serve(relay, stream).await {
+              warn!("connection from {peer} failed: {err:#}");
+            }
+          });
+        },
+        Err(err) => error!("failed to accept connection: {err}"),
+      },
+      Some(_result) = tasks.join_next(), if !tasks.is_empty() => (),
+    }
+  }
+
+  info!("shutting down");
+  if !tasks.is_empty() {
+    info!("waiting for {} open connection(s)", tasks.len());
+    let _results = tasks.join_all().await;
+  }
+  Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::borrow::Cow;
+  use std::fs::read_to_string;
+
+  use maily::LocalAccount;
+  use maily::MailboxFormat;
+
+  use tempfile::tempdir;
+
+  use tokio::io::duplex;
+  use tokio::spawn;
+  use tokio::test;
+
+
+  /// Check that we parse `MAIL` and `RCPT` arguments correctly.
+  #[test]
+  async fn path_parsing() {
+    assert_eq!(parse_path("FROM:<a@b.c>", "FROM:"), Some("a@b.c"));
+    assert_eq!(parse_path("from: <a@b.c> SIZE=12", "FROM:"), Some("a@b.c"));
+    assert_eq!(parse_path("TO:root", "TO:"), Some("root"));
+    assert_eq!(parse_path("FROM:<>", "FROM:"), Some(""));
+    assert_eq!(parse_path("TO:<a@b.c>", "FROM:"), None);
+  }
+
+  /// Check that we can relay an email received via SMTP.
+  #[test]
+  async fn relaying() {
+    let dir = tempdir().unwrap();
+    let mbox = dir.path().join("mbox");
+    let relay = Relay {
+      accounts: vec![Account::Local(LocalAccount {
+        mailbox: Cow::Owned(mbox.clone()),
+        mailbox_format: MailboxFormat::Mbox,
+        from: Cow::Borrowed("relay@example.com"),
+      })],
+      recipients: vec!["admin@example.com".to_string()],
+      address_book: AddressBook::default(),
+      opts: EmailOpts::default(),
+      filters: Vec::new(),
+      credentials: Some(RelayConfig {
+        user: "user".to_string(),
+        password: "secret".to_string(),
+      }),
+    };
+
+    let (client, server) = duplex(4096);
+    let server = spawn(serve(Arc::new(relay), server));
+    let mut client = BufReader::new(client);
+
+    for (command, expected) in [
+      ("", "220"),
+      ("EHLO client\r\n", "250-localhost"),
+      ("", "250-8BITMIME"),
+      ("", "250-AUTH PLAIN LOGIN"),
+      ("", "250 SIZE"),
+      ("MAIL FROM:<cron@host>\r\n", "530"),
+      // "\0user\0wrong"
+      ("AUTH PLAIN AHVzZXIAd3Jvbmc=\r\n", "535"),
+      // "\0user\0secret"
+      ("AUTH PLAIN AHVzZXIAc2VjcmV0\r\n", "235"),
+      ("MAIL FROM:<cron@host>\r\n", "250"),
+      ("RCPT TO:<root>\r\n", "250"),
+      ("DATA\r\n", "354"),
+      (
+        "From: Cron <cron@host>\r\nTo: root\r\nMessage-ID: <1@host>\r\n\
+Subject: backup\r\n\r\nall good\r\n..\r\n.\r\n",
+        "250",
+      ),
+      ("QUIT\r\n", "221"),
+    ] {
+      let () = client.write_all(command.as_bytes()).await.unwrap();
+      let mut line = String::new();
+      let _count = client.read_line(&mut line).await.unwrap();
+      assert!(line.starts_with(expected), "{command:?}: {line:?}");
+    }
+    let () = server.await.unwrap().unwrap();
+
+    let content = read_to_string(&mbox).unwrap();
+    assert!(content.starts_with("From relay@example.com "), "{content}");
+    assert!(content.contains("From: Cron <cron@host>\n"), "{content}");
+    assert!(content.contains("Message-ID: <1@host>\n"), "{content}");
+    assert!(content.contains("Subject: backup\n"), "{content}");
+    assert!(content.contains("\nall good\n.\n"), "{content}");
+  }
+
+  /// Check that we reject lines exceeding the maximum length instead
+  /// of splitting them.
+  #[test]
+  async fn long_line_rejection() {
+    let relay = Relay {
+      accounts: Vec::new(),
+      recipients: Vec::new(),
+      address_book: AddressBook::default(),
+      opts: EmailOpts::default(),
+      filters: Vec::new(),
+      credentials: None,
+    };
+
+    let (client, server) = duplex(4096);
+    let server = spawn(serve(Arc::new(relay), server));
+    let mut client = BufReader::new(client);
+
+    let mut line = String::new();
+    let _count = client.read_line(&mut line).await.unwrap();
+    assert!(line.starts_with("220"), "{line:?}");
+
+    let command = format!("NOOP {}\r\n", "x".repeat(MAX_LINE_LENGTH as usize));
+    // The server may hang up before having read the entire line.
+    let _result = client.write_all(command.as_bytes()).await;
+    let mut line = String::new();
+    let _count = client.read_line(&mut line).await.unwrap();
+    assert!(line.starts_with("500"), "{line:?}");
+
+    let err = server.await.unwrap().unwrap_err();
+    assert!(err.to_string().contains("exceeding"), "{err:#}");
+  }
+}
@@ -0,0 +1,345 @@
+// Copyright (C) 2024 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// A command line interface compatible with the subset of sendmail(8)
+// commonly used by system services such as cron, smartd, or mdadm.
+
+use std::borrow::Cow;
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context as _;
+use anyhow::Result;
+
+use maily::send_email;
+
+use tokio::io::stdin;
+use tokio::io::AsyncReadExt as _;
+
+use crate::config::Config;
+use crate::config::Filter;
+use crate::load_config;
+use crate::message::parse_email;
+use crate::message::rewrite_recipients;
+use crate::util::pipeline;
+
+
+/// The options understood in sendmail compatibility mode.
+#[derive(Debug, Default, PartialEq)]
+struct Options {
+  /// The path to the configuration file (`-C`).
+  config: Option<PathBuf>,
+  /// Whether to extract recipients from the message headers (`-t`).
+  extract_recipients: bool,
+  /// Whether to not treat a line with a single dot as the end of the
+  /// message (`-i`, `-oi`).
+  ignore_dots: bool,
+  /// The sender address (`-f`, `-r`).
+  sender: Option<String>,
+  /// The full name of the sender (`-F`).
+  full_name: Option<String>,
+  /// The recipients provided on the command line.
+  recipients: Vec<String>,
+}
+
+
+/// Parse sendmail style command line arguments (excluding the program
+/// name).
+fn parse_args<I>(args: I) -> Result<Options>
+where
+  I: IntoIterator<Item = OsString>,
+{
+  let mut args = args.into_iter().map(|arg| {
+    arg
+      .into_string()
+      .map_err(|arg| anyhow!("argument `{}` is not valid UTF-8", arg.to_string_lossy()))
+  });
+  let mut options = Options::default();
+  let mut only_recipients = false;
+
+  while let Some(arg) = args.next() {
+    let arg = arg?;
+    if only_recipients || !arg.starts_with('-') || arg == "-" {
+      let () = options.recipients.push(arg);
+      continue
+    }
+    if arg == "--" {
+      only_recipients = true;
+      continue
+    }
+
+    let flags = &arg[1..];
+    // Flags without values may be combined, as in `-ti`.
+    if flags.chars().all(|c| "eimtUv".contains(c)) {
+      options.extract_recipients |= flags.contains('t');
+      options.ignore_dots |= flags.contains('i');
+      continue
+    }
+
+    let mut chars = flags.chars();
+    // SANITY: `flags` is not empty, or it would have been handled
+    //         above.
+    let flag = chars.next().unwrap();
+    let rest = chars.as_str();
+    let mut value = || -> Result<String> {
+      if rest.is_empty() {
+        args
+          .next()
+          .with_context(|| format!("option `-{flag}` requires a value"))?
+      } else {
+        Ok(rest.to_string())
+      }
+    };
+
+    match flag {
+      'C' => options.config = Some(PathBuf::from(value()?)),
+      'f' | 'r' => options.sender = Some(value()?),
+      'F' => options.full_name = Some(value()?),
+      'o' => {
+        if value()? == "i" {
+          options.ignore_dots = true;
+        }
+      },
+      'b' => match value()?.as_str() {
+        "m" => (),
+        mode => bail!("unsupported operation mode `-b{mode}`"),
+      },
+      // Options we accept but that have no meaning for us.
+      'B' | 'L' | 'N' | 'R' | 'V' | 'X' => {
+        let _value = value()?;
+      },
+      _ => bail!("unsupported option `{arg}`"),
+    }
+  }
+  Ok(options)
+}
+
+
+/// Compose a "From" identifier from an account's existing one, a sender
+/// address, and a full name.
+///
+/// Sender addresses without a domain (e.g., `root`, as used by cron)
+/// are ignored, as they are not meaningful outside of the host.
+fn compose_from(from: &str, sender: Option<&str>, full_name: Option<&str>) -> String {
+  let (name, address) = match from.rsplit_once('<') {
+    Some((name, address)) => (name.trim(), address.trim_end_matches('>').trim()),
+    None => ("", from.trim()),
+  };
+  let address = sender
+    .filter(|sender| sender.contains('@'))
+    .unwrap_or(address);
+
+  let name = match full_name {
+    Some(full_name) => Cow::Owned(format!(
+      "\"{}\"",
+      full_name.replace('\\', "\\\\").replace('"', "\\\"")
+    )),
+    None => Cow::Borrowed(name),
+  };
+
+  if name.is_empty() {
+    address.to_string()
+  } else {
+    format!("{name} <{address}>")
+  }
+}
+
+
+/// Cut off a message at a line consisting of a single dot, as
+/// traditionally done by sendmail.
+fn truncate_at_dot(message: &[u8]) -> &[u8] {
+  let mut offset = 0;
+  for line in message.split_inclusive(|byte| *byte == b'\n') {
+    if line == b".\n" || line == b".\r\n" || line == b"." {
+      return &message[..offset]
+    }
+    offset += line.len();
+  }
+  message
+}
+
+
+/// Send an email read from standard input, in the way sendmail would.
+pub(crate) async fn run<I>(args: I) -> Result<()>
+where
+  I: IntoIterator<Item = OsString>,
+{
+  let options = parse_args(args)?;
+  // sendmail has no notion of profiles, but one may still be selected
+  // via the `MAILY_PROFILE` environment variable.
+  let (path, config) = load_config(options.config, None).await?;
+  let Config {
+    maily,
+    filters,
+    relay: _,
+  } = config;
+  ensure!(
+    !maily.accounts.is_empty(),
+    "no email accounts configured in `{}`",
+    path.display()
+  );
+
+  let mut message = Vec::new();
+  let _count = stdin()
+    .read_to_end(&mut message)
+    .await
+    .context("failed to read message from stdin")?;
+  let message = if options.ignore_dots {
+    &message
+  } else {
+    truncate_at_dot(&message)
+  };
+  let email = parse_email(message);
+
+  let address_book = maily.address_book.clone();
+  let (mut accounts, defaults, opts) = maily.into_inputs()?;
+  if options.sender.is_some() || options.full_name.is_some() {
+    for account in &mut accounts {
+      let from = compose_from(
+        account.from(),
+        options.sender.as_deref(),
+        options.full_name.as_deref(),
+      );
+      let () = account.set_from(Cow::Owned(from));
+    }
+  }
+
+  let mut recipients = options.recipients;
+  let mut blind = Vec::new();
+  if options.extract_recipients {
+    let () = recipients.extend(email.addresses(&["To", "Cc"]));
+    blind = email.addresses(&["Bcc"]);
+  }
+  let recipients = rewrite_recipients(&recipients, &defaults, &address_book)?;
+  // Blind carbon copy recipients must not show up in the message
+  // visible to others, so we send a separate copy to each of them.
+  let blind = if blind.is_empty() {
+    blind
+  } else {
+    rewrite_recipients(&blind, &defaults, &address_book)?
+      .into_iter()
+      .filter(|recipient| !recipients.contains(recipient))
+      .collect()
+  };
+  ensure!(
+    !recipients.is_empty() || !blind.is_empty(),
+    "no recipients specified"
+  );
+
+  let body = pipeline(&email.body, filters.into_iter().map(Filter::into))
+    .await
+    .context("failed to apply filters to message")?;
+
+  let visible = (!recipients.is_empty()).then_some(recipients);
+  let copies = visible
+    .into_iter()
+    .chain(blind.into_iter().map(|recipient| vec![recipient]))
+    .collect::<Vec<_>>();
+  let count = copies.len();
+  let mut failed = Vec::new();
+  // Attempt all sends before reporting failures, so that a problem with
+  // one copy does not prevent the delivery of the others.
+  for recipients in copies {
+    let result = send_email(
+      accounts.iter(),
+      &email.subject,
+      &body,
+      email.content_type.as_deref(),
+      recipients.iter(),
+      &opts,
+    )
+    .await;
+    if let Err(err) = result {
+      let () = failed.push((recipients, err));
+    }
+  }
+
+  for (recipients, err) in &failed {
+    eprintln!("{}: {err:#}", recipients.join(", "));
+  }
+  ensure!(
+    failed.is_empty(),
+    "failed to send {} of {count} copies of the email",
+    failed.len()
+  );
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+
+  /// Check that we parse sendmail style arguments correctly.
+  #[test]
+  fn argument_parsing() {
+    let args = |args: &[&str]| parse_args(args.iter().map(OsString::from));
+
+    let options = args(&["-t", "-oi"]).unwrap();
+    let expected = Options {
+      extract_recipients: true,
+      ignore_dots: true,
+      ..Default::default()
+    };
+    assert_eq!(options, expected);
+
+    let options = args(&[
+      "-FCronDaemon",
+      "-i",
+      "-B8BITMIME",
+      "-oem",
+      "-f",
+      "root",
+      "-C",
+      "/etc/maily.json",
+      "--",
+      "-admin@example.com",
+    ])
+    .unwrap();
+    let expected = Options {
+      config: Some(PathBuf::from("/etc/maily.json")),
+      ignore_dots: true,
+      sender: Some("root".to_string()),
+      full_name: Some("CronDaemon".to_string()),
+      recipients: vec!["-admin@example.com".to_string()],
+      ..Default::default()
+    };
+    assert_eq!(options, expected);
+
+    let options = args(&["-ti", "a@example.com", "b@example.com"]).unwrap();
+    assert!(options.extract_recipients);
+    assert!(options.ignore_dots);
+    assert_eq!(options.recipients, ["a@example.com", "b@example.com"]);
+
+    let err = args(&["-bp"]).unwrap_err();
+    assert_eq!(err.to_string(), "unsupported operation mode `-bp`");
+    let err = args(&["-f"]).unwrap_err();
+    assert_eq!(err.to_string(), "option `-f` requires a value");
+  }
+
+  /// Check that we compose "From" identifiers as expected.
+  #[test]
+  fn from_composition() {
+    assert_eq!(compose_from("a@example.com", None, None), "a@example.com");
+    assert_eq!(
+      compose_from("Alice <a@example.com>", Some("b@example.com"), None),
+      "Alice <b@example.com>"
+    );
+    assert_eq!(
+      compose_from("Alice <a@example.com>", Some("root"), Some("Cron \"Daemon\"")),
+      "\"Cron \\\"Daemon\\\"\" <a@example.com>"
+    );
+  }
+
+  /// Check that messages are cut off at a line with a single dot.
+  #[test]
+  fn dot_truncation() {
+    assert_eq!(truncate_at_dot(b"a\n.\nb\n"), b"a\n");
+    assert_eq!(truncate_at_dot(b"a\r\n.\r\n"), b"a\r\n");
+    assert_eq!(truncate_at_dot(b"a\n..\nb"), b"a\n..\nb");
+    assert_eq!(truncate_at_dot(b"a\n."), b"a\n");
+  }
+}
@@ -4,9 +4,15 @@
 use std::borrow::Cow;
 use std::ffi::OsStr;
 use std::ffi::OsString;
+use std::fs::read_to_string;
 use std::future::ready;
+use std::os::unix::fs::PermissionsExt as _;
+use std::path::Path;
+use std::process;
 use std::process::Output;
 use std::process::Stdio;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
 use anyhow::bail;
 use anyhow::Context as _;
@@ -17,6 +23,10 @@ use futures::future::Either;
 use futures::TryStreamExt as _;
 
 use futures::stream::FuturesUnordered;
+use tokio::fs::metadata;
+use tokio::fs::remove_file;
+use tokio::fs::rename;
+use tokio::fs::OpenOptions;
 use tokio::io::AsyncReadExt as _;
 use tokio::io::AsyncWriteExt as _;
 use tokio::process::Command;
@@ -40,7 +50,7 @@ where
 
 
 /// Format a command with the given list of arguments as a string.
-fn format_command<C, A, S>(command: C, args: A) -> String
+pub(crate) fn format_command<C, A, S>(command: C, args: A) -> String
 where
   C: AsRef<OsStr>,
   A: IntoIterator<Item = S>,
@@ -79,7 +89,7 @@ where
   Ok(())
 }
 
-pub async fn pipeline<I, E, C, A, S>(input: &[u8], commands: I) -> Result<Cow<[u8]>>
+pub async fn pipeline<I, E, C, A, S>(input: &[u8], commands: I) -> Result<Cow<'_, [u8]>>
 where
   I: IntoIterator<IntoIter = E>,
   E: ExactSizeIterator<Item = (C, A)>,
@@ -184,13 +194,128 @@ where
 }
 
 
+/// Retrieve the name of the local host, the same way the library does
+/// for `${HOSTNAME}` in configurations.
+///
+/// If the name cannot be determined, `localhost` is reported instead.
+pub(crate) fn hostname() -> String {
+  ["/proc/sys/kernel/hostname", "/etc/hostname"]
+    .into_iter()
+    .find_map(|path| {
+      let name = read_to_string(path).ok()?;
+      let name = name.trim();
+      (!name.is_empty()).then(|| name.to_string())
+    })
+    .unwrap_or_else(|| "localhost".to_string())
+}
+
+
+/// Decode standard base64 data, ignoring any invalid characters.
+///
+/// Padding as well as line breaks, as present in base64 encoded email
+/// content, are skipped over.
+pub(crate) fn decode_base64(data: &[u8]) -> Vec<u8> {
+  let mut decoded = Vec::with_capacity(data.len() * 3 / 4);
+  let mut buffer = 0u32;
+  let mut bits = 0;
+
+  for byte in data {
+    let value = match byte {
+      b'A'..=b'Z' => byte - b'A',
+      b'a'..=b'z' => byte - b'a' + 26,
+      b'0'..=b'9' => byte - b'0' + 52,
+      b'+' => 62,
+      b'/' => 63,
+      _ => continue,
+    };
+    buffer = buffer << 6 | u32::from(value);
+    bits += 6;
+    if bits >= 8 {
+      bits -= 8;
+      let () = decoded.push((buffer >> bits) as u8);
+    }
+  }
+  decoded
+}
+
+
+/// Replace the contents of the file at `path` with `data` atomically,
+/// retaining the file's permissions.
+///
+/// The data is written to a temporary file next to `path`, which is
+/// then renamed, so that `path` never contains partial data.
+pub(crate) async fn write_atomically(path: &Path, data: &[u8]) -> Result<()> {
+  let mode = metadata(path)
+    .await
+    .with_context(|| format!("failed to query metadata of `{}`", path.display()))?
+    .permissions()
+    .mode();
+  // SANITY: `path` refers to an existing file, as per the above.
+  let name = path.file_name().unwrap();
+  // SANITY: `UNIX_EPOCH` is earlier than *any* other `SystemTime`.
+  let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+  // The name is unique, so that a file left behind by a previous,
+  // interrupted run does not get in the way.
+  let mut tmp_name = OsString::from(".");
+  let () = tmp_name.push(name);
+  let () = tmp_name.push(format!(".{}.{}.tmp", process::id(), now.as_nanos()));
+  let tmp_path = path.with_file_name(tmp_name);
+
+  let mut file = OpenOptions::new()
+    .write(true)
+    .create_new(true)
+    .mode(mode & 0o7777)
+    .open(&tmp_path)
+    .await
+    .with_context(|| format!("failed to create `{}`", tmp_path.display()))?;
+
+  let result = async {
+    let () = file
+      .write_all(data)
+      .await
+      .with_context(|| format!("failed to write to `{}`", tmp_path.display()))?;
+    let () = file
+      .sync_all()
+      .await
+      .with_context(|| format!("failed to sync `{}`", tmp_path.display()))?;
+    let () = rename(&tmp_path, path).await.with_context(|| {
+      format!(
+        "failed to rename `{}` to `{}`",
+        tmp_path.display(),
+        path.display()
+      )
+    })?;
+    Ok(())
+  }
+  .await;
+
+  if result.is_err() {
+    // Don't leave the temporary file behind, but there is nothing we
+    // can do if removal fails.
+    let _result = remove_file(&tmp_path).await;
+  }
+  result
+}
+
+
 #[cfg(test)]
 mod tests {
   use super::*;
 
+  use std::fs::Permissions;
+
+  use tempfile::tempdir;
+
   use tokio::test;
 
 
+  /// Check that we can decode base64 data spread over multiple lines.
+  #[test]
+  async fn base64_decoding() {
+    assert_eq!(decode_base64(b"AHVzZXIAcGFzc3dvcmQ="), b"\0user\0password");
+    assert_eq!(decode_base64(b"Zm9v\r\nYg==\r\n"), b"foob");
+  }
+
   /// Check that we can create a proper pipeline of commands.
   #[test]
   async fn command_chaining() {
@@ -263,4 +388,30 @@ mod tests {
       .unwrap();
     assert_eq!(output.as_ref(), input);
   }
+
+  /// Check that files are replaced atomically, retaining their
+  /// permissions.
+  #[test]
+  async fn atomic_writing() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("config.json");
+    let () = std::fs::write(&path, "old").unwrap();
+    let () = std::fs::set_permissions(&path, Permissions::from_mode(0o640)).unwrap();
+
+    let () = write_atomically(&path, b"new").await.unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), b"new");
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o640);
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+    // Leftovers of an interrupted write must not cause failures.
+    let () = std::fs::write(dir.path().join(".config.json.tmp"), "stale").unwrap();
+    let () = write_atomically(&path, b"newer").await.unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), b"newer");
+
+    let err = write_atomically(&dir.path().join("missing"), b"")
+      .await
+      .unwrap_err();
+    assert!(err.to_string().starts_with("failed to query metadata"), "{err}");
+  }
 }
@@ -0,0 +1,461 @@
+// Copyright (C) 2024 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// An interactive wizard for creating an initial configuration.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::env::var;
+use std::fs::read_to_string;
+use std::path::absolute;
+use std::path::PathBuf;
+use std::process::Command as StdCommand;
+
+use anyhow::bail;
+use anyhow::Context as _;
+use anyhow::Result;
+
+use maily::check_account;
+use maily::Account;
+use maily::AddressBook;
+use maily::ErrorNotification;
+use maily::SmtpAccount;
+use maily::SmtpMode;
+use maily::CONFIG_VERSION;
+
+use tokio::io::AsyncBufRead;
+use tokio::io::AsyncBufReadExt as _;
+use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt as _;
+
+use crate::config::Config;
+
+
+/// The supported SMTP modes, along with their default ports.
+const MODES: [(&str, u16); 4] = [
+  ("starttls", 587),
+  ("tls", 465),
+  ("unencrypted", 25),
+  ("lmtp", 24),
+];
+
+/// The supported sources of passwords.
+const PASSWORD_SOURCES: [&str; 3] = ["prompt", "file", "env"];
+
+
+/// A password as provided by the user.
+#[derive(Debug)]
+struct Password {
+  /// The actual password, used for testing the account.
+  value: String,
+  /// The representation of the password to store in the
+  /// configuration: a reference to its source, if any, so that the
+  /// secret itself does not end up in the configuration file.
+  stored: String,
+}
+
+
+/// A helper for asking the user questions.
+pub(crate) struct Prompter<R, W> {
+  /// The stream to read answers from.
+  input: R,
+  /// The stream to write questions to.
+  output: W,
+  /// Whether the input is a terminal, on which echoing is disabled
+  /// while entering secrets.
+  terminal: bool,
+}
+
+impl<R, W> Prompter<R, W>
+where
+  R: AsyncBufRead + Unpin,
+  W: AsyncWrite + Unpin,
+{
+  /// Create a new `Prompter` object.
+  pub fn new(input: R, output: W, terminal: bool) -> Self {
+    Self {
+      input,
+      output,
+      terminal,
+    }
+  }
+
+  /// Write some text to the user.
+  async fn say(&mut self, text: &str) -> Result<()> {
+    let () = self
+      .output
+      .write_all(text.as_bytes())
+      .await
+      .context("failed to write prompt")?;
+    let () = self.output.flush().await.context("failed to write prompt")?;
+    Ok(())
+  }
+
+  /// Ask a question and read the (trimmed) answer, falling back to
+  /// `default` if the answer is empty.
+  async fn ask(&mut self, question: &str, default: Option<&str>) -> Result<String> {
+    loop {
+      let () = match default {
+        Some(default) if !default.is_empty() => {
+          self.say(&format!("{question} [{default}]: ")).await?
+        },
+        _ => self.say(&format!("{question}: ")).await?,
+      };
+
+      let mut line = String::new();
+      let count = self
+        .input
+        .read_line(&mut line)
+        .await
+        .context("failed to read answer")?;
+      if count == 0 {
+        bail!("unexpected end of input")
+      }
+
+      match (line.trim(), default) {
+        ("", Some(default)) => return Ok(default.to_string()),
+        ("", None) => continue,
+        (answer, _) => return Ok(answer.to_string()),
+      }
+    }
+  }
+
+  /// Ask for a secret, without echoing it back if possible.
+  async fn ask_secret(&mut self, question: &str) -> Result<String> {
+    let terminal = self.terminal;
+    let set_echo = |on: bool| {
+      if terminal {
+        // Failing to disable echoing is not a reason to give up, so
+        // errors are ignored.
+        let _status = StdCommand::new("stty")
+          .arg(if on { "echo" } else { "-echo" })
+          .status();
+      }
+    };
+
+    let () = set_echo(false);
+    let answer = self.ask(question, None).await;
+    let () = set_echo(true);
+    if self.terminal {
+      let () = self.say("\n").await?;
+    }
+    answer
+  }
+
+  /// Ask a yes/no question.
+  async fn confirm(&mut self, question: &str, default: bool) -> Result<bool> {
+    let default = if default { "y" } else { "n" };
+    loop {
+      match self.ask(&format!("{question} (y/n)"), Some(default)).await?.as_str() {
+        "y" | "yes" => return Ok(true),
+        "n" | "no" => return Ok(false),
+        _ => continue,
+      }
+    }
+  }
+
+  /// Ask the user to choose one of the given options.
+  async fn choose<'opt>(
+    &mut self,
+    question: &str,
+    options: &[&'opt str],
+    default: &str,
+  ) -> Result<&'opt str> {
+    let question = format!("{question} ({})", options.join("/"));
+    loop {
+      let answer = self.ask(&question, Some(default)).await?;
+      if let Some(option) = options.iter().find(|option| **option == answer) {
+        return Ok(option)
+      }
+    }
+  }
+
+  /// Ask for a password, using the source the user chooses.
+  ///
+  /// Passwords read from a file or an environment variable are stored
+  /// as references to them, which get resolved when the configuration
+  /// is loaded.
+  async fn ask_password(&mut self) -> Result<Password> {
+    loop {
+      let source = self
+        .choose("Password source", &PASSWORD_SOURCES, PASSWORD_SOURCES[0])
+        .await?;
+      let result = match source {
+        "prompt" => {
+          let value = self.ask_secret("Password").await?;
+          // Make sure that the password is not mistaken for a
+          // reference when loading the configuration.
+          let stored = value.replace("${", "$${");
+          return Ok(Password { value, stored })
+        },
+        "file" => {
+          let path = self.ask("Password file", None).await?;
+          absolute(&path)
+            .and_then(|path| {
+              let value = read_to_string(&path)?;
+              let password = Password {
+                value: value.trim_end_matches(['\r', '\n']).to_string(),
+                stored: format!("@file:{}", path.display()),
+              };
+              Ok(password)
+            })
+            .with_context(|| format!("failed to read password from `{path}`"))
+        },
+        "env" => {
+          let name = self.ask("Environment variable", None).await?;
+          var(&name)
+            .map(|value| Password {
+              value,
+              stored: format!("${{env:{name}}}"),
+            })
+            .with_context(|| format!("failed to read environment variable `{name}`"))
+        },
+        _ => unreachable!(),
+      };
+
+      match result {
+        Ok(password) => return Ok(password),
+        Err(err) => self.say(&format!("{err:#}\n")).await?,
+      }
+    }
+  }
+
+  /// Ask for the details of an SMTP account.
+  ///
+  /// The account to store in the configuration is returned, along with
+  /// a copy using the actual password, for testing it.
+  async fn ask_account(&mut self) -> Result<(Account<'static>, Account<'static>)> {
+    let host = self.ask("SMTP host", None).await?;
+    let modes = MODES.map(|(mode, _port)| mode);
+    let mode = self.choose("Mode", &modes, modes[0]).await?;
+    // SANITY: `choose` only returns one of the provided modes.
+    let (_, default_port) = MODES.iter().find(|(name, _)| *name == mode).unwrap();
+    let port = loop {
+      let port = self.ask("Port", Some(&default_port.to_string())).await?;
+      match port.parse::<u16>() {
+        Ok(port) => break port,
+        Err(_) => self.say(&format!("`{port}` is not a valid port\n")).await?,
+      }
+    };
+    let from = self.ask("From", None).await?;
+
+    let (user, password) = if mode == "lmtp" {
+      let password = Password {
+        value: String::new(),
+        stored: String::new(),
+      };
+      (String::new(), password)
+    } else {
+      let user = self.ask("User", None).await?;
+      let password = self.ask_password().await?;
+      (user, password)
+    };
+
+    let smtp_mode = match mode {
+      "starttls" => SmtpMode::StartTls,
+      "tls" => SmtpMode::Tls,
+      "unencrypted" => SmtpMode::Unencrypted,
+      "lmtp" => SmtpMode::Lmtp,
+      _ => unreachable!(),
+    };
+
+    let account = SmtpAccount {
+      smtp_host: Cow::Owned(host),
+      smtp_mode,
+      smtp_port: (port != *default_port).then_some(port),
+      smtp_ca_file: None,
+      from: Cow::Owned(from),
+      user: Cow::Owned(user),
+      password: Cow::Owned(password.stored),
+    };
+    let resolved = SmtpAccount {
+      password: Cow::Owned(password.value),
+      ..account.clone()
+    };
+    Ok((Account::Smtp(account), Account::Smtp(resolved)))
+  }
+}
+
+
+/// Interactively create a configuration.
+///
+/// If `check` is `true`, a connection to each account is established
+/// to test it.
+pub(crate) async fn run<R, W>(prompter: &mut Prompter<R, W>, check: bool) -> Result<Config>
+where
+  R: AsyncBufRead + Unpin,
+  W: AsyncWrite + Unpin,
+{
+  let mut accounts = Vec::new();
+  loop {
+    let (account, resolved) = prompter.ask_account().await?;
+    if check {
+      let () = prompter.say("Testing account... ").await?;
+      match check_account(&resolved, &[] as &[&str]).await {
+        Ok(()) => {
+          let () = prompter.say("ok\n").await?;
+          let () = accounts.push(account);
+        },
+        Err(err) => {
+          let () = prompter.say(&format!("failed: {err:#}\n")).await?;
+          if prompter.confirm("Keep account anyway?", false).await? {
+            let () = accounts.push(account);
+          }
+        },
+      }
+    } else {
+      let () = accounts.push(account);
+    }
+
+    if !accounts.is_empty() && !prompter.confirm("Add another account?", false).await? {
+      break
+    }
+  }
+
+  let recipients = loop {
+    let recipients = prompter.ask("Recipients (comma separated)", None).await?;
+    let recipients = recipients
+      .split(',')
+      .map(str::trim)
+      .filter(|recipient| !recipient.is_empty())
+      .map(str::to_string)
+      .collect::<Vec<_>>();
+    if !recipients.is_empty() {
+      break recipients
+    }
+  };
+
+  let keybox = prompter.ask("PGP keybox (empty for none)", Some("")).await?;
+  let pgp_keybox = (!keybox.is_empty()).then(|| PathBuf::from(keybox));
+
+  let maily = maily::Config {
+    version: CONFIG_VERSION,
+    accounts,
+    recipients,
+    pgp_keybox,
+    error_notification: ErrorNotification::default(),
+    fallback: Vec::new(),
+    queue: None,
+    queue_expiry: None,
+    subject_prefix: None,
+    profiles: BTreeMap::new(),
+    address_book: AddressBook::default(),
+  };
+
+  let report = maily.validate(false).await;
+  if !report.is_ok() {
+    let errors = report
+      .errors
+      .iter()
+      .chain(report.accounts.iter().flat_map(|account| &account.errors))
+      .map(|err| format!("{err:#}"))
+      .collect::<Vec<_>>();
+    bail!("created configuration is invalid:\n{}", errors.join("\n"))
+  }
+
+  let config = Config {
+    maily,
+    filters: Vec::new(),
+    relay: None,
+  };
+  Ok(config)
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::fs::write;
+
+  use serde_json::to_value as to_json;
+
+  use tempfile::tempdir;
+
+
+  /// Check that we can create a configuration interactively.
+  #[tokio::test]
+  async fn configuration_creation() {
+    let dir = tempdir().unwrap();
+    let secret = dir.path().join("secret");
+    let () = write(&secret, "from-file\n").unwrap();
+
+    let input = format!(
+      "\
+smtp.example.com
+
+465
+Sender <sender@example.com>
+sender@example.com
+secret
+prompt
+hunter${{2
+y
+lmtp.example.com
+lmtp
+24
+local@example.com
+y
+file.example.com
+tls
+
+file@example.com
+file@example.com
+file
+{}
+y
+env.example.com
+
+
+env@example.com
+env@example.com
+env
+PATH
+n
+a@example.com, b@example.com
+
+",
+      secret.display()
+    );
+    let mut output = Vec::new();
+    let mut prompter = Prompter::new(input.as_bytes(), &mut output, false);
+    let config = run(&mut prompter, false).await.unwrap();
+
+    let json = to_json(&config).unwrap();
+    let expected = serde_json::json!({
+      "version": CONFIG_VERSION,
+      "accounts": [{
+        "smtp_host": "smtp.example.com",
+        "smtp_mode": "starttls",
+        "smtp_port": 465,
+        "from": "Sender <sender@example.com>",
+        "user": "sender@example.com",
+        "password": "hunter$${2",
+      }, {
+        "smtp_host": "lmtp.example.com",
+        "smtp_mode": "lmtp",
+        "from": "local@example.com",
+        "user": "",
+        "password": "",
+      }, {
+        "smtp_host": "file.example.com",
+        "smtp_mode": "tls",
+        "from": "file@example.com",
+        "user": "file@example.com",
+        "password": format!("@file:{}", secret.display()),
+      }, {
+        "smtp_host": "env.example.com",
+        "smtp_mode": "starttls",
+        "from": "env@example.com",
+        "user": "env@example.com",
+        "password": "${env:PATH}",
+      }],
+      "recipients": ["a@example.com", "b@example.com"],
+      "error_notification": json["error_notification"],
+    });
+    assert_eq!(json, expected);
+
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.starts_with("SMTP host: Mode (starttls/tls/unencrypted/lmtp) [starttls]: "));
+  }
+}
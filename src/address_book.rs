@@ -0,0 +1,174 @@
+// Copyright (C) 2024 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+
+use anyhow::anyhow;
+use anyhow::Result;
+
+use lettre::message::Mailbox;
+
+#[cfg(feature = "config")]
+use serde::Deserialize;
+#[cfg(feature = "config")]
+use serde::Serialize;
+
+
+/// A book of named recipients, allowing recipients to be referenced by
+/// name instead of by email address.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "config", derive(Deserialize, Serialize))]
+pub struct AddressBook {
+  /// Names of individual recipients, mapped to their email address
+  /// specification, e.g., `Jane Doe <jane@example.com>`.
+  #[cfg_attr(
+    feature = "config",
+    serde(default, skip_serializing_if = "BTreeMap::is_empty")
+  )]
+  pub aliases: BTreeMap<String, String>,
+  /// Names of groups of recipients, mapped to their members.
+  ///
+  /// Members may be email addresses, aliases, or other groups.
+  #[cfg_attr(
+    feature = "config",
+    serde(default, skip_serializing_if = "BTreeMap::is_empty")
+  )]
+  pub groups: BTreeMap<String, Vec<String>>,
+}
+
+impl AddressBook {
+  /// Check whether `name` is the name of an alias or group.
+  pub fn contains(&self, name: &str) -> bool {
+    self.aliases.contains_key(name) || self.groups.contains_key(name)
+  }
+
+  /// Expand the recipient `name` into `resolved`.
+  ///
+  /// `stack` contains the groups currently being expanded.
+  fn expand<'book>(
+    &'book self,
+    name: &'book str,
+    stack: &mut Vec<&'book str>,
+    resolved: &mut Vec<String>,
+  ) -> Result<()> {
+    if let Some((name, members)) = self.groups.get_key_value(name) {
+      if stack.contains(&name.as_str()) {
+        let () = stack.push(name);
+        return Err(anyhow!(
+          "recipient group `{name}` includes itself (via {})",
+          stack
+            .iter()
+            .map(|name| format!("`{name}`"))
+            .collect::<Vec<_>>()
+            .join(" -> ")
+        ))
+      }
+
+      let () = stack.push(name);
+      for member in members {
+        let () = self.expand(member, stack, resolved)?;
+      }
+      let _name = stack.pop();
+    } else if let Some(alias) = self.aliases.get(name) {
+      let () = resolved.push(alias.clone());
+    } else {
+      let () = resolved.push(name.to_string());
+    }
+    Ok(())
+  }
+
+  /// Resolve the provided recipients.
+  ///
+  /// Names of groups are replaced with the group's members, recursively,
+  /// and aliases with the email address specification they represent.
+  /// All other recipients are taken as-is. Recipients with the same
+  /// email address are only reported once.
+  pub fn resolve<R, S>(&self, recipients: R) -> Result<Vec<String>>
+  where
+    R: IntoIterator<Item = S>,
+    S: AsRef<str>,
+  {
+    let mut resolved = Vec::new();
+    for recipient in recipients {
+      let () = self.expand(recipient.as_ref(), &mut Vec::new(), &mut resolved)?;
+    }
+    Ok(dedup_recipients(resolved))
+  }
+}
+
+
+/// Remove recipients with duplicate email addresses, retaining the
+/// first occurrence.
+///
+/// Domains are compared case-insensitively. Recipients that cannot be
+/// parsed are compared verbatim.
+pub(crate) fn dedup_recipients<I>(recipients: I) -> Vec<String>
+where
+  I: IntoIterator<Item = String>,
+{
+  let mut seen = HashSet::new();
+  recipients
+    .into_iter()
+    .filter(|recipient| {
+      let key = match recipient.parse::<Mailbox>() {
+        Ok(mailbox) => format!(
+          "{}@{}",
+          mailbox.email.user(),
+          mailbox.email.domain().to_ascii_lowercase()
+        ),
+        Err(_) => recipient.clone(),
+      };
+      seen.insert(key)
+    })
+    .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+
+  /// Check that recipients are resolved as expected.
+  #[test]
+  fn resolution() {
+    let book = AddressBook {
+      aliases: BTreeMap::from([
+        ("jane".to_string(), "Jane Doe <jane@example.com>".to_string()),
+        ("john".to_string(), "john@example.com".to_string()),
+      ]),
+      groups: BTreeMap::from([
+        ("dba".to_string(), vec!["jane".to_string(), "dba@example.com".to_string()]),
+        (
+          "oncall".to_string(),
+          vec!["dba".to_string(), "john".to_string(), "JANE@EXAMPLE.COM".to_string()],
+        ),
+      ]),
+    };
+
+    let resolved = book.resolve(["oncall", "john@Example.com", "x@example.com"]).unwrap();
+    assert_eq!(
+      resolved,
+      [
+        "Jane Doe <jane@example.com>",
+        "dba@example.com",
+        "john@example.com",
+        "JANE@EXAMPLE.COM",
+        "x@example.com",
+      ]
+    );
+    assert!(book.contains("jane"));
+    assert!(!book.contains("jane@example.com"));
+
+    let mut book = book;
+    let _members = book
+      .groups
+      .insert("dba".to_string(), vec!["oncall".to_string()]);
+    let err = book.resolve(["oncall"]).unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "recipient group `oncall` includes itself (via `oncall` -> `dba` -> `oncall`)"
+    );
+  }
+}
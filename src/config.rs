@@ -2,13 +2,16 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
 use std::borrow::Cow;
+use std::path::Path;
 
 #[cfg(feature = "config")]
 use serde::Deserialize;
+#[cfg(feature = "config")]
+use serde::Serialize;
 
 
 #[derive(Clone, Copy, Debug)]
-#[cfg_attr(feature = "config", derive(Deserialize))]
+#[cfg_attr(feature = "config", derive(Deserialize, Serialize))]
 #[non_exhaustive]
 pub enum SmtpMode {
   /// Use unencrypted SMTP (typically on port 25).
@@ -20,17 +23,39 @@ pub enum SmtpMode {
   /// Use full TLS mode (often on port 465).
   #[cfg_attr(feature = "config", serde(rename = "tls"))]
   Tls,
+  /// Use LMTP to deliver directly to a mail store, such as Dovecot or
+  /// Cyrus.
+  ///
+  /// In this mode the host may either be given as `host[:port]` (with
+  /// port 24 being the default) or as the absolute path to a Unix
+  /// domain socket. No authentication is performed.
+  #[cfg_attr(feature = "config", serde(rename = "lmtp"))]
+  Lmtp,
 }
 
 
-/// A type representing a single email account.
+/// A type representing an email account reachable via SMTP.
 #[derive(Clone, Debug)]
-#[cfg_attr(feature = "config", derive(Deserialize))]
-pub struct Account<'input> {
+#[cfg_attr(feature = "config", derive(Deserialize, Serialize))]
+pub struct SmtpAccount<'input> {
   /// The hostname of the SMTP server.
   pub smtp_host: Cow<'input, str>,
   /// The SMTP "mode" to use.
   pub smtp_mode: SmtpMode,
+  /// The port to connect to, if different from the default one of the
+  /// SMTP mode in use.
+  #[cfg_attr(
+    feature = "config",
+    serde(default, skip_serializing_if = "Option::is_none")
+  )]
+  pub smtp_port: Option<u16>,
+  /// The path to a PEM encoded CA certificate to trust in addition to
+  /// the system's ones when establishing TLS connections.
+  #[cfg_attr(
+    feature = "config",
+    serde(default, skip_serializing_if = "Option::is_none")
+  )]
+  pub smtp_ca_file: Option<Cow<'input, Path>>,
   /// The "From" identifier to use.
   pub from: Cow<'input, str>,
   /// The user to log in as.
@@ -40,29 +65,531 @@ pub struct Account<'input> {
 }
 
 
+/// The format of a local mailbox.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "config", derive(Deserialize, Serialize))]
+#[non_exhaustive]
+pub enum MailboxFormat {
+  /// A Maildir directory, with one file per message.
+  #[cfg_attr(feature = "config", serde(rename = "maildir"))]
+  Maildir,
+  /// An mbox file, with all messages appended to a single file.
+  #[cfg_attr(feature = "config", serde(rename = "mbox"))]
+  Mbox,
+}
+
+
+/// A type representing an "account" delivering emails into a local
+/// mailbox instead of sending them over the network.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "config", derive(Deserialize, Serialize))]
+pub struct LocalAccount<'input> {
+  /// The path to the mailbox.
+  ///
+  /// For a Maildir this is the directory containing the `tmp`, `new`,
+  /// and `cur` sub-directories, which will be created as necessary.
+  pub mailbox: Cow<'input, Path>,
+  /// The format of the mailbox.
+  pub mailbox_format: MailboxFormat,
+  /// The "From" identifier to use.
+  pub from: Cow<'input, str>,
+}
+
+
+/// A type representing an account of a JMAP (RFC 8620 & RFC 8621)
+/// based email provider.
+#[cfg(feature = "jmap")]
+#[cfg_attr(docsrs, doc(cfg(feature = "jmap")))]
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "config", derive(Deserialize, Serialize))]
+pub struct JmapAccount<'input> {
+  /// The URL of the JMAP session resource, e.g.,
+  /// `https://jmap.example.com/.well-known/jmap`.
+  pub jmap_url: Cow<'input, str>,
+  /// The "From" identifier to use.
+  pub from: Cow<'input, str>,
+  /// The user to log in as.
+  ///
+  /// If empty, `password` is used as a bearer token instead of for
+  /// basic authentication.
+  pub user: Cow<'input, str>,
+  /// The password (or bearer token) to use for logging in.
+  pub password: Cow<'input, str>,
+}
+
+
+/// A type representing an "account" delivering emails directly to the
+/// mail exchangers of the recipients' domains, without going through a
+/// relay.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "config", derive(Deserialize, Serialize))]
+pub struct MxAccount<'input> {
+  /// The DNS resolver to use for looking up MX records.
+  ///
+  /// This is either an `ip[:port]` address or the path to a file in
+  /// `resolv.conf` format (typically `/etc/resolv.conf`), in which case
+  /// the first name server listed in it is used.
+  pub mx_resolver: Cow<'input, str>,
+  /// The port to connect to on mail exchangers; defaults to 25.
+  #[cfg_attr(
+    feature = "config",
+    serde(default, skip_serializing_if = "Option::is_none")
+  )]
+  pub mx_port: Option<u16>,
+  /// The "From" identifier to use.
+  pub from: Cow<'input, str>,
+}
+
+
+/// A type representing a single email account.
+///
+/// When deserialized, the kind of account is determined by its `type`
+/// attribute, one of `smtp`, `local`, `jmap`, or `mx`. If that is not
+/// present, it is inferred from the account's attributes, defaulting
+/// to SMTP.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "config", derive(Serialize))]
+#[cfg_attr(feature = "config", serde(untagged))]
+#[non_exhaustive]
+pub enum Account<'input> {
+  /// An account reachable via SMTP.
+  Smtp(SmtpAccount<'input>),
+  /// A local mailbox.
+  Local(LocalAccount<'input>),
+  /// An account reachable via JMAP.
+  #[cfg(feature = "jmap")]
+  #[cfg_attr(docsrs, doc(cfg(feature = "jmap")))]
+  Jmap(JmapAccount<'input>),
+  /// Direct delivery to the recipients' mail exchangers.
+  Mx(MxAccount<'input>),
+}
+
+impl<'input> Account<'input> {
+  /// Retrieve the "From" identifier used by the account.
+  pub fn from(&self) -> &str {
+    match self {
+      Self::Smtp(account) => &account.from,
+      Self::Local(account) => &account.from,
+      #[cfg(feature = "jmap")]
+      Self::Jmap(account) => &account.from,
+      Self::Mx(account) => &account.from,
+    }
+  }
+
+  /// Set the "From" identifier used by the account.
+  pub fn set_from(&mut self, from: Cow<'input, str>) {
+    match self {
+      Self::Smtp(account) => account.from = from,
+      Self::Local(account) => account.from = from,
+      #[cfg(feature = "jmap")]
+      Self::Jmap(account) => account.from = from,
+      Self::Mx(account) => account.from = from,
+    }
+  }
+}
+
+
+/// The point in time at which to send a notification about failed
+/// attempts at sending an email.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "config", derive(Deserialize, Serialize))]
+#[cfg_attr(feature = "config", serde(rename_all = "kebab-case"))]
+#[non_exhaustive]
+pub enum NotifyWhen {
+  /// Send a notification right after an attempt failed, using the next
+  /// account to be tried.
+  #[default]
+  Immediately,
+  /// Send a notification only once the email was eventually sent,
+  /// using the account that succeeded.
+  OnSuccess,
+  /// Send a notification only if sending the email failed with all
+  /// accounts.
+  OnFailure,
+  /// Send a notification once the email was eventually sent or failed
+  /// to be sent with all accounts.
+  Finally,
+}
+
+
+/// Configuration of the notification sent when an attempt at sending an
+/// email failed.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "config", derive(Deserialize, Serialize))]
+#[cfg_attr(feature = "config", serde(default))]
+pub struct ErrorNotification<'input> {
+  /// Whether to send notifications at all.
+  pub enabled: bool,
+  /// The recipients to send notifications to.
+  ///
+  /// If empty, notifications are sent to the recipients of the
+  /// original email.
+  pub recipients: Vec<Cow<'input, str>>,
+  /// The subject to use for notifications.
+  pub subject: Cow<'input, str>,
+  /// Whether to append the subject of the original email to the
+  /// notification's subject.
+  pub include_subject: bool,
+  /// The template for the body of notifications.
+  ///
+  /// The placeholders `{error}` and `{subject}` are replaced with the
+  /// error(s) encountered and the subject of the original email,
+  /// respectively.
+  pub body: Cow<'input, str>,
+  /// When to send notifications.
+  pub when: NotifyWhen,
+  /// Whether to PGP encrypt notifications, if the original email is
+  /// encrypted.
+  ///
+  /// Notifications are commonly sent to administrators, whose keys may
+  /// not be contained in the keybox in use, so this is off by default.
+  #[cfg(feature = "pgp")]
+  #[cfg_attr(docsrs, doc(cfg(feature = "pgp")))]
+  pub encrypt: bool,
+}
+
+impl ErrorNotification<'_> {
+  /// Render the subject of a notification for an email with the given
+  /// subject.
+  pub(crate) fn render_subject(&self, subject: &str) -> String {
+    if self.include_subject {
+      format!("{}: {subject}", self.subject)
+    } else {
+      self.subject.to_string()
+    }
+  }
+
+  /// Render the body of a notification for an email with the given
+  /// subject, reporting `error`.
+  pub(crate) fn render_body(&self, subject: &str, error: &str) -> String {
+    let mut body = String::with_capacity(self.body.len() + error.len());
+    let mut rest = self.body.as_ref();
+    // Substitute placeholders in a single pass, so that placeholders
+    // contained in the subject or error are left untouched.
+    while let Some(idx) = rest.find('{') {
+      let () = body.push_str(&rest[..idx]);
+      rest = &rest[idx..];
+
+      if let Some(remainder) = rest.strip_prefix("{subject}") {
+        let () = body.push_str(subject);
+        rest = remainder;
+      } else if let Some(remainder) = rest.strip_prefix("{error}") {
+        let () = body.push_str(error);
+        rest = remainder;
+      } else {
+        let () = body.push('{');
+        rest = &rest[1..];
+      }
+    }
+    let () = body.push_str(rest);
+    body
+  }
+}
+
+impl Default for ErrorNotification<'_> {
+  fn default() -> Self {
+    Self {
+      enabled: true,
+      recipients: Vec::new(),
+      subject: Cow::Borrowed("email error"),
+      include_subject: false,
+      body: Cow::Borrowed("{error}"),
+      when: NotifyWhen::default(),
+      #[cfg(feature = "pgp")]
+      encrypt: false,
+    }
+  }
+}
+
+
+/// A last-resort destination for emails that could not be sent with
+/// any of the configured accounts.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "config", derive(Deserialize, Serialize))]
+#[cfg_attr(feature = "config", serde(rename_all = "kebab-case"))]
+#[non_exhaustive]
+pub enum FallbackSink<'input> {
+  /// Append the email to the plain file at the given path.
+  File(Cow<'input, Path>),
+  /// Append the email to the mbox file at the given path.
+  Mbox(Cow<'input, Path>),
+  /// Log the email, line by line, to the local syslog daemon via
+  /// `/dev/log`.
+  Syslog,
+  /// Log the email to the systemd journal via its native socket.
+  ///
+  /// Emails larger than 128 KiB are truncated, as larger journal
+  /// entries don't fit into a single datagram.
+  Journal,
+  /// Write the email to standard error.
+  Stderr,
+}
+
+
 #[cfg(feature = "config")]
 #[cfg_attr(docsrs, doc(cfg(feature = "config")))]
 mod implementation {
   use super::*;
 
+  use std::collections::BTreeMap;
+  use std::env::var_os;
+  use std::env::vars_os;
+  use std::ffi::OsString;
+  use std::fs::read_dir;
+  use std::fs::read_to_string;
+  use std::iter::once;
   use std::marker::PhantomData;
-  use std::path::Path;
+  use std::mem::take;
   use std::path::PathBuf;
+  #[cfg(feature = "queue")]
+  use std::time::Duration;
 
+  use anyhow::anyhow;
   use anyhow::Context as _;
+  use anyhow::Error;
   use anyhow::Result;
 
+  use lettre::message::Mailbox;
+
+  use serde::de::DeserializeOwned;
+  use serde::de::Error as _;
+  use serde::de::Unexpected;
+  use serde::Deserializer;
+
   use serde_json::from_slice as from_json;
+  use serde_json::from_str as from_json_str;
+  use serde_json::from_value as from_json_value;
+  use serde_json::to_vec_pretty as to_json;
+  use serde_json::Map;
+  use serde_json::Value;
 
   use tokio::fs::read;
 
+  use crate::check_account;
+  use crate::util::hostname;
+  use crate::AddressBook;
   use crate::EmailOpts;
 
 
-  /// A type representing a deserializable configuration for the
+  /// Parse an email address specification, as used for the "From"
+  /// identifier and recipients.
+  fn parse_mailbox(spec: &str, what: &str) -> Result<Mailbox> {
+    spec
+      .parse::<Mailbox>()
+      .with_context(|| format!("failed to parse {what} specification: `{spec}`"))
+  }
+
+
+  /// Describe where emails sent via `account` end up.
+  fn describe_target(account: &Account<'_>) -> String {
+    match account {
+      Account::Smtp(account) => {
+        let scheme = match account.smtp_mode {
+          SmtpMode::Unencrypted => "smtp",
+          SmtpMode::StartTls => "smtp+starttls",
+          SmtpMode::Tls => "smtps",
+          SmtpMode::Lmtp => "lmtp",
+        };
+        match account.smtp_port {
+          Some(port) => format!("{scheme}://{}:{port}", account.smtp_host),
+          None => format!("{scheme}://{}", account.smtp_host),
+        }
+      },
+      Account::Local(account) => {
+        let format = match account.mailbox_format {
+          MailboxFormat::Maildir => "maildir",
+          MailboxFormat::Mbox => "mbox",
+        };
+        format!("{format}:{}", account.mailbox.display())
+      },
+      #[cfg(feature = "jmap")]
+      Account::Jmap(account) => account.jmap_url.to_string(),
+      Account::Mx(account) => format!("mx (resolver {})", account.mx_resolver),
+    }
+  }
+
+
+  /// Find the account referenced by its "From" identifier or the
+  /// latter's email address.
+  fn find_account<'acc>(
+    accounts: &'acc [Account<'static>],
+    reference: &str,
+  ) -> Result<&'acc Account<'static>> {
+    accounts
+      .iter()
+      .find(|account| {
+        let from = account.from();
+        from == reference
+          || from
+            .parse::<Mailbox>()
+            .is_ok_and(|mailbox| mailbox.email.to_string() == reference)
+      })
+      .with_context(|| format!("no account with 'From' identifier `{reference}` configured"))
+  }
+
+
+  impl<'de> Deserialize<'de> for Account<'_> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+      D: Deserializer<'de>,
+    {
+      let mut account = Map::<String, Value>::deserialize(deserializer)?;
+      let type_ = match account.remove("type") {
+        Some(Value::String(type_)) => type_,
+        Some(type_) => {
+          return Err(D::Error::invalid_type(
+            Unexpected::Other(&type_.to_string()),
+            &"an account type",
+          ))
+        },
+        // Accounts used to be SMTP accounts exclusively, so that is
+        // what we fall back to if no other kind is apparent.
+        None if account.contains_key("mailbox") => "local".to_string(),
+        None if account.contains_key("jmap_url") => "jmap".to_string(),
+        None if account.contains_key("mx_resolver") => "mx".to_string(),
+        None => "smtp".to_string(),
+      };
+
+      let account = Value::Object(account);
+      let result = match type_.as_str() {
+        "smtp" => from_json_value(account).map(Self::Smtp),
+        "local" => from_json_value(account).map(Self::Local),
+        #[cfg(feature = "jmap")]
+        "jmap" => from_json_value(account).map(Self::Jmap),
+        "mx" => from_json_value(account).map(Self::Mx),
+        _ => {
+          return Err(D::Error::unknown_variant(
+            &type_,
+            &["smtp", "local", "jmap", "mx"],
+          ))
+        },
+      };
+      result.map_err(|err| D::Error::custom(format!("invalid {type_} account: {err}")))
+    }
+  }
+
+
+  /// The outcome of validating a single account, as part of a
+  /// [`ValidationReport`].
+  #[derive(Debug)]
+  #[non_exhaustive]
+  pub struct AccountReport {
+    /// The "From" identifier of the account.
+    pub from: String,
+    /// A human readable description of where emails sent via the
+    /// account end up.
+    pub target: String,
+    /// Whether a connection to the account was attempted.
+    pub connected: bool,
+    /// The problems found with the account.
+    pub errors: Vec<Error>,
+  }
+
+
+  /// The outcome of [`Config::validate`].
+  #[derive(Debug, Default)]
+  #[non_exhaustive]
+  pub struct ValidationReport {
+    /// Problems with the configuration that are not specific to a
+    /// single account.
+    pub errors: Vec<Error>,
+    /// The per-account results, in the order the accounts are
+    /// configured.
+    pub accounts: Vec<AccountReport>,
+  }
+
+  impl ValidationReport {
+    /// Check whether the validation found no problems.
+    pub fn is_ok(&self) -> bool {
+      self.errors.is_empty() && self.accounts.iter().all(|account| account.errors.is_empty())
+    }
+  }
+
+
+  /// A named set of overrides of [`Config`] attributes, used for
+  /// sending a certain class of emails.
+  ///
+  /// Attributes that are not provided are inherited from the
+  /// configuration.
+  #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+  pub struct Profile {
+    /// The recipients to send each email to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recipients: Option<Vec<String>>,
+    /// The accounts to use, in order, referenced by their "From"
+    /// identifier or its email address.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accounts: Option<Vec<String>>,
+    /// PGP encrypt any email using the provided keybox file.
+    #[cfg(feature = "pgp")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "pgp")))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pgp_keybox: Option<PathBuf>,
+    /// Whether to PGP encrypt emails at all. If `false`, no keybox is
+    /// used, even if one is configured.
+    #[cfg(feature = "pgp")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "pgp")))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encrypt: Option<bool>,
+    /// The prefix to add to the subject of each email.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject_prefix: Option<String>,
+    /// Attributes not known to [`Profile`], such as overrides of
+    /// attributes belonging to applications embedding the
+    /// configuration.
+    #[serde(flatten)]
+    pub extensions: BTreeMap<String, Value>,
+  }
+
+
+  /// How the value of an environment variable overriding a
+  /// configuration attribute is interpreted.
+  #[derive(Clone, Copy, Debug, PartialEq)]
+  pub(super) enum EnvKind {
+    /// The value is used verbatim.
+    String,
+    /// The value is a JSON array or a comma separated list of strings.
+    List,
+    /// The value is JSON.
+    Json,
+  }
+
+
+  /// The attributes of [`Config`] that do not hold strings, with `*`
+  /// matching any key.
+  ///
+  /// This list has to be kept in sync with the fields below, which the
+  /// `env_schema` test checks.
+  const ENV_SCHEMA: [(&str, EnvKind); 18] = [
+    ("version", EnvKind::Json),
+    ("accounts", EnvKind::Json),
+    ("recipients", EnvKind::List),
+    ("error_notification", EnvKind::Json),
+    ("error_notification.enabled", EnvKind::Json),
+    ("error_notification.recipients", EnvKind::List),
+    ("error_notification.include_subject", EnvKind::Json),
+    ("error_notification.encrypt", EnvKind::Json),
+    ("fallback", EnvKind::Json),
+    ("queue_expiry", EnvKind::Json),
+    ("profiles", EnvKind::Json),
+    ("profiles.*", EnvKind::Json),
+    ("profiles.*.recipients", EnvKind::List),
+    ("profiles.*.accounts", EnvKind::List),
+    ("profiles.*.encrypt", EnvKind::Json),
+    ("aliases", EnvKind::Json),
+    ("groups", EnvKind::Json),
+    ("groups.*", EnvKind::List),
+  ];
+
+
+  /// A type representing a (de)serializable configuration for the
   /// email sending functionality.
-  #[derive(Debug, Deserialize)]
+  #[derive(Debug, Deserialize, Serialize)]
   pub struct Config {
+    /// The version of the schema the configuration adheres to (see
+    /// [`CONFIG_VERSION`]).
+    #[serde(default)]
+    pub version: u32,
     /// The known accounts.
     pub accounts: Vec<Account<'static>>,
     /// The list of (default) recipients to send each email to.
@@ -73,8 +600,39 @@ mod implementation {
     /// provided recipients.
     #[cfg(feature = "pgp")]
     #[cfg_attr(docsrs, doc(cfg(feature = "pgp")))]
-    #[serde(alias = "pgp-keybox")]
+    #[serde(alias = "pgp-keybox", skip_serializing_if = "Option::is_none")]
     pub pgp_keybox: Option<PathBuf>,
+    /// Configuration of the notification sent when an attempt at
+    /// sending an email failed.
+    #[serde(default)]
+    pub error_notification: ErrorNotification<'static>,
+    /// The sinks to store emails in that could not be sent with any of
+    /// the accounts.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fallback: Vec<FallbackSink<'static>>,
+    /// The directory of the queue to add emails to that could not be
+    /// sent with any of the accounts.
+    #[cfg(feature = "queue")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "queue")))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queue: Option<PathBuf>,
+    /// The number of seconds after which queued emails expire and are
+    /// dropped instead of being sent.
+    #[cfg(feature = "queue")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "queue")))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queue_expiry: Option<u64>,
+    /// The prefix to add to the subject of each email.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject_prefix: Option<String>,
+    /// Named sets of overrides of the above attributes, for sending
+    /// different classes of emails.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, Profile>,
+    /// The aliases and groups recipients may be referenced by, in the
+    /// form of the `aliases` and `groups` attributes.
+    #[serde(flatten)]
+    pub address_book: AddressBook,
   }
 
   impl Config {
@@ -82,47 +640,1634 @@ mod implementation {
     /// inputs to email sending APIs such as
     /// [`send_email`][crate::send_email].
     ///
+    /// Recipients, including those of error notifications, are
+    /// resolved via the address book (see [`AddressBook::resolve`]).
+    ///
     /// # Returns
     /// The function returns a tuple comprised of a list of accounts, a
     /// list of recipients, and an [`EmailOpts`] object.
-    pub fn into_inputs(self) -> (Vec<Account<'static>>, Vec<String>, EmailOpts<'static>) {
+    pub fn into_inputs(self) -> Result<(Vec<Account<'static>>, Vec<String>, EmailOpts<'static>)> {
       let Self {
+        version: _,
         accounts,
         recipients,
         #[cfg(feature = "pgp")]
         pgp_keybox,
+        mut error_notification,
+        fallback,
+        #[cfg(feature = "queue")]
+        queue,
+        #[cfg(feature = "queue")]
+        queue_expiry,
+        subject_prefix,
+        profiles: _,
+        address_book,
       } = self;
 
+      let recipients = address_book.resolve(&recipients)?;
+      error_notification.recipients = address_book
+        .resolve(&error_notification.recipients)
+        .context("failed to resolve error notification recipients")?
+        .into_iter()
+        .map(Cow::Owned)
+        .collect();
+
       let opts = EmailOpts {
         #[cfg(feature = "pgp")]
         pgp_keybox: pgp_keybox.map(Cow::Owned),
+        attachments: Vec::new(),
+        error_notification,
+        fallback,
+        #[cfg(feature = "queue")]
+        queue: queue.map(Cow::Owned),
+        #[cfg(feature = "queue")]
+        queue_expiry: queue_expiry.map(Duration::from_secs),
+        subject_prefix: subject_prefix.map(Cow::Owned),
         _phantom: PhantomData,
       };
 
-      (accounts, recipients, opts)
+      Ok((accounts, recipients, opts))
+    }
+
+    /// Override attributes of this configuration with those provided
+    /// by `profile`.
+    ///
+    /// Accounts referenced by the profile are selected in the order
+    /// given, with an error being reported for references not matching
+    /// any configured account.
+    pub fn apply_profile(&mut self, profile: &Profile) -> Result<()> {
+      let Profile {
+        recipients,
+        accounts,
+        #[cfg(feature = "pgp")]
+        pgp_keybox,
+        #[cfg(feature = "pgp")]
+        encrypt,
+        subject_prefix,
+        extensions: _,
+      } = profile;
+
+      if let Some(accounts) = accounts {
+        let selected = accounts
+          .iter()
+          .map(|reference| find_account(&self.accounts, reference).cloned())
+          .collect::<Result<Vec<_>>>()?;
+        self.accounts = selected;
+      }
+      if let Some(recipients) = recipients {
+        self.recipients = recipients.clone();
+      }
+      #[cfg(feature = "pgp")]
+      {
+        if let Some(pgp_keybox) = pgp_keybox {
+          self.pgp_keybox = Some(pgp_keybox.clone());
+        }
+        if *encrypt == Some(false) {
+          self.pgp_keybox = None;
+        }
+      }
+      if let Some(subject_prefix) = subject_prefix {
+        self.subject_prefix = Some(subject_prefix.clone());
+      }
+      Ok(())
+    }
+
+    /// Destruct this object into inputs to email sending APIs, just
+    /// like [`into_inputs`][Self::into_inputs], after applying the
+    /// profile with the given name (see
+    /// [`apply_profile`][Self::apply_profile]).
+    pub fn into_profile_inputs(
+      mut self,
+      profile: &str,
+    ) -> Result<(Vec<Account<'static>>, Vec<String>, EmailOpts<'static>)> {
+      let selected = self
+        .profiles
+        .get(profile)
+        .cloned()
+        .with_context(|| format!("profile `{profile}` is not defined"))?;
+      let () = self.apply_profile(&selected)?;
+      self.into_inputs()
+    }
+
+    /// Resolve `recipients` via the address book and parse the result,
+    /// recording any problems in `errors`.
+    ///
+    /// # Returns
+    /// The function returns the resolved recipients that are valid.
+    fn check_recipients<R, S>(
+      &self,
+      recipients: R,
+      what: &str,
+      errors: &mut Vec<Error>,
+    ) -> Vec<String>
+    where
+      R: IntoIterator<Item = S>,
+      S: AsRef<str>,
+    {
+      let resolved = match self.address_book.resolve(recipients) {
+        Ok(resolved) => resolved,
+        Err(err) => {
+          let () = errors.push(err);
+          return Vec::new()
+        },
+      };
+
+      resolved
+        .into_iter()
+        .filter(|recipient| match parse_mailbox(recipient, what) {
+          Ok(_mailbox) => true,
+          Err(err) => {
+            let () = errors.push(err);
+            false
+          },
+        })
+        .collect()
+    }
+
+    /// Validate the configuration.
+    ///
+    /// All email addresses are parsed and, if PGP encryption is
+    /// configured, the keybox is checked for usable encryption keys
+    /// for each recipient. If `connect` is `true`, a connection to
+    /// each account is established (and authenticated) as well, but
+    /// no email is sent.
+    pub async fn validate(&self, connect: bool) -> ValidationReport {
+      let mut report = ValidationReport::default();
+
+      if self.version > CONFIG_VERSION {
+        let () = report.errors.push(unsupported_version(self.version));
+      }
+      if self.accounts.is_empty() {
+        let () = report.errors.push(anyhow!("no email accounts configured"));
+      }
+      if self.recipients.is_empty() {
+        let () = report.errors.push(anyhow!("no recipients configured"));
+      }
+
+      for (name, alias) in &self.address_book.aliases {
+        if let Err(err) = parse_mailbox(alias, &format!("alias `{name}`")) {
+          let () = report.errors.push(err);
+        }
+        if self.address_book.groups.contains_key(name) {
+          let () = report.errors.push(anyhow!(
+            "`{name}` is defined as both an alias and a recipient group"
+          ));
+        }
+      }
+      for name in self.address_book.groups.keys() {
+        let mut errors = Vec::new();
+        let _valid = self.check_recipients([name], "recipient", &mut errors);
+        let () = report.errors.extend(
+          errors
+            .into_iter()
+            .map(|err| err.context(format!("recipient group `{name}` is invalid"))),
+        );
+      }
+
+      // Only recipients with valid addresses are considered for any
+      // further checks, so that problems are not reported repeatedly.
+      let valid = self.check_recipients(&self.recipients, "recipient", &mut report.errors);
+      let _valid = self.check_recipients(
+        &self.error_notification.recipients,
+        "error notification recipient",
+        &mut report.errors,
+      );
+
+      #[cfg(feature = "pgp")]
+      if let Some(keybox) = &self.pgp_keybox {
+        if let Err(err) = crate::pgp::check(keybox, &valid) {
+          let err = err.context(format!("PGP keybox `{}` is unusable", keybox.display()));
+          let () = report.errors.push(err);
+        }
+      }
+
+      for (name, profile) in &self.profiles {
+        let mut errors = Vec::new();
+        for reference in profile.accounts.iter().flatten() {
+          if let Err(err) = find_account(&self.accounts, reference) {
+            let () = errors.push(err);
+          }
+        }
+        let _valid = self.check_recipients(
+          profile.recipients.iter().flatten(),
+          "recipient",
+          &mut errors,
+        );
+        if profile.recipients.as_ref().is_some_and(Vec::is_empty) {
+          let () = errors.push(anyhow!("no recipients configured"));
+        }
+        let () = report.errors.extend(
+          errors
+            .into_iter()
+            .map(|err| err.context(format!("profile `{name}` is invalid"))),
+        );
+      }
+
+      for account in &self.accounts {
+        let mut errors = Vec::new();
+        if let Err(err) = parse_mailbox(account.from(), "'From'") {
+          let () = errors.push(err);
+        }
+
+        if let Account::Smtp(SmtpAccount {
+          smtp_ca_file: Some(ca_file),
+          ..
+        }) = account
+        {
+          if !ca_file.is_file() {
+            let () = errors.push(anyhow!(
+              "CA certificate `{}` does not exist",
+              ca_file.display()
+            ));
+          }
+        }
+
+        let connected = connect && errors.is_empty();
+        if connected {
+          if let Err(err) = check_account(account, &valid).await {
+            let () = errors.push(err);
+          }
+        }
+
+        let () = report.accounts.push(AccountReport {
+          from: account.from().to_string(),
+          target: describe_target(account),
+          connected,
+          errors,
+        });
+      }
+      report
     }
   }
 
 
+  /// The format of a configuration file.
+  #[derive(Clone, Copy, Debug)]
+  enum Format {
+    Json,
+    #[cfg(feature = "toml")]
+    Toml,
+    #[cfg(feature = "yaml")]
+    Yaml,
+  }
+
+  impl Format {
+    /// Determine the format of the configuration file at `path` based
+    /// on its extension, defaulting to JSON.
+    fn from_path(path: &Path) -> Result<Self> {
+      let extension = path.extension().and_then(|extension| extension.to_str());
+      match extension {
+        #[cfg(feature = "toml")]
+        Some("toml") => Ok(Self::Toml),
+        #[cfg(not(feature = "toml"))]
+        Some("toml") => Err(anyhow!(
+          "`{}`: support for TOML configuration files is not enabled",
+          path.display()
+        )),
+        #[cfg(feature = "yaml")]
+        Some("yaml" | "yml") => Ok(Self::Yaml),
+        #[cfg(not(feature = "yaml"))]
+        Some("yaml" | "yml") => Err(anyhow!(
+          "`{}`: support for YAML configuration files is not enabled",
+          path.display()
+        )),
+        _ => Ok(Self::Json),
+      }
+    }
+
+    /// Retrieve the name of the format.
+    fn name(&self) -> &'static str {
+      match self {
+        Self::Json => "JSON",
+        #[cfg(feature = "toml")]
+        Self::Toml => "TOML",
+        #[cfg(feature = "yaml")]
+        Self::Yaml => "YAML",
+      }
+    }
+  }
+
+
+  /// Parse the contents of the configuration file at `path`.
+  ///
+  /// The format is chosen based on the file's extension: `.toml` files
+  /// are parsed as TOML (with the `toml` feature), `.yaml` and `.yml`
+  /// files as YAML (with the `yaml` feature), and all others as JSON.
+  pub fn parse_config<T>(path: &Path, data: &[u8]) -> Result<T>
+  where
+    T: DeserializeOwned,
+  {
+    let format = Format::from_path(path)?;
+    let result = match format {
+      Format::Json => from_json::<T>(data).map_err(Error::from),
+      #[cfg(feature = "toml")]
+      Format::Toml => std::str::from_utf8(data)
+        .map_err(Error::from)
+        .and_then(|data| toml::from_str::<T>(data).map_err(Error::from)),
+      #[cfg(feature = "yaml")]
+      Format::Yaml => serde_yaml::from_slice::<T>(data).map_err(Error::from),
+    };
+    result.with_context(|| {
+      format!(
+        "failed to parse `{}` contents as {}",
+        path.display(),
+        format.name()
+      )
+    })
+  }
+
+
+  /// Serialize a configuration for storing it in the file at `path`,
+  /// in the format [`parse_config`] expects.
+  pub fn serialize_config<T>(path: &Path, config: &T) -> Result<Vec<u8>>
+  where
+    T: Serialize,
+  {
+    let format = Format::from_path(path)?;
+    let result = match format {
+      Format::Json => to_json(config).map_err(Error::from).map(|mut data| {
+        let () = data.push(b'\n');
+        data
+      }),
+      #[cfg(feature = "toml")]
+      Format::Toml => toml::to_string_pretty(config)
+        .map(String::into_bytes)
+        .map_err(Error::from),
+      #[cfg(feature = "yaml")]
+      Format::Yaml => serde_yaml::to_string(config)
+        .map(String::into_bytes)
+        .map_err(Error::from),
+    };
+    result.with_context(|| format!("failed to serialize configuration as {}", format.name()))
+  }
+
+
+  /// The version of the configuration schema described by [`Config`].
+  ///
+  /// Configurations without a `version` attribute are considered to
+  /// be of version `0`, the original layout.
+  pub const CONFIG_VERSION: u32 = 1;
+
+  /// The migrations of configurations to the next version, indexed by
+  /// the version they migrate from.
+  const MIGRATIONS: [fn(&mut Map<String, Value>); CONFIG_VERSION as usize] = [migrate_v0];
+
+
+  /// Create an error reporting that configuration version `version` is
+  /// not supported.
+  fn unsupported_version(version: u32) -> Error {
+    anyhow!(
+      "configuration version {version} is not supported; the newest supported version is \
+       {CONFIG_VERSION}"
+    )
+  }
+
+
+  /// Migrate a configuration of version `0` to version `1`.
+  ///
+  /// Version `0` allowed for the `pgp-keybox` spelling of the
+  /// `pgp_keybox` attribute, which is renamed in place. If both
+  /// spellings are present, the current one takes precedence.
+  fn migrate_v0(config: &mut Map<String, Value>) {
+    let present = config.contains_key("pgp_keybox");
+
+    *config = take(config)
+      .into_iter()
+      .filter_map(|(key, value)| match key.as_str() {
+        "pgp-keybox" if present => None,
+        "pgp-keybox" => Some(("pgp_keybox".to_string(), value)),
+        _ => Some((key, value)),
+      })
+      .collect();
+  }
+
+
+  /// Migrate `config` to the current schema version in place, retaining
+  /// any attributes unknown to [`Config`].
+  ///
+  /// # Returns
+  /// The function returns the version `config` had before.
+  pub(super) fn migrate(config: &mut Value) -> Result<u32> {
+    let config = config
+      .as_object_mut()
+      .context("configuration is not a map of attributes")?;
+    let version = match config.get("version") {
+      None => 0,
+      Some(version) => version
+        .as_u64()
+        .and_then(|version| u32::try_from(version).ok())
+        .with_context(|| format!("`{version}` is not a valid configuration version"))?,
+    };
+    if version > CONFIG_VERSION {
+      return Err(unsupported_version(version))
+    }
+
+    for migration in &MIGRATIONS[version as usize..] {
+      let () = migration(config);
+    }
+    // Make sure that the version is the first attribute, while
+    // retaining the order of all others.
+    *config = once(("version".to_string(), Value::from(CONFIG_VERSION)))
+      .chain(take(config).into_iter().filter(|(key, _value)| key != "version"))
+      .collect();
+    Ok(version)
+  }
+
+
+  /// Migrate the contents of the configuration file at `path` to the
+  /// current schema version (see [`CONFIG_VERSION`]).
+  ///
+  /// Attributes not known to [`Config`], such as those of applications
+  /// embedding it, are retained. Files included by the configuration
+  /// are not considered.
+  ///
+  /// # Returns
+  /// The function returns the migrated configuration, in the format
+  /// [`parse_config`] expects, or `None` if the configuration already
+  /// is of the current version.
+  pub fn migrate_config(path: &Path, data: &[u8]) -> Result<Option<Vec<u8>>> {
+    let mut config = parse_config::<Value>(path, data)?;
+    let version = migrate(&mut config)
+      .with_context(|| format!("failed to migrate configuration `{}`", path.display()))?;
+    if version == CONFIG_VERSION {
+      return Ok(None)
+    }
+    serialize_config(path, &config).map(Some)
+  }
+
+
+  /// The prefix of environment variables overriding configuration
+  /// attributes.
+  const ENV_PREFIX: &str = "MAILY_";
+  /// The environment variable containing the path to an additional
+  /// configuration file.
+  const ENV_CONFIG: &str = "MAILY_CONFIG";
+  /// The environment variable reserved for applications to select a
+  /// profile with.
+  const ENV_PROFILE: &str = "MAILY_PROFILE";
+
+
+  /// Find the configuration file in `dir`.
+  ///
+  /// Depending on the enabled features, `config.toml` and
+  /// `config.yaml` are probed before `config.json`. If none of them
+  /// exists, the path to `config.json` is returned.
+  fn probe_config(dir: &Path) -> PathBuf {
+    let candidates: &[&str] = &[
+      #[cfg(feature = "toml")]
+      "config.toml",
+      #[cfg(feature = "yaml")]
+      "config.yaml",
+    ];
+    candidates
+      .iter()
+      .map(|name| dir.join(name))
+      .find(|path| path.exists())
+      .unwrap_or_else(|| dir.join("config.json"))
+  }
+
+
   /// Retrieve the path to the system configuration.
+  ///
+  /// Depending on the enabled features, `config.toml` and
+  /// `config.yaml` are probed before `config.json` in `/etc/maily`.
+  /// If none of them exists, the path to `config.json` is returned.
   #[inline]
   pub fn system_config_path() -> Result<Cow<'static, Path>> {
-    let path = Cow::Borrowed(Path::new("/etc/maily/config.json"));
-    Ok(path)
+    let path = probe_config(Path::new("/etc/maily"));
+    Ok(Cow::Owned(path))
+  }
+
+
+  /// Retrieve the path to the user's configuration, in
+  /// `$XDG_CONFIG_HOME/maily/` (or `~/.config/maily/`).
+  ///
+  /// Files are probed in the same way as for [`system_config_path`].
+  /// `None` is returned if the user's configuration directory could
+  /// not be determined.
+  pub fn user_config_path() -> Option<PathBuf> {
+    let dir = var_os("XDG_CONFIG_HOME")
+      .map(PathBuf::from)
+      .filter(|dir| dir.is_absolute())
+      .or_else(|| {
+        var_os("HOME")
+          .map(PathBuf::from)
+          .filter(|dir| dir.is_absolute())
+          .map(|home| home.join(".config"))
+      })?;
+    Some(probe_config(&dir.join("maily")))
+  }
+
+
+  /// Merge the configuration attributes in `layer` into `base`.
+  ///
+  /// Objects are merged attribute by attribute. Arrays in `layer` are
+  /// appended to those in `base` if `append` is `true`, while all other
+  /// values replace those in `base`.
+  pub(super) fn merge(base: &mut Value, layer: Value, append: bool) {
+    match (base, layer) {
+      (Value::Object(base), Value::Object(layer)) => {
+        for (key, value) in layer {
+          match base.get_mut(&key) {
+            Some(existing) => merge(existing, value, append),
+            None => {
+              let _prev = base.insert(key, value);
+            },
+          }
+        }
+      },
+      (Value::Array(base), Value::Array(layer)) if append => base.extend(layer),
+      (base, layer) => *base = layer,
+    }
+  }
+
+
+  /// Determine how to interpret the value of an environment variable
+  /// overriding the attribute at `path`.
+  ///
+  /// Attributes of [`Config`] are interpreted according to their type.
+  /// Others, e.g., those of applications embedding the configuration,
+  /// are interpreted according to the type of the value they override,
+  /// if any, and as strings otherwise.
+  pub(super) fn env_kind(path: &[&str], existing: &Value, value: &str) -> EnvKind {
+    let known = ENV_SCHEMA.iter().find(|(attribute, _kind)| {
+      attribute.split('.').count() == path.len()
+        && attribute
+          .split('.')
+          .zip(path)
+          .all(|(key, component)| key == "*" || key == *component)
+    });
+
+    match (known, existing) {
+      (Some((_attribute, kind)), _) => *kind,
+      (None, Value::String(_)) => EnvKind::String,
+      (None, Value::Array(_)) => EnvKind::List,
+      (None, Value::Null) if value.trim_start().starts_with(['[', '{']) => EnvKind::Json,
+      (None, Value::Null) => EnvKind::String,
+      (None, Value::Bool(_) | Value::Number(_) | Value::Object(_)) => EnvKind::Json,
+    }
+  }
+
+
+  /// Apply overrides from `MAILY_*` environment variables to `config`.
+  ///
+  /// The part of a variable's name after the prefix denotes the
+  /// (lower cased) attribute to override, with `__` separating nested
+  /// attributes, e.g., `MAILY_ERROR_NOTIFICATION__ENABLED`. Values of
+  /// attributes holding strings are used verbatim, while other values
+  /// are interpreted as JSON. Lists of strings may also be provided as
+  /// comma separated lists. Attributes nested inside of values other
+  /// than objects, e.g., of individual accounts, cannot be overridden.
+  pub(super) fn apply_env<I>(config: &mut Value, vars: I) -> Result<()>
+  where
+    I: IntoIterator<Item = (OsString, OsString)>,
+  {
+    let mut vars = vars
+      .into_iter()
+      .filter_map(|(name, value)| {
+        let name = name.into_string().ok()?;
+        let attribute = name.strip_prefix(ENV_PREFIX)?.to_ascii_lowercase();
+        (name != ENV_CONFIG && name != ENV_PROFILE).then_some((name, attribute, value))
+      })
+      .collect::<Vec<_>>();
+    let () = vars.sort();
+
+    for (name, attribute, value) in vars {
+      let value = value
+        .into_string()
+        .map_err(|_| anyhow!("environment variable `{name}` is not valid UTF-8"))?;
+
+      let path = attribute.split("__").collect::<Vec<_>>();
+      let mut target = &mut *config;
+      for (idx, key) in path.iter().enumerate() {
+        if target.is_null() {
+          *target = Value::Object(Default::default());
+        }
+        let object = target.as_object_mut().with_context(|| {
+          format!(
+            "environment variable `{name}` refers to attribute inside of non-object `{}`",
+            path[..idx].join(".")
+          )
+        })?;
+        target = object.entry(*key).or_insert(Value::Null);
+      }
+
+      *target = match env_kind(&path, target, &value) {
+        EnvKind::String => Value::String(value),
+        EnvKind::List if !value.trim_start().starts_with('[') => Value::Array(
+          value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| Value::String(item.to_string()))
+            .collect(),
+        ),
+        EnvKind::List | EnvKind::Json => from_json_str(&value)
+          .with_context(|| format!("environment variable `{name}` does not contain valid JSON"))?,
+      };
+    }
+    Ok(())
+  }
+
+
+  /// The prefix of string values referencing a file whose contents
+  /// to use instead.
+  const FILE_PREFIX: &str = "@file:";
+  /// The attributes of [`Config`] in which references are resolved.
+  const INTERPOLATED: [&str; 9] = [
+    "accounts",
+    "recipients",
+    "aliases",
+    "groups",
+    "pgp_keybox",
+    "error_notification",
+    "fallback",
+    "queue",
+    "subject_prefix",
+  ];
+
+
+  /// Resolve references in a single configuration string value.
+  ///
+  /// A value starting with `@file:` is replaced with the contents of
+  /// the file it references, without trailing line breaks. Otherwise,
+  /// `${HOSTNAME}` is replaced with the host's name and `${env:NAME}`
+  /// with the value of the environment variable `NAME`, as retrieved
+  /// via `env`. `$${` produces a literal `${`.
+  fn interpolate_str<E>(value: &str, env: &E) -> Result<String>
+  where
+    E: Fn(&str) -> Option<String>,
+  {
+    if let Some(path) = value.strip_prefix(FILE_PREFIX) {
+      let contents =
+        read_to_string(path).with_context(|| format!("failed to read file `{path}`"))?;
+      return Ok(contents.trim_end_matches(['\r', '\n']).to_string())
+    }
+
+    let mut result = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(idx) = rest.find("${") {
+      if rest[..idx].ends_with('$') {
+        let () = result.push_str(&rest[..idx]);
+        let () = result.push('{');
+        rest = &rest[idx + 2..];
+        continue
+      }
+
+      let () = result.push_str(&rest[..idx]);
+      let reference = &rest[idx + 2..];
+      let end = reference
+        .find('}')
+        .with_context(|| format!("unterminated reference in `{value}`"))?;
+      let name = &reference[..end];
+      let replacement = match name.strip_prefix("env:") {
+        Some(var) => env(var)
+          .with_context(|| format!("environment variable `{var}` is not defined"))?,
+        None if name == "HOSTNAME" => hostname(),
+        None => return Err(anyhow!("unknown variable `{name}`")),
+      };
+      let () = result.push_str(&replacement);
+      rest = &reference[end + 1..];
+    }
+    let () = result.push_str(rest);
+    Ok(result)
+  }
+
+
+  /// Resolve references in all string values of `config` (see
+  /// [`interpolate_str`]).
+  ///
+  /// `attribute` is the path to `config`, used in error messages.
+  pub(super) fn interpolate<E>(config: &mut Value, attribute: &str, env: &E) -> Result<()>
+  where
+    E: Fn(&str) -> Option<String>,
+  {
+    match config {
+      Value::String(value) => {
+        *value = interpolate_str(value, env)
+          .with_context(|| format!("failed to interpolate `{attribute}`"))?;
+      },
+      Value::Array(values) => {
+        for (idx, value) in values.iter_mut().enumerate() {
+          let () = interpolate(value, &format!("{attribute}[{idx}]"), env)?;
+        }
+      },
+      Value::Object(values) => {
+        for (key, value) in values.iter_mut() {
+          let attribute = if attribute.is_empty() {
+            key.clone()
+          } else {
+            format!("{attribute}.{key}")
+          };
+          let () = interpolate(value, &attribute, env)?;
+        }
+      },
+      Value::Null | Value::Bool(_) | Value::Number(_) => (),
+    }
+    Ok(())
+  }
+
+
+  /// Resolve references in the attributes of `config` belonging to
+  /// [`Config`], including those of its profiles (see
+  /// [`interpolate_str`]).
+  ///
+  /// Other attributes, such as those of applications embedding the
+  /// configuration, are left untouched.
+  fn interpolate_config<E>(config: &mut Value, env: &E) -> Result<()>
+  where
+    E: Fn(&str) -> Option<String>,
+  {
+    if let Value::Object(values) = config {
+      for (key, value) in values.iter_mut() {
+        if INTERPOLATED.contains(&key.as_str()) {
+          let () = interpolate(value, key, env)?;
+        } else if key == "profiles" {
+          if let Value::Object(profiles) = value {
+            for (name, profile) in profiles.iter_mut() {
+              let () = interpolate_config(profile, env)
+                .with_context(|| format!("failed to interpolate profile `{name}`"))?;
+            }
+          }
+        }
+      }
+    }
+    Ok(())
+  }
+
+
+  /// Look up an environment variable for the purpose of interpolation.
+  fn env_var(name: &str) -> Option<String> {
+    var_os(name).and_then(|value| value.into_string().ok())
+  }
+
+
+  /// List the configuration fragments in `dir`, in lexical order.
+  ///
+  /// Files with extensions not denoting a supported format are
+  /// ignored.
+  fn fragments(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = read_dir(dir)
+      .with_context(|| format!("failed to read directory `{}`", dir.display()))?;
+    let mut fragments = Vec::new();
+    for entry in entries {
+      let path = entry
+        .with_context(|| format!("failed to read directory `{}`", dir.display()))?
+        .path();
+      let supported = match path.extension().and_then(|extension| extension.to_str()) {
+        Some("json") => true,
+        #[cfg(feature = "toml")]
+        Some("toml") => true,
+        #[cfg(feature = "yaml")]
+        Some("yaml" | "yml") => true,
+        _ => false,
+      };
+      if supported && path.is_file() {
+        let () = fragments.push(path);
+      }
+    }
+    let () = fragments.sort();
+    Ok(fragments)
+  }
+
+
+  /// A helper for reading configuration files, resolving their
+  /// includes.
+  #[derive(Debug, Default)]
+  pub(super) struct Loader {
+    /// The files read so far, in order.
+    pub(super) files: Vec<PathBuf>,
+    /// The files currently being read, used for detecting include
+    /// cycles.
+    stack: Vec<PathBuf>,
+  }
+
+  impl Loader {
+    /// Read the configuration file at `path`, along with the files it
+    /// includes.
+    ///
+    /// The `include` attribute may contain a path or a list of paths,
+    /// relative to the including file. A directory is included by
+    /// including all fragments in it. Included files are merged in
+    /// order after the including one, with arrays being combined.
+    pub(super) async fn load_file(&mut self, path: &Path) -> Result<Value> {
+      let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+      if self.stack.contains(&canonical) {
+        return Err(anyhow!(
+          "configuration file `{}` includes itself",
+          path.display()
+        ))
+      }
+
+      let data = read(path)
+        .await
+        .with_context(|| format!("failed to read configuration file `{}`", path.display()))?;
+      let mut config = parse_config::<Value>(path, &data)?;
+      let _version = migrate(&mut config)
+        .with_context(|| format!("failed to migrate configuration `{}`", path.display()))?;
+      let () = self.files.push(path.to_path_buf());
+
+      let includes = match config
+        .as_object_mut()
+        .and_then(|config| config.remove("include"))
+      {
+        None => Vec::new(),
+        Some(Value::String(include)) => vec![include],
+        Some(Value::Array(includes)) => includes
+          .into_iter()
+          .map(|include| match include {
+            Value::String(include) => Ok(include),
+            _ => Err(anyhow!(
+              "`include` in `{}` contains a non-string value",
+              path.display()
+            )),
+          })
+          .collect::<Result<_>>()?,
+        Some(_) => {
+          return Err(anyhow!(
+            "`include` in `{}` is neither a path nor a list of paths",
+            path.display()
+          ))
+        },
+      };
+
+      let () = self.stack.push(canonical);
+      let base = path.parent().unwrap_or(Path::new(""));
+      for include in includes {
+        let include = base.join(include);
+        let files = if include.is_dir() {
+          fragments(&include)?
+        } else {
+          vec![include]
+        };
+
+        for file in files {
+          let fragment = Box::pin(self.load_file(&file))
+            .await
+            .with_context(|| format!("failed to include configuration of `{}`", path.display()))?;
+          let () = merge(&mut config, fragment, true);
+        }
+      }
+      let _path = self.stack.pop();
+      Ok(config)
+    }
+
+    /// Read the configuration file at `path`, if it exists, as well as
+    /// all fragments in the `config.d` directory next to it.
+    ///
+    /// Fragments are merged in lexical order, with arrays being
+    /// combined. `None` is returned if neither the file nor any
+    /// fragment exists.
+    pub(super) async fn load_layer(&mut self, path: &Path) -> Result<Option<Value>> {
+      let mut layer = if path.exists() {
+        Some(self.load_file(path).await?)
+      } else {
+        None
+      };
+
+      let dir = path.parent().unwrap_or(Path::new("")).join("config.d");
+      if dir.is_dir() {
+        for fragment in fragments(&dir)? {
+          let config = self.load_file(&fragment).await?;
+          let () = match &mut layer {
+            Some(layer) => merge(layer, config, true),
+            None => layer = Some(config),
+          };
+        }
+      }
+      Ok(layer)
+    }
+  }
+
+
+  /// Load a configuration made up of multiple layers.
+  ///
+  /// The layers are, in increasing order of precedence:
+  /// - the system configuration (see [`system_config_path`])
+  /// - the user's configuration (see [`user_config_path`])
+  /// - the configuration file at `path` or, if not provided, the one
+  ///   referenced by the `MAILY_CONFIG` environment variable
+  /// - overrides from `MAILY_*` environment variables, e.g.,
+  ///   `MAILY_RECIPIENTS=admin@example.com,ops@example.com`, except for
+  ///   `MAILY_PROFILE`, which is reserved for selecting a profile
+  ///
+  /// Each file is migrated to the current schema version before being
+  /// merged (see [`migrate_config`]), so that layers of different
+  /// versions can be combined.
+  ///
+  /// Once merged, references in string values of [`Config`]
+  /// attributes are resolved: `${HOSTNAME}` is replaced with the host's
+  /// name, `${env:NAME}` with the value of the environment variable
+  /// `NAME`, and values of the form `@file:PATH` with the contents of
+  /// the referenced file.
+  ///
+  /// The system and user configurations are each accompanied by the
+  /// fragments (`*.json`, as well as `*.toml` and `*.yaml` if
+  /// supported) in the `config.d` directory next to them, which are
+  /// merged in lexical order, combining arrays. Any file may include
+  /// others via its `include` attribute, containing a path or a list
+  /// of paths relative to the file itself; directories are included
+  /// like `config.d`.
+  ///
+  /// Missing system and user configuration files are skipped, but at
+  /// least one configuration file has to exist. Layers are merged
+  /// attribute by attribute, so that, for example, a user's
+  /// configuration can provide recipients while inheriting the accounts
+  /// of the system configuration.
+  ///
+  /// # Returns
+  /// The function returns the paths of the files that were read, in
+  /// order, along with the configuration.
+  pub async fn layered_config<T>(path: Option<&Path>) -> Result<(Vec<PathBuf>, T)>
+  where
+    T: DeserializeOwned,
+  {
+    let mut loader = Loader::default();
+    let mut layers = Vec::new();
+    let system = system_config_path()
+      .context("failed to retrieve path to system configuration")?
+      .into_owned();
+    if let Some(layer) = loader.load_layer(&system).await? {
+      let () = layers.push(layer);
+    }
+    if let Some(user) = user_config_path() {
+      if let Some(layer) = loader.load_layer(&user).await? {
+        let () = layers.push(layer);
+      }
+    }
+    if let Some(path) = path
+      .map(Path::to_path_buf)
+      .or_else(|| var_os(ENV_CONFIG).map(PathBuf::from))
+    {
+      let () = layers.push(loader.load_file(&path).await?);
+    }
+
+    if layers.is_empty() {
+      let user = user_config_path()
+        .map(|user| format!(" or `{}`", user.display()))
+        .unwrap_or_default();
+      return Err(anyhow!(
+        "no configuration found; tried `{}`{user}",
+        system.display()
+      ))
+    }
+
+    let mut config = Value::Object(Default::default());
+    for layer in layers {
+      let () = merge(&mut config, layer, false);
+    }
+    let () = apply_env(&mut config, vars_os())?;
+    let () = interpolate_config(&mut config, &env_var)?;
+
+    let paths = loader.files;
+    let config = from_json_value::<T>(config).with_context(|| {
+      format!(
+        "invalid configuration merged from {}",
+        paths
+          .iter()
+          .map(|path| format!("`{}`", path.display()))
+          .collect::<Vec<_>>()
+          .join(", ")
+      )
+    })?;
+    Ok((paths, config))
+  }
+
+
+  /// Load the configuration at `path`, along with the fragments in the
+  /// `config.d` directory next to it and any includes.
+  pub(super) async fn load_config(path: &Path) -> Result<Config> {
+    let mut loader = Loader::default();
+    let mut config = loader
+      .load_layer(path)
+      .await?
+      .with_context(|| format!("no configuration found; tried `{}`", path.display()))?;
+    let () = interpolate_config(&mut config, &env_var)?;
+
+    let config = from_json_value::<Config>(config).with_context(|| {
+      format!(
+        "invalid configuration merged from {}",
+        loader
+          .files
+          .iter()
+          .map(|path| format!("`{}`", path.display()))
+          .collect::<Vec<_>>()
+          .join(", ")
+      )
+    })?;
+    Ok(config)
   }
 
 
   /// Load the system configuration.
+  ///
+  /// Just like for [`layered_config`], the fragments in the `config.d`
+  /// directory next to the system configuration file are merged into
+  /// it and includes are resolved. Environment variables are not
+  /// considered, though.
   pub async fn system_config() -> Result<Config> {
     let path = system_config_path().context("failed to retrieve path to system configuration")?;
-    let data = read(&path)
-      .await
-      .with_context(|| format!("failed to read configuration file `{}`", path.display()))?;
-    let config = from_json::<Config>(&data)
-      .with_context(|| format!("failed to parse `{}` contents as JSON", path.display()))?;
-    Ok(config)
+    load_config(&path).await
   }
 }
 
 #[cfg(feature = "config")]
 pub use implementation::*;
+
+
+#[cfg(all(test, feature = "config"))]
+mod tests {
+  use super::*;
+
+  use std::collections::BTreeMap;
+  use std::ffi::OsString;
+
+  use serde_json::from_str as from_json;
+  use serde_json::from_value as from_json_value;
+  use serde_json::Value;
+
+  use tempfile::tempdir;
+
+  use crate::AddressBook;
+
+
+  /// Check that configurations in all supported formats can be parsed
+  /// and serialized.
+  #[test]
+  fn formats() {
+    let json = br#"{
+      "accounts": [{"mailbox": "/tmp/mbox", "mailbox_format": "mbox", "from": "a@example.com"}],
+      "recipients": ["admin@example.com"]
+    }"#;
+    let config = parse_config::<Config>(Path::new("config.json"), json).unwrap();
+    assert_eq!(config.recipients, ["admin@example.com"]);
+
+    #[allow(unused_mut)]
+    let mut paths = vec!["config.json"];
+    #[cfg(feature = "toml")]
+    let () = paths.push("config.toml");
+    #[cfg(feature = "yaml")]
+    let () = paths.extend(["config.yaml", "config.yml"]);
+
+    for path in paths {
+      let path = Path::new(path);
+      let data = serialize_config(path, &config).unwrap();
+      let parsed = parse_config::<Config>(path, &data).unwrap();
+      assert_eq!(parsed.recipients, config.recipients);
+      assert_eq!(parsed.accounts[0].from(), "a@example.com");
+    }
+
+    #[cfg(feature = "toml")]
+    {
+      let err = parse_config::<Config>(Path::new("config.toml"), json).unwrap_err();
+      assert_eq!(err.to_string(), "failed to parse `config.toml` contents as TOML");
+    }
+    #[cfg(not(feature = "yaml"))]
+    {
+      let err = parse_config::<Config>(Path::new("config.yaml"), json).unwrap_err();
+      assert_eq!(
+        err.to_string(),
+        "`config.yaml`: support for YAML configuration files is not enabled"
+      );
+    }
+  }
+
+  /// Check that the kind of an account is determined correctly and that
+  /// problems with it are reported in detail.
+  #[test]
+  fn account_kinds() {
+    let accounts = from_json::<Vec<Account>>(
+      r#"[
+        {"smtp_host": "smtp.example.com", "smtp_mode": "tls", "from": "a@example.com",
+         "user": "a", "password": "secret"},
+        {"type": "local", "mailbox": "/tmp/mbox", "mailbox_format": "mbox",
+         "from": "b@example.com"},
+        {"mx_resolver": "127.0.0.1", "from": "c@example.com"}
+      ]"#,
+    )
+    .unwrap();
+    assert!(matches!(accounts[0], Account::Smtp(..)));
+    assert!(matches!(accounts[1], Account::Local(..)));
+    assert!(matches!(accounts[2], Account::Mx(..)));
+
+    let err = from_json::<Account>(
+      r#"{"smtp_host": "smtp.example.com", "from": "a@example.com", "user": "a",
+          "password": "secret"}"#,
+    )
+    .unwrap_err();
+    assert_eq!(err.to_string(), "invalid smtp account: missing field `smtp_mode`");
+
+    let err = from_json::<Account>(r#"{"type": "pigeon", "from": "a@example.com"}"#).unwrap_err();
+    assert!(err.to_string().starts_with("unknown variant `pigeon`"), "{err}");
+  }
+
+  /// Check that error notifications are rendered as expected.
+  #[test]
+  fn notification_rendering() {
+    let notification = ErrorNotification {
+      body: Cow::Borrowed("Sending {subject} failed: {error} {unknown} {"),
+      ..Default::default()
+    };
+    let body = notification.render_body("{error}", "{subject} missing");
+    assert_eq!(body, "Sending {error} failed: {subject} missing {unknown} {");
+  }
+
+  /// Check that configuration layers are merged as expected.
+  #[tokio::test]
+  async fn layering() {
+    let dir = tempdir().unwrap();
+    let system = dir.path().join("system.json");
+    let user = dir.path().join("user.json");
+    let () = std::fs::write(
+      &system,
+      r#"{
+        "accounts": [{"mailbox": "/tmp/mbox", "mailbox_format": "mbox", "from": "a@example.com"}],
+        "recipients": ["root@example.com"],
+        "error_notification": {"subject": "oops", "include_subject": true}
+      }"#,
+    )
+    .unwrap();
+    let () = std::fs::write(
+      &user,
+      r#"{"recipients": ["user@example.com"], "error_notification": {"subject": "failed"}}"#,
+    )
+    .unwrap();
+
+    let mut loader = Loader::default();
+    let mut value = loader.load_file(&system).await.unwrap();
+    let () = merge(&mut value, loader.load_file(&user).await.unwrap(), false);
+    let vars = [
+      ("MAILY_CONFIG", "/ignored"),
+      ("MAILY_PROFILE", "ignored"),
+      ("MAILY_ERROR_NOTIFICATION__ENABLED", "false"),
+      ("MAILY_RECIPIENTS", "x@example.com, y@example.com"),
+      ("OTHER", "ignored"),
+    ]
+    .map(|(name, value)| (OsString::from(name), OsString::from(value)));
+    let () = apply_env(&mut value, vars).unwrap();
+
+    let config = from_json_value::<Config>(value).unwrap();
+    assert_eq!(config.accounts.len(), 1);
+    assert_eq!(config.recipients, ["x@example.com", "y@example.com"]);
+    assert!(!config.error_notification.enabled);
+    assert_eq!(config.error_notification.subject, "failed");
+    assert!(config.error_notification.include_subject);
+
+    let mut value = serde_json::json!({"relay": {"password": "secret"}});
+    let vars = [("MAILY_RELAY__PASSWORD", "1234"), ("MAILY_QUEUE_EXPIRY", "60")]
+      .map(|(name, value)| (OsString::from(name), OsString::from(value)));
+    let () = apply_env(&mut value, vars).unwrap();
+    assert_eq!(
+      value,
+      serde_json::json!({"relay": {"password": "1234"}, "queue_expiry": 60})
+    );
+
+    // Overrides of attributes not set by any layer are interpreted
+    // according to their type as well.
+    let mut value = serde_json::json!({});
+    let vars = [
+      ("MAILY_RECIPIENTS", "a@example.com"),
+      ("MAILY_RELAY__PASSWORD", "1234"),
+      ("MAILY_SUBJECT_PREFIX", "true"),
+      ("MAILY_GROUPS__OPS", "a@example.com,b@example.com"),
+      ("MAILY_ERROR_NOTIFICATION__ENABLED", "false"),
+      ("MAILY_FILTERS", r#"[{"command": "cat"}]"#),
+    ]
+    .map(|(name, value)| (OsString::from(name), OsString::from(value)));
+    let () = apply_env(&mut value, vars).unwrap();
+    assert_eq!(
+      value,
+      serde_json::json!({
+        "recipients": ["a@example.com"],
+        "relay": {"password": "1234"},
+        "subject_prefix": "true",
+        "groups": {"ops": ["a@example.com", "b@example.com"]},
+        "error_notification": {"enabled": false},
+        "filters": [{"command": "cat"}],
+      })
+    );
+
+    let mut value = serde_json::json!({});
+    let vars = [("MAILY_QUEUE_EXPIRY", "soon")]
+      .map(|(name, value)| (OsString::from(name), OsString::from(value)));
+    let err = apply_env(&mut value, vars).unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "environment variable `MAILY_QUEUE_EXPIRY` does not contain valid JSON"
+    );
+
+    // Attributes inside of arrays or strings cannot be overridden.
+    for (name, parent) in [
+      ("MAILY_ACCOUNTS__0__PASSWORD", "accounts"),
+      ("MAILY_SUBJECT_PREFIX__X", "subject_prefix"),
+    ] {
+      let mut value = serde_json::json!({"accounts": [{}], "subject_prefix": "x"});
+      let vars = [(OsString::from(name), OsString::from("1234"))];
+      let err = apply_env(&mut value, vars).unwrap_err();
+      assert_eq!(
+        err.to_string(),
+        format!("environment variable `{name}` refers to attribute inside of non-object `{parent}`")
+      );
+    }
+  }
+
+  /// Check that [`ENV_SCHEMA`] covers all attributes of [`Config`]
+  /// not holding strings.
+  #[test]
+  fn env_schema() {
+    fn check(path: &mut Vec<String>, value: &Value) {
+      let components = path.iter().map(String::as_str).collect::<Vec<_>>();
+      let kind = env_kind(&components, &Value::Null, "");
+      let expected = match value {
+        Value::String(_) => EnvKind::String,
+        Value::Array(items) if items.iter().all(Value::is_string) => EnvKind::List,
+        Value::Null | Value::Bool(_) | Value::Number(_) | Value::Array(_) => EnvKind::Json,
+        Value::Object(object) => {
+          for (key, value) in object {
+            let () = path.push(key.clone());
+            let () = check(path, value);
+            let _key = path.pop();
+          }
+          EnvKind::Json
+        },
+      };
+      if !path.is_empty() {
+        assert_eq!(kind, expected, "`{}`", path.join("."));
+      }
+    }
+
+    // All fields are provided explicitly and with non-empty values,
+    // so that new ones have to be considered here.
+    let config = Config {
+      version: CONFIG_VERSION,
+      accounts: vec![Account::Local(LocalAccount {
+        mailbox: Cow::Borrowed(Path::new("/tmp/mbox")),
+        mailbox_format: MailboxFormat::Mbox,
+        from: Cow::Borrowed("a@example.com"),
+      })],
+      recipients: vec!["b@example.com".to_string()],
+      #[cfg(feature = "pgp")]
+      pgp_keybox: Some("/tmp/keybox".into()),
+      error_notification: ErrorNotification {
+        enabled: true,
+        recipients: vec![Cow::Borrowed("c@example.com")],
+        subject: Cow::Borrowed("subject"),
+        include_subject: true,
+        body: Cow::Borrowed("body"),
+        when: NotifyWhen::Finally,
+        #[cfg(feature = "pgp")]
+        encrypt: true,
+      },
+      fallback: vec![FallbackSink::File(Cow::Borrowed(Path::new("/tmp/fallback")))],
+      #[cfg(feature = "queue")]
+      queue: Some("/tmp/queue".into()),
+      #[cfg(feature = "queue")]
+      queue_expiry: Some(60),
+      subject_prefix: Some("prefix".to_string()),
+      profiles: BTreeMap::from([(
+        "profile".to_string(),
+        Profile {
+          recipients: Some(vec!["d@example.com".to_string()]),
+          accounts: Some(vec!["a@example.com".to_string()]),
+          #[cfg(feature = "pgp")]
+          pgp_keybox: Some("/tmp/keybox".into()),
+          #[cfg(feature = "pgp")]
+          encrypt: Some(true),
+          subject_prefix: Some("prefix".to_string()),
+          extensions: BTreeMap::new(),
+        },
+      )]),
+      address_book: AddressBook {
+        aliases: BTreeMap::from([("e".to_string(), "e@example.com".to_string())]),
+        groups: BTreeMap::from([("f".to_string(), vec!["e".to_string()])]),
+      },
+    };
+    let value = serde_json::to_value(&config).unwrap();
+    let () = check(&mut Vec::new(), &value);
+  }
+
+  /// Check that configuration fragments and includes are merged as
+  /// expected.
+  #[tokio::test]
+  async fn fragments_and_includes() {
+    let dir = tempdir().unwrap();
+    let config = dir.path().join("config.json");
+    let fragments = dir.path().join("config.d");
+    let () = std::fs::create_dir(&fragments).unwrap();
+    let () = std::fs::write(
+      &config,
+      r#"{"recipients": ["root@example.com"], "include": "extra.json"}"#,
+    )
+    .unwrap();
+    let () = std::fs::write(
+      dir.path().join("extra.json"),
+      r#"{"recipients": ["extra@example.com"]}"#,
+    )
+    .unwrap();
+    let () = std::fs::write(
+      fragments.join("20-b.json"),
+      r#"{"accounts": [{"mailbox": "/b", "mailbox_format": "mbox", "from": "b@example.com"}]}"#,
+    )
+    .unwrap();
+    let () = std::fs::write(
+      fragments.join("10-a.json"),
+      r#"{"accounts": [{"mailbox": "/a", "mailbox_format": "mbox", "from": "a@example.com"}]}"#,
+    )
+    .unwrap();
+    let () = std::fs::write(fragments.join("README"), "ignored").unwrap();
+
+    let mut loader = Loader::default();
+    let value = loader.load_layer(&config).await.unwrap().unwrap();
+    let config = from_json_value::<Config>(value).unwrap();
+    assert_eq!(config.recipients, ["root@example.com", "extra@example.com"]);
+    let from = config
+      .accounts
+      .iter()
+      .map(Account::from)
+      .collect::<Vec<_>>();
+    assert_eq!(from, ["a@example.com", "b@example.com"]);
+    assert_eq!(loader.files.len(), 4);
+
+    let () = std::fs::write(fragments.join("30-broken.json"), "{").unwrap();
+    let err = Loader::default()
+      .load_layer(&dir.path().join("config.json"))
+      .await
+      .unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      format!(
+        "failed to parse `{}` contents as JSON",
+        fragments.join("30-broken.json").display()
+      )
+    );
+
+    let looping = dir.path().join("loop.json");
+    let () = std::fs::write(&looping, r#"{"include": ["./loop.json"]}"#).unwrap();
+    let err = Loader::default().load_file(&looping).await.unwrap_err();
+    assert_eq!(
+      format!("{:#}", err.root_cause()),
+      format!(
+        "configuration file `{}` includes itself",
+        dir.path().join("./loop.json").display()
+      )
+    );
+  }
+
+  /// Check that the system configuration is loaded along with its
+  /// fragments and includes.
+  #[tokio::test]
+  async fn system_configuration() {
+    let dir = tempdir().unwrap();
+    let config = dir.path().join("config.json");
+    let fragments = dir.path().join("config.d");
+    let () = std::fs::create_dir(&fragments).unwrap();
+    let () = std::fs::write(
+      &config,
+      r#"{"version": 1, "recipients": ["root@example.com"], "include": "extra.json"}"#,
+    )
+    .unwrap();
+    let () = std::fs::write(
+      dir.path().join("extra.json"),
+      r#"{"recipients": ["extra@example.com"]}"#,
+    )
+    .unwrap();
+    let () = std::fs::write(
+      fragments.join("10-a.json"),
+      r#"{"accounts": [{"mailbox": "/a", "mailbox_format": "mbox", "from": "a@${HOSTNAME}"}]}"#,
+    )
+    .unwrap();
+
+    let config = load_config(&config).await.unwrap();
+    assert_eq!(config.recipients, ["root@example.com", "extra@example.com"]);
+    assert_eq!(
+      config.accounts[0].from(),
+      format!("a@{}", crate::util::hostname())
+    );
+
+    let missing = dir.path().join("config.d").join("missing.json");
+    let err = load_config(&missing).await.unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      format!("no configuration found; tried `{}`", missing.display())
+    );
+  }
+
+  /// Check that references in configuration values are resolved.
+  #[test]
+  fn interpolation() {
+    let dir = tempdir().unwrap();
+    let secret = dir.path().join("secret");
+    let () = std::fs::write(&secret, "hunter2\n").unwrap();
+    let env = |name: &str| (name == "SMTP_USER").then(|| "user".to_string());
+
+    let mut value = serde_json::json!({
+      "accounts": [{
+        "from": "maily@${HOSTNAME}",
+        "user": "${env:SMTP_USER}",
+        "password": format!("@file:{}", secret.display()),
+      }],
+      "subject": "cost: $${literal}",
+      "queue_expiry": 60,
+    });
+    let () = interpolate(&mut value, "", &env).unwrap();
+    assert_eq!(
+      value,
+      serde_json::json!({
+        "accounts": [{
+          "from": format!("maily@{}", crate::util::hostname()),
+          "user": "user",
+          "password": "hunter2",
+        }],
+        "subject": "cost: ${literal}",
+        "queue_expiry": 60,
+      })
+    );
+
+    let mut value = serde_json::json!({"accounts": [{"user": "${env:UNDEFINED}"}]});
+    let err = interpolate(&mut value, "", &env).unwrap_err();
+    assert_eq!(
+      format!("{err:#}"),
+      "failed to interpolate `accounts[0].user`: environment variable `UNDEFINED` is not defined"
+    );
+
+    let mut value = serde_json::json!({"from": "${USER}"});
+    let err = interpolate(&mut value, "", &env).unwrap_err();
+    assert_eq!(
+      format!("{err:#}"),
+      "failed to interpolate `from`: unknown variable `USER`"
+    );
+  }
+
+  /// Check that configurations of older versions are migrated as
+  /// expected.
+  #[tokio::test]
+  async fn migration() {
+    let path = Path::new("config.json");
+    let legacy = br#"{
+      "accounts": [],
+      "recipients": ["admin@example.com"],
+      "pgp-keybox": "/tmp/keybox",
+      "error_notification": {"subject": "oops"},
+      "filters": [{"command": "cat", "args": []}]
+    }"#;
+    let data = migrate_config(path, legacy).unwrap().unwrap();
+    let value = parse_config::<serde_json::Value>(path, &data).unwrap();
+    let keys = value.as_object().unwrap().keys().collect::<Vec<_>>();
+    assert_eq!(
+      keys,
+      [
+        "version",
+        "accounts",
+        "recipients",
+        "pgp_keybox",
+        "error_notification",
+        "filters"
+      ]
+    );
+    assert_eq!(
+      value,
+      serde_json::json!({
+        "version": CONFIG_VERSION,
+        "accounts": [],
+        "recipients": ["admin@example.com"],
+        "pgp_keybox": "/tmp/keybox",
+        "error_notification": {"subject": "oops"},
+        "filters": [{"command": "cat", "args": []}],
+      })
+    );
+    assert_eq!(migrate_config(path, &data).unwrap(), None);
+
+    let conflicting = br#"{"pgp-keybox": "/tmp/legacy", "pgp_keybox": "/tmp/keybox"}"#;
+    let data = migrate_config(path, conflicting).unwrap().unwrap();
+    let value = parse_config::<serde_json::Value>(path, &data).unwrap();
+    assert_eq!(
+      value,
+      serde_json::json!({"version": CONFIG_VERSION, "pgp_keybox": "/tmp/keybox"})
+    );
+
+    let err = migrate_config(path, br#"{"version": 99}"#).unwrap_err();
+    assert_eq!(
+      format!("{err:#}"),
+      format!(
+        "failed to migrate configuration `config.json`: configuration version 99 is not \
+         supported; the newest supported version is {CONFIG_VERSION}"
+      )
+    );
+    let err = migrate_config(path, br#"{"version": "1"}"#).unwrap_err();
+    assert_eq!(
+      format!("{:#}", err.root_cause()),
+      r#"`"1"` is not a valid configuration version"#
+    );
+
+    let dir = tempdir().unwrap();
+    let legacy_path = dir.path().join("legacy.json");
+    let () = std::fs::write(&legacy_path, legacy).unwrap();
+    let value = Loader::default().load_file(&legacy_path).await.unwrap();
+    let config = from_json_value::<Config>(value).unwrap();
+    assert_eq!(config.version, CONFIG_VERSION);
+    assert_eq!(config.error_notification.subject, "oops");
+  }
+
+  /// Check that profiles override configuration attributes as
+  /// expected.
+  #[tokio::test]
+  async fn profiles() {
+    let config = r#"{
+      "accounts": [
+        {"mailbox": "/a", "mailbox_format": "mbox", "from": "A <a@example.com>"},
+        {"mailbox": "/b", "mailbox_format": "mbox", "from": "b@example.com"}
+      ],
+      "recipients": ["admin@example.com"],
+      "profiles": {
+        "oncall": {
+          "accounts": ["b@example.com", "a@example.com"],
+          "recipients": ["oncall@example.com"],
+          "subject_prefix": "[oncall]",
+          "filters": [{"command": "cat"}]
+        },
+        "broken": {"accounts": ["c@example.com"], "recipients": ["invalid"]}
+      }
+    }"#;
+    let config = from_json::<Config>(config).unwrap();
+    let report = config.validate(false).await;
+    let errors = report
+      .errors
+      .iter()
+      .map(|err| format!("{err:#}"))
+      .collect::<Vec<_>>();
+    assert_eq!(
+      errors,
+      [
+        "profile `broken` is invalid: no account with 'From' identifier `c@example.com` configured",
+        "profile `broken` is invalid: failed to parse recipient specification: `invalid`: Invalid input",
+      ]
+    );
+
+    // Attributes unknown to the profile are retained.
+    let profile = &config.profiles["oncall"];
+    assert_eq!(
+      profile.extensions["filters"],
+      serde_json::json!([{"command": "cat"}])
+    );
+
+    let (accounts, recipients, opts) = config.into_profile_inputs("oncall").unwrap();
+    let from = accounts.iter().map(Account::from).collect::<Vec<_>>();
+    assert_eq!(from, ["b@example.com", "A <a@example.com>"]);
+    assert_eq!(recipients, ["oncall@example.com"]);
+    assert_eq!(opts.subject_prefix.as_deref(), Some("[oncall]"));
+
+    let config = from_json::<Config>(r#"{"accounts": [], "recipients": []}"#).unwrap();
+    let err = config.into_profile_inputs("oncall").unwrap_err();
+    assert_eq!(err.to_string(), "profile `oncall` is not defined");
+  }
+
+  /// Check that configuration validation reports problems as expected.
+  #[tokio::test]
+  async fn validation() {
+    let dir = tempdir().unwrap();
+    let mbox = dir.path().join("mbox");
+    let missing = dir.path().join("missing").join("mbox");
+    let config = format!(
+      r#"{{
+        "accounts": [
+          {{"mailbox": {mbox:?}, "mailbox_format": "mbox", "from": "Host <host@example.com>"}},
+          {{"mailbox": {missing:?}, "mailbox_format": "mbox", "from": "host@example.com"}},
+          {{"mailbox": {mbox:?}, "mailbox_format": "mbox", "from": "not an address"}}
+        ],
+        "recipients": ["admin@example.com"]
+      }}"#
+    );
+    let config = from_json::<Config>(&config).unwrap();
+
+    let report = config.validate(false).await;
+    assert!(!report.is_ok());
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+    assert_eq!(report.accounts.len(), 3);
+    assert_eq!(report.accounts[0].target, format!("mbox:{}", mbox.display()));
+    assert!(!report.accounts[0].connected);
+    assert!(report.accounts[0].errors.is_empty());
+    assert!(report.accounts[1].errors.is_empty());
+    assert_eq!(
+      report.accounts[2].errors[0].to_string(),
+      "failed to parse 'From' specification: `not an address`"
+    );
+
+    let report = config.validate(true).await;
+    assert!(report.accounts[0].connected);
+    assert!(report.accounts[0].errors.is_empty());
+    assert_eq!(
+      report.accounts[1].errors[0].to_string(),
+      format!(
+        "directory `{}` of mbox does not exist",
+        missing.parent().unwrap().display()
+      )
+    );
+    assert!(!report.accounts[2].connected);
+    assert!(!mbox.exists());
+
+    let config = from_json::<Config>(r#"{"accounts": [], "recipients": ["x"]}"#).unwrap();
+    let report = config.validate(true).await;
+    assert_eq!(report.errors.len(), 2);
+    assert_eq!(report.errors[0].to_string(), "no email accounts configured");
+  }
+}
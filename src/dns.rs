@@ -0,0 +1,338 @@
+// Copyright (C) 2024 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// A minimal DNS client, just capable enough to look up MX records, as
+// described by RFC 1035.
+
+use std::fs::read_to_string;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context as _;
+use anyhow::Result;
+
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+use crate::rand::Rng;
+
+
+/// The default DNS port.
+const DNS_PORT: u16 = 53;
+/// The time we wait for a response before retrying.
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// The number of attempts we make before giving up.
+const ATTEMPTS: usize = 3;
+/// The DNS record type for MX records.
+const TYPE_MX: u16 = 15;
+/// The DNS record class for Internet records.
+const CLASS_IN: u16 = 1;
+
+
+/// A mail exchanger as reported by an MX record.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Mx {
+  /// The preference of the mail exchanger; lower values are preferred.
+  pub preference: u16,
+  /// The name of the mail exchanger.
+  pub exchange: String,
+}
+
+
+/// The outcome of an MX lookup.
+#[derive(Debug, PartialEq)]
+pub(crate) enum MxLookup {
+  /// The domain does not exist.
+  NoDomain,
+  /// The domain exists and has the provided MX records, which may be
+  /// none.
+  Records(Vec<Mx>),
+}
+
+
+/// Parse a resolver specification, which is either an `ip[:port]`
+/// address or the path to a file in `resolv.conf` format, in which case
+/// the first name server listed in it will be used.
+pub(crate) fn parse_resolver(resolver: &str) -> Result<SocketAddr> {
+  if resolver.starts_with('/') {
+    let path = Path::new(resolver);
+    let content = read_to_string(path)
+      .with_context(|| format!("failed to read `{}`", path.display()))?;
+    let server = content
+      .lines()
+      .find_map(|line| line.trim().strip_prefix("nameserver"))
+      .map(str::trim)
+      .with_context(|| format!("no name server found in `{}`", path.display()))?;
+    let addr = server
+      .parse::<IpAddr>()
+      .with_context(|| format!("failed to parse name server address `{server}`"))?;
+    Ok(SocketAddr::new(addr, DNS_PORT))
+  } else if let Ok(addr) = resolver.parse::<IpAddr>() {
+    Ok(SocketAddr::new(addr, DNS_PORT))
+  } else {
+    resolver
+      .parse::<SocketAddr>()
+      .with_context(|| format!("failed to parse resolver address `{resolver}`"))
+  }
+}
+
+
+/// Build a query for the MX records of `domain`.
+fn encode_query(id: u16, domain: &str) -> Result<Vec<u8>> {
+  let mut query = Vec::with_capacity(domain.len() + 18);
+  let () = query.extend_from_slice(&id.to_be_bytes());
+  // Flags: standard query with recursion desired.
+  let () = query.extend_from_slice(&0x0100u16.to_be_bytes());
+  // One question, no other records.
+  let () = query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+
+  for label in domain.trim_end_matches('.').split('.') {
+    ensure!(
+      !label.is_empty() && label.len() < 64,
+      "domain `{domain}` contains invalid label"
+    );
+    let () = query.push(label.len() as u8);
+    let () = query.extend_from_slice(label.as_bytes());
+  }
+  let () = query.push(0);
+  let () = query.extend_from_slice(&TYPE_MX.to_be_bytes());
+  let () = query.extend_from_slice(&CLASS_IN.to_be_bytes());
+  Ok(query)
+}
+
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16> {
+  let bytes = data
+    .get(offset..offset + 2)
+    .context("DNS response is truncated")?;
+  Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+
+/// Decode a (potentially compressed) domain name starting at `offset`,
+/// returning it along with the offset just past it.
+fn decode_name(data: &[u8], mut offset: usize) -> Result<(String, usize)> {
+  let mut name = String::new();
+  let mut end = None;
+  // Guard against pointer loops by bounding the number of jumps.
+  let mut jumps = 0;
+
+  loop {
+    let len = *data.get(offset).context("DNS response is truncated")?;
+    match len {
+      0 => {
+        let end = end.unwrap_or(offset + 1);
+        break Ok((name, end))
+      },
+      len if len & 0xc0 == 0xc0 => {
+        let pointer = usize::from(read_u16(data, offset)? & 0x3fff);
+        if end.is_none() {
+          end = Some(offset + 2);
+        }
+        jumps += 1;
+        ensure!(jumps < 64, "DNS response contains name compression loop");
+        offset = pointer;
+      },
+      len => {
+        let len = usize::from(len);
+        let label = data
+          .get(offset + 1..offset + 1 + len)
+          .context("DNS response is truncated")?;
+        if !name.is_empty() {
+          let () = name.push('.');
+        }
+        let () = name.push_str(&String::from_utf8_lossy(label));
+        offset += 1 + len;
+      },
+    }
+  }
+}
+
+
+/// Decode a response to an MX query.
+fn decode_response(data: &[u8]) -> Result<MxLookup> {
+  let flags = read_u16(data, 2)?;
+  match flags & 0x000f {
+    0 => (),
+    3 => return Ok(MxLookup::NoDomain),
+    rcode => bail!("DNS server reported error (RCODE {rcode})"),
+  }
+  ensure!(flags & 0x0200 == 0, "DNS response was truncated");
+
+  let questions = read_u16(data, 4)?;
+  let answers = read_u16(data, 6)?;
+
+  let mut offset = 12;
+  for _ in 0..questions {
+    let (_name, end) = decode_name(data, offset)?;
+    // Skip type and class.
+    offset = end + 4;
+  }
+
+  let mut records = Vec::new();
+  for _ in 0..answers {
+    let (_name, end) = decode_name(data, offset)?;
+    let type_ = read_u16(data, end)?;
+    let class = read_u16(data, end + 2)?;
+    let len = usize::from(read_u16(data, end + 8)?);
+    let rdata = end + 10;
+    ensure!(rdata + len <= data.len(), "DNS response is truncated");
+
+    if type_ == TYPE_MX && class == CLASS_IN {
+      let preference = read_u16(data, rdata)?;
+      let (exchange, _end) = decode_name(data, rdata + 2)?;
+      let () = records.push(Mx {
+        preference,
+        exchange,
+      });
+    }
+    offset = rdata + len;
+  }
+
+  let () = records.sort_by_key(|mx| mx.preference);
+  Ok(MxLookup::Records(records))
+}
+
+
+/// Look up the MX records of `domain` using the DNS server at
+/// `resolver`.
+pub(crate) async fn lookup_mx(resolver: SocketAddr, domain: &str) -> Result<MxLookup> {
+  let local = if resolver.is_ipv4() {
+    "0.0.0.0:0"
+  } else {
+    "[::]:0"
+  };
+  let socket = UdpSocket::bind(local)
+    .await
+    .context("failed to create UDP socket")?;
+  let () = socket
+    .connect(resolver)
+    .await
+    .with_context(|| format!("failed to connect to DNS server {resolver}"))?;
+
+  let id = Rng::new().rand_u32() as u16;
+  let query = encode_query(id, domain)?;
+
+  for _ in 0..ATTEMPTS {
+    let _count = socket
+      .send(&query)
+      .await
+      .with_context(|| format!("failed to send DNS query to {resolver}"))?;
+
+    let receive = async {
+      let mut buffer = [0; 4096];
+      loop {
+        let count = socket
+          .recv(&mut buffer)
+          .await
+          .with_context(|| format!("failed to receive DNS response from {resolver}"))?;
+        let response = &buffer[..count];
+        // Responses not matching our query, e.g., because they are
+        // spoofed, are ignored and we continue waiting for the right
+        // one.
+        if read_u16(response, 0).ok() == Some(id) {
+          break decode_response(response)
+            .with_context(|| format!("failed to look up MX records of `{domain}`"))
+        }
+      }
+    };
+
+    match timeout(TIMEOUT, receive).await {
+      Ok(result) => return result,
+      Err(_elapsed) => continue,
+    }
+  }
+
+  bail!("DNS server {resolver} did not respond to MX query for `{domain}`")
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::io::Write as _;
+
+  use tempfile::NamedTempFile;
+
+  use tokio::spawn;
+
+
+  /// Check that we can parse resolver specifications.
+  #[test]
+  fn resolver_parsing() {
+    let addr = parse_resolver("127.0.0.1").unwrap();
+    assert_eq!(addr, "127.0.0.1:53".parse().unwrap());
+    let addr = parse_resolver("127.0.0.1:5353").unwrap();
+    assert_eq!(addr, "127.0.0.1:5353".parse().unwrap());
+    let addr = parse_resolver("::1").unwrap();
+    assert_eq!(addr, "[::1]:53".parse().unwrap());
+
+    let mut file = NamedTempFile::new().unwrap();
+    let () = writeln!(file, "# comment\nsearch example.com\nnameserver 10.0.0.1").unwrap();
+    let addr = parse_resolver(file.path().to_str().unwrap()).unwrap();
+    assert_eq!(addr, "10.0.0.1:53".parse().unwrap());
+
+    assert!(parse_resolver("localhost").is_err());
+  }
+
+  /// Check that we can look up MX records from a DNS server, including
+  /// handling of compressed names and responses with unexpected IDs.
+  #[tokio::test]
+  async fn mx_lookup() {
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addr().unwrap();
+
+    let server = spawn(async move {
+      let mut buffer = [0; 512];
+      let (count, peer) = server.recv_from(&mut buffer).await.unwrap();
+      let query = &buffer[..count];
+
+      // A response with a different ID should be ignored.
+      let mut response = query.to_vec();
+      response[0] = !response[0];
+      response[2..4].copy_from_slice(&0x8183u16.to_be_bytes());
+      let _count = server.send_to(&response, peer).await.unwrap();
+
+      let mut response = query.to_vec();
+      // Flags: response, recursion available; one answer.
+      response[2..4].copy_from_slice(&0x8180u16.to_be_bytes());
+      response[6..8].copy_from_slice(&2u16.to_be_bytes());
+      // Answer 1: pointer to question name, MX, IN, TTL, length.
+      response.extend_from_slice(&[0xc0, 12, 0, 15, 0, 1, 0, 0, 1, 0, 0, 7]);
+      response.extend_from_slice(&[0, 20, 2, b'm', b'x', 0xc0, 12]);
+      // Answer 2: with a preference lower than the first one.
+      response.extend_from_slice(&[0xc0, 12, 0, 15, 0, 1, 0, 0, 1, 0, 0, 7]);
+      response.extend_from_slice(&[0, 10, 2, b'm', b'y', 0xc0, 12]);
+      let _count = server.send_to(&response, peer).await.unwrap();
+    });
+
+    let lookup = lookup_mx(addr, "example.com").await.unwrap();
+    let () = server.await.unwrap();
+
+    let expected = MxLookup::Records(vec![
+      Mx {
+        preference: 10,
+        exchange: "my.example.com".to_string(),
+      },
+      Mx {
+        preference: 20,
+        exchange: "mx.example.com".to_string(),
+      },
+    ]);
+    assert_eq!(lookup, expected);
+  }
+
+  /// Check that we report non-existent domains as such.
+  #[test]
+  fn nxdomain_response() {
+    let mut response = encode_query(42, "example.invalid").unwrap();
+    response[2..4].copy_from_slice(&0x8183u16.to_be_bytes());
+    let lookup = decode_response(&response).unwrap();
+    assert_eq!(lookup, MxLookup::NoDomain);
+  }
+}
@@ -0,0 +1,126 @@
+// Copyright (C) 2024 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::error::Error as StdError;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::fmt::Result as FmtResult;
+
+use anyhow::Error;
+
+use lettre::Address;
+
+
+/// Join the provided addresses into a comma separated list.
+fn join(addrs: &[Address]) -> String {
+  addrs
+    .iter()
+    .map(Address::as_ref)
+    .collect::<Vec<_>>()
+    .join(", ")
+}
+
+
+/// An error aggregating the errors of multiple failed attempts at
+/// performing an operation, such as sending an email with different
+/// accounts.
+///
+/// The individual errors are retained as-is, so that they can be
+/// inspected, e.g., via [`Error::downcast_ref`].
+#[derive(Debug)]
+pub struct Errors(Vec<Error>);
+
+impl Errors {
+  /// Combine `err` with the error(s) captured in `result`, if any.
+  pub(crate) fn combine(result: Result<(), Error>, err: Error) -> Error {
+    match result {
+      Ok(()) => err,
+      Err(mut overall) => {
+        if let Some(errors) = overall.downcast_mut::<Self>() {
+          let () = errors.0.push(err);
+          overall
+        } else {
+          Error::new(Self(vec![overall, err]))
+        }
+      },
+    }
+  }
+
+  /// Retrieve the individual errors, in the order they occurred.
+  #[inline]
+  pub fn errors(&self) -> &[Error] {
+    &self.0
+  }
+}
+
+impl Display for Errors {
+  fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+    for (i, err) in self.0.iter().enumerate() {
+      if i > 0 {
+        let () = f.write_str("; ")?;
+      }
+      // Make sure to include the entire chain of causes, as otherwise
+      // the actual reason for a failure may be lost.
+      let () = write!(f, "{err:#}")?;
+    }
+    Ok(())
+  }
+}
+
+impl StdError for Errors {}
+
+
+/// An error indicating that an email got delivered to some of its
+/// recipients, but not to others.
+///
+/// When sending an email with multiple accounts, only the recipients
+/// that the email could not be delivered to are retried with the
+/// remaining accounts.
+#[derive(Debug)]
+pub struct PartialDelivery {
+  /// The recipients the email was delivered to.
+  delivered: Vec<Address>,
+  /// The recipients the email could not be delivered to.
+  failed: Vec<Address>,
+  /// The error describing why delivery to `failed` failed.
+  error: Error,
+}
+
+impl PartialDelivery {
+  pub(crate) fn new(delivered: Vec<Address>, failed: Vec<Address>, error: Error) -> Self {
+    Self {
+      delivered,
+      failed,
+      error,
+    }
+  }
+
+  /// Retrieve the recipients the email was delivered to.
+  #[inline]
+  pub fn delivered(&self) -> &[Address] {
+    &self.delivered
+  }
+
+  /// Retrieve the recipients the email could not be delivered to.
+  #[inline]
+  pub fn failed(&self) -> &[Address] {
+    &self.failed
+  }
+}
+
+impl Display for PartialDelivery {
+  fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+    write!(
+      f,
+      "email was delivered to {}, but not to {}",
+      join(&self.delivered),
+      join(&self.failed)
+    )
+  }
+}
+
+impl StdError for PartialDelivery {
+  fn source(&self) -> Option<&(dyn StdError + 'static)> {
+    Some(self.error.as_ref())
+  }
+}
@@ -0,0 +1,214 @@
+// Copyright (C) 2024 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::borrow::Cow;
+use std::path::Path;
+use std::process;
+
+use anyhow::Context as _;
+use anyhow::Result;
+
+use lettre::address::Envelope;
+
+use tokio::fs::OpenOptions;
+use tokio::io::stderr;
+use tokio::io::AsyncWriteExt as _;
+use tokio::net::UnixDatagram;
+
+use crate::local;
+use crate::FallbackSink;
+use crate::LocalAccount;
+use crate::MailboxFormat;
+
+
+/// The path to the syslog socket.
+const SYSLOG_SOCKET: &str = "/dev/log";
+/// The path to the socket of the systemd journal.
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+/// The maximum number of message bytes we log to the systemd journal.
+///
+/// The journal's native protocol transfers each entry in a single
+/// datagram, the size of which is limited by the socket's send buffer
+/// (about 208 KiB by default). Larger entries would have to be passed
+/// as a sealed memory file, which we don't support. Hence, we truncate
+/// longer messages instead.
+const JOURNAL_MESSAGE_MAX: usize = 128 * 1024;
+/// The syslog facility for the mail system.
+const FACILITY_MAIL: u8 = 2;
+/// The syslog severity for error conditions.
+const SEVERITY_ERR: u8 = 3;
+/// The identifier we log messages under.
+const IDENTIFIER: &str = "maily";
+
+
+/// Append a message to a plain file, separating it from previously
+/// stored ones by an empty line.
+async fn append(path: &Path, message: &[u8]) -> Result<()> {
+  let mut file = OpenOptions::new()
+    .append(true)
+    .create(true)
+    .open(path)
+    .await
+    .with_context(|| format!("failed to open `{}`", path.display()))?;
+
+  let mut data = message.to_vec();
+  let () = data.extend_from_slice(b"\n");
+  let () = file
+    .write_all(&data)
+    .await
+    .with_context(|| format!("failed to append message to `{}`", path.display()))?;
+  let () = file
+    .sync_all()
+    .await
+    .with_context(|| format!("failed to sync `{}`", path.display()))?;
+  Ok(())
+}
+
+
+/// Encode the lines of a message as syslog (RFC 3164) datagrams.
+fn encode_syslog(message: &[u8]) -> Vec<Vec<u8>> {
+  let priority = FACILITY_MAIL * 8 + SEVERITY_ERR;
+  let pid = process::id();
+
+  String::from_utf8_lossy(message)
+    .lines()
+    .map(|line| format!("<{priority}>{IDENTIFIER}[{pid}]: {line}").into_bytes())
+    .collect()
+}
+
+
+/// Log a message to the syslog daemon listening on `socket`, one line at
+/// a time.
+async fn syslog(socket: &Path, message: &[u8]) -> Result<()> {
+  let datagram = UnixDatagram::unbound().context("failed to create Unix datagram socket")?;
+  for data in encode_syslog(message) {
+    let _count = datagram
+      .send_to(&data, socket)
+      .await
+      .with_context(|| format!("failed to send message to syslog socket `{}`", socket.display()))?;
+  }
+  Ok(())
+}
+
+
+/// Encode a message using the native protocol of the systemd journal,
+/// truncating it to [`JOURNAL_MESSAGE_MAX`] bytes.
+fn encode_journal(message: &[u8]) -> Vec<u8> {
+  let mut data = format!(
+    "PRIORITY={SEVERITY_ERR}\nSYSLOG_FACILITY={FACILITY_MAIL}\nSYSLOG_IDENTIFIER={IDENTIFIER}\n"
+  )
+  .into_bytes();
+
+  let message = if message.len() > JOURNAL_MESSAGE_MAX {
+    let notice = format!(
+      "\n[message truncated from {} to {JOURNAL_MESSAGE_MAX} bytes]\n",
+      message.len()
+    );
+    Cow::Owned([&message[..JOURNAL_MESSAGE_MAX], notice.as_bytes()].concat())
+  } else {
+    Cow::Borrowed(message)
+  };
+  // The message will contain newlines and so we have to use the binary
+  // safe encoding, with an explicit little endian length.
+  let () = data.extend_from_slice(b"MESSAGE\n");
+  let () = data.extend_from_slice(&(message.len() as u64).to_le_bytes());
+  let () = data.extend_from_slice(&message);
+  let () = data.push(b'\n');
+  data
+}
+
+
+/// Log a message to the systemd journal listening on `socket`.
+async fn journal(socket: &Path, message: &[u8]) -> Result<()> {
+  let datagram = UnixDatagram::unbound().context("failed to create Unix datagram socket")?;
+  let _count = datagram
+    .send_to(&encode_journal(message), socket)
+    .await
+    .with_context(|| format!("failed to send message to journal socket `{}`", socket.display()))?;
+  Ok(())
+}
+
+
+/// Store an already formatted email in the provided fallback sink.
+pub(crate) async fn store(
+  sink: &FallbackSink<'_>,
+  envelope: &Envelope,
+  message: &[u8],
+) -> Result<()> {
+  match sink {
+    FallbackSink::File(path) => append(path, message).await,
+    FallbackSink::Mbox(path) => {
+      let account = LocalAccount {
+        mailbox: Cow::Borrowed(path),
+        mailbox_format: MailboxFormat::Mbox,
+        from: Cow::Borrowed(""),
+      };
+      local::deliver(&account, envelope, message).await
+    },
+    FallbackSink::Syslog => syslog(Path::new(SYSLOG_SOCKET), message).await,
+    FallbackSink::Journal => journal(Path::new(JOURNAL_SOCKET), message).await,
+    FallbackSink::Stderr => {
+      let mut stderr = stderr();
+      let () = stderr
+        .write_all(message)
+        .await
+        .context("failed to write message to stderr")?;
+      let () = stderr.flush().await.context("failed to flush stderr")?;
+      Ok(())
+    },
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use tempfile::tempdir;
+
+
+  /// Check that we log messages line by line to syslog.
+  #[tokio::test]
+  async fn syslog_logging() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("log");
+    let socket = UnixDatagram::bind(&path).unwrap();
+
+    let () = syslog(&path, b"Subject: test\r\n\r\nbody\r\n").await.unwrap();
+
+    let prefix = format!("<19>maily[{}]: ", process::id());
+    let mut buffer = [0; 1024];
+    for line in ["Subject: test", "", "body"] {
+      let count = socket.recv(&mut buffer).await.unwrap();
+      assert_eq!(&buffer[..count], format!("{prefix}{line}").as_bytes());
+    }
+  }
+
+  /// Check that we encode messages for the journal correctly.
+  #[test]
+  fn journal_encoding() {
+    let data = encode_journal(b"a\nb");
+    let mut expected =
+      b"PRIORITY=3\nSYSLOG_FACILITY=2\nSYSLOG_IDENTIFIER=maily\nMESSAGE\n".to_vec();
+    let () = expected.extend_from_slice(&[3, 0, 0, 0, 0, 0, 0, 0]);
+    let () = expected.extend_from_slice(b"a\nb\n");
+    assert_eq!(data, expected);
+  }
+
+  /// Check that we truncate messages exceeding the journal's datagram
+  /// size limit.
+  #[test]
+  fn journal_truncation() {
+    let message = vec![b'x'; JOURNAL_MESSAGE_MAX + 1];
+    let data = encode_journal(&message);
+    let notice = format!(
+      "\n[message truncated from {} to {JOURNAL_MESSAGE_MAX} bytes]\n",
+      JOURNAL_MESSAGE_MAX + 1
+    );
+    let length = JOURNAL_MESSAGE_MAX + notice.len();
+    let start = data.len() - length - 1;
+    assert_eq!(data[start - 8..start], (length as u64).to_le_bytes());
+    assert_eq!(data[start..start + JOURNAL_MESSAGE_MAX], message[1..]);
+    assert!(data.ends_with(format!("{notice}\n").as_bytes()));
+  }
+}
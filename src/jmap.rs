@@ -0,0 +1,461 @@
+// Copyright (C) 2024 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context as _;
+use anyhow::Result;
+
+use lettre::address::Envelope;
+
+use reqwest::header::CONTENT_TYPE;
+use reqwest::Client;
+use reqwest::RequestBuilder;
+use reqwest::Url;
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::from_slice as from_json;
+use serde_json::json;
+use serde_json::Value;
+
+use crate::JmapAccount;
+
+
+/// The capability identifying JMAP mail support.
+const MAIL: &str = "urn:ietf:params:jmap:mail";
+/// The capability identifying JMAP email submission support.
+const SUBMISSION: &str = "urn:ietf:params:jmap:submission";
+/// The timeout applied to each HTTP request.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+
+/// The relevant parts of a JMAP session resource.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Session {
+  api_url: String,
+  upload_url: String,
+  primary_accounts: HashMap<String, String>,
+}
+
+/// The response to a blob upload.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Upload {
+  blob_id: String,
+}
+
+/// The response to a JMAP API request.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Response {
+  method_responses: Vec<(String, Value, String)>,
+}
+
+
+/// A client for a single JMAP account.
+struct Jmap<'acc> {
+  client: Client,
+  account: &'acc JmapAccount<'acc>,
+  session_url: Url,
+}
+
+impl<'acc> Jmap<'acc> {
+  fn new(account: &'acc JmapAccount<'acc>) -> Result<Self> {
+    let client = Client::builder()
+      .timeout(TIMEOUT)
+      .build()
+      .context("failed to create HTTP client")?;
+    let session_url = Url::parse(&account.jmap_url)
+      .with_context(|| format!("failed to parse JMAP URL `{}`", account.jmap_url))?;
+
+    Ok(Self {
+      client,
+      account,
+      session_url,
+    })
+  }
+
+  fn authenticate(&self, request: RequestBuilder) -> RequestBuilder {
+    if self.account.user.is_empty() {
+      request.bearer_auth(&self.account.password)
+    } else {
+      request.basic_auth(&self.account.user, Some(&self.account.password))
+    }
+  }
+
+  /// Resolve a URL as reported by the server, which may be relative to
+  /// the session resource.
+  fn resolve(&self, url: &str) -> Result<Url> {
+    self
+      .session_url
+      .join(url)
+      .with_context(|| format!("failed to parse URL `{url}` reported by JMAP server"))
+  }
+
+  async fn send<T>(&self, request: RequestBuilder) -> Result<T>
+  where
+    T: DeserializeOwned,
+  {
+    let response = self
+      .authenticate(request)
+      .send()
+      .await?
+      .error_for_status()?;
+    let body = response.bytes().await?;
+    let value = from_json::<T>(&body).context("failed to parse JMAP server response")?;
+    Ok(value)
+  }
+
+  async fn session(&self) -> Result<Session> {
+    self
+      .send(self.client.get(self.session_url.clone()))
+      .await
+      .with_context(|| format!("failed to retrieve JMAP session from `{}`", self.session_url))
+  }
+
+  async fn upload(&self, session: &Session, account_id: &str, message: &[u8]) -> Result<String> {
+    let url = self.resolve(&session.upload_url.replace("{accountId}", account_id))?;
+    let request = self
+      .client
+      .post(url)
+      .header(CONTENT_TYPE, "message/rfc822")
+      .body(message.to_vec());
+    let upload = self
+      .send::<Upload>(request)
+      .await
+      .context("failed to upload message to JMAP server")?;
+    Ok(upload.blob_id)
+  }
+
+  /// Invoke the given JMAP methods, returning the arguments of their
+  /// responses in order.
+  async fn call(&self, session: &Session, calls: Value) -> Result<Vec<Value>> {
+    let url = self.resolve(&session.api_url)?;
+    let request = json!({
+      "using": ["urn:ietf:params:jmap:core", MAIL, SUBMISSION],
+      "methodCalls": calls,
+    });
+    let request = self
+      .client
+      .post(url)
+      .header(CONTENT_TYPE, "application/json")
+      .body(request.to_string());
+    let response = self
+      .send::<Response>(request)
+      .await
+      .context("JMAP API request failed")?;
+
+    response
+      .method_responses
+      .into_iter()
+      .map(|(name, arguments, _id)| {
+        if name == "error" {
+          Err(anyhow!(
+            "JMAP server reported error: {}",
+            arguments
+              .get("description")
+              .or_else(|| arguments.get("type"))
+              .unwrap_or(&arguments)
+          ))
+        } else {
+          Ok(arguments)
+        }
+      })
+      .collect()
+  }
+}
+
+
+/// Check a `/set` or `/import` style method response for a failure to
+/// create the object with the given creation ID.
+fn check_created(response: &Value, id: &str, what: &str) -> Result<()> {
+  if let Some(err) = response.get("notCreated").and_then(|map| map.get(id)) {
+    bail!("JMAP server failed to create {what}: {err}");
+  }
+  if response.get("created").and_then(|map| map.get(id)).is_none() {
+    bail!("JMAP server did not report {what} as created");
+  }
+  Ok(())
+}
+
+
+/// Pick the mailbox to store the submitted email in, preferring the one
+/// designated for sent emails over the drafts one.
+fn find_mailbox(mailboxes: &Value) -> Option<&str> {
+  let mailboxes = mailboxes.get("list")?.as_array()?;
+  ["sent", "drafts"].into_iter().find_map(|role| {
+    mailboxes
+      .iter()
+      .find(|mailbox| mailbox.get("role").and_then(Value::as_str) == Some(role))
+      .and_then(|mailbox| mailbox.get("id")?.as_str())
+  })
+}
+
+
+/// Pick the identity to submit the email with, based on the sender
+/// address.
+fn find_identity<'ids>(identities: &'ids Value, from: &str) -> Option<&'ids str> {
+  let identities = identities.get("list")?.as_array()?;
+  let domain = from.rsplit_once('@').map(|(_, domain)| domain);
+  let email = |identity: &'ids Value| identity.get("email").and_then(Value::as_str);
+
+  identities
+    .iter()
+    .find(|identity| email(identity).is_some_and(|email| email.eq_ignore_ascii_case(from)))
+    .or_else(|| {
+      identities.iter().find(|identity| {
+        email(identity)
+          .and_then(|email| email.strip_prefix("*@"))
+          .is_some_and(|wildcard| Some(wildcard) == domain)
+      })
+    })
+    .and_then(|identity| identity.get("id")?.as_str())
+}
+
+
+/// Submit an already formatted email via the JMAP server described by
+/// `account`.
+pub(crate) async fn send(
+  account: &JmapAccount<'_>,
+  envelope: &Envelope,
+  message: &[u8],
+) -> Result<()> {
+  let jmap = Jmap::new(account)?;
+  let session = jmap.session().await?;
+  let account_id = session
+    .primary_accounts
+    .get(SUBMISSION)
+    .or_else(|| session.primary_accounts.get(MAIL))
+    .context("JMAP server does not offer an account with email submission support")?;
+
+  let blob_id = jmap.upload(&session, account_id, message).await?;
+
+  let responses = jmap
+    .call(
+      &session,
+      json!([
+        ["Mailbox/get", {"accountId": account_id, "properties": ["id", "role"]}, "0"],
+        ["Identity/get", {"accountId": account_id}, "1"],
+      ]),
+    )
+    .await?;
+  let [mailboxes, identities] = responses.as_slice() else {
+    bail!("JMAP server sent unexpected number of method responses");
+  };
+
+  let mailbox_id = find_mailbox(mailboxes).context("failed to find sent or drafts mailbox")?;
+  let from = envelope
+    .from()
+    .map(ToString::to_string)
+    .unwrap_or_default();
+  let identity_id = find_identity(identities, &from)
+    .with_context(|| format!("failed to find sending identity for `{from}`"))?;
+  let recipients = envelope
+    .to()
+    .iter()
+    .map(|recipient| json!({"email": recipient.to_string()}))
+    .collect::<Vec<_>>();
+
+  let responses = jmap
+    .call(
+      &session,
+      json!([
+        ["Email/import", {
+          "accountId": account_id,
+          "emails": {
+            "email": {
+              "blobId": blob_id,
+              "mailboxIds": {mailbox_id: true},
+              "keywords": {"$seen": true},
+            },
+          },
+        }, "0"],
+        ["EmailSubmission/set", {
+          "accountId": account_id,
+          "create": {
+            "submission": {
+              "identityId": identity_id,
+              "emailId": "#email",
+              "envelope": {
+                "mailFrom": {"email": from},
+                "rcptTo": recipients,
+              },
+            },
+          },
+        }, "1"],
+      ]),
+    )
+    .await?;
+  let [import, submission] = responses.as_slice() else {
+    bail!("JMAP server sent unexpected number of method responses");
+  };
+
+  let () = check_created(import, "email", "email")?;
+  let () = check_created(submission, "submission", "email submission")?;
+  Ok(())
+}
+
+
+/// Check that the JMAP session resource of `account` can be retrieved,
+/// which requires successful authentication.
+pub(crate) async fn check(account: &JmapAccount<'_>) -> Result<()> {
+  let jmap = Jmap::new(account)?;
+  let _session = jmap.session().await?;
+  Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::borrow::Cow;
+  use std::sync::Arc;
+  use std::sync::Mutex;
+
+  use tokio::io::AsyncBufReadExt as _;
+  use tokio::io::AsyncReadExt as _;
+  use tokio::io::AsyncWriteExt as _;
+  use tokio::io::BufReader;
+  use tokio::net::TcpListener;
+  use tokio::spawn;
+
+
+  /// The requests (path and body) received by our JMAP stand-in.
+  type Requests = Arc<Mutex<Vec<(String, Vec<u8>)>>>;
+
+
+  /// Serve HTTP requests in the fashion of a minimal JMAP server,
+  /// recording them in `requests`.
+  async fn serve(listener: TcpListener, requests: Requests) {
+    let addr = listener.local_addr().unwrap();
+    loop {
+      let (stream, _addr) = listener.accept().await.unwrap();
+      let mut stream = BufReader::new(stream);
+      let mut request = String::new();
+      let _count = stream.read_line(&mut request).await.unwrap();
+      let path = request.split(' ').nth(1).unwrap().to_string();
+
+      let mut length = 0;
+      loop {
+        let mut line = String::new();
+        let _count = stream.read_line(&mut line).await.unwrap();
+        if line == "\r\n" {
+          break
+        }
+        if let Some((name, value)) = line.split_once(':') {
+          if name.eq_ignore_ascii_case("content-length") {
+            length = value.trim().parse().unwrap();
+          }
+        }
+      }
+
+      let mut body = vec![0; length];
+      let () = stream.read_exact(&mut body).await.map(|_| ()).unwrap();
+
+      let response = match path.as_str() {
+        "/session" => json!({
+          "apiUrl": format!("http://{addr}/api"),
+          "uploadUrl": "/upload/{accountId}/",
+          "primaryAccounts": {SUBMISSION: "acc1", MAIL: "acc1"},
+        }),
+        "/upload/acc1/" => json!({"blobId": "blob1"}),
+        "/api" => {
+          let request = from_json::<Value>(&body).unwrap();
+          let calls = request["methodCalls"].as_array().unwrap();
+          if calls[0][0] == "Mailbox/get" {
+            json!({"methodResponses": [
+              ["Mailbox/get", {"list": [
+                {"id": "mb1", "role": "inbox"},
+                {"id": "mb2", "role": "sent"},
+              ]}, "0"],
+              ["Identity/get", {"list": [
+                {"id": "id1", "email": "other@example.com"},
+                {"id": "id2", "email": "*@example.com"},
+              ]}, "1"],
+            ]})
+          } else {
+            json!({"methodResponses": [
+              ["Email/import", {"created": {"email": {"id": "e1"}}}, "0"],
+              ["EmailSubmission/set", {"created": {"submission": {"id": "s1"}}}, "1"],
+            ]})
+          }
+        },
+        _ => panic!("unexpected request for `{path}`"),
+      };
+      let () = requests.lock().unwrap().push((path, body));
+
+      let response = response.to_string();
+      let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{response}",
+        response.len()
+      );
+      let () = stream.get_mut().write_all(response.as_bytes()).await.unwrap();
+    }
+  }
+
+  /// Check that we can submit an email via a JMAP server.
+  #[tokio::test]
+  async fn submission() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let _server = spawn(serve(listener, requests.clone()));
+
+    let account = JmapAccount {
+      jmap_url: Cow::Owned(format!("http://{addr}/session")),
+      from: Cow::Borrowed("me@example.com"),
+      user: Cow::Borrowed("me"),
+      password: Cow::Borrowed("secret"),
+    };
+    let envelope = Envelope::new(
+      Some("me@example.com".parse().unwrap()),
+      vec!["you@example.com".parse().unwrap()],
+    )
+    .unwrap();
+
+    let () = send(&account, &envelope, b"Subject: hi\r\n\r\nbody\r\n")
+      .await
+      .unwrap();
+
+    let requests = requests.lock().unwrap().clone();
+    let paths = requests
+      .iter()
+      .map(|(path, _body)| path.as_str())
+      .collect::<Vec<_>>();
+    assert_eq!(paths, ["/session", "/upload/acc1/", "/api", "/api"]);
+    assert_eq!(requests[1].1, b"Subject: hi\r\n\r\nbody\r\n");
+
+    let submit = from_json::<Value>(&requests[3].1).unwrap();
+    let calls = &submit["methodCalls"];
+    assert_eq!(calls[0][1]["emails"]["email"]["blobId"], "blob1");
+    assert_eq!(calls[0][1]["emails"]["email"]["mailboxIds"]["mb2"], true);
+    let submission = &calls[1][1]["create"]["submission"];
+    assert_eq!(submission["identityId"], "id2");
+    assert_eq!(submission["emailId"], "#email");
+    assert_eq!(
+      submission["envelope"]["rcptTo"],
+      json!([{"email": "you@example.com"}])
+    );
+
+    // An email from an address without matching identity must not get
+    // submitted under an unrelated one.
+    let envelope = Envelope::new(
+      Some("me@example.org".parse().unwrap()),
+      vec!["you@example.com".parse().unwrap()],
+    )
+    .unwrap();
+    let err = send(&account, &envelope, b"Subject: hi\r\n\r\nbody\r\n")
+      .await
+      .unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "failed to find sending identity for `me@example.org`"
+    );
+  }
+}
@@ -16,52 +16,165 @@
 //! at random. If sending fails, another one if picked and the operation
 //! retried, until one succeeded or all failed sending.
 //!
+//! Besides SMTP accounts, local mailboxes (in Maildir or mbox format)
+//! can be used as delivery targets, e.g., on machines without network
+//! access. Alternatively, emails can be delivered directly to the mail
+//! exchangers of the recipients' domains, without any relay.
+//!
 //! If the `pgp` feature is enabled, emails can be PGP encrypted to the
 //! given set of recipients.
 //!
+//! The `jmap` feature adds support for submitting emails via providers
+//! offering JMAP.
+//!
+//! With the `submit` feature, emails can be submitted to a local daemon
+//! over a Unix domain socket, so that programs do not need access to
+//! any account credentials themselves.
+//!
+//! The `testing` feature provides utilities, such as a fake SMTP server,
+//! for testing code sending emails using this crate.
+//!
+//! Emails that could not be sent with any of the accounts can be
+//! stored in last-resort fallback sinks, such as a local file or the
+//! system log, so that they do not get lost.
+//!
 //! With the `config` feature enable, the library honors a global
 //! system-wide configuration. This configuration can capture anything
 //! from SMTP account to default recipients and means that clients of
 //! this crate don't *have to* specify anything but message contents.
 
+mod address_book;
 mod config;
+mod dns;
+mod error;
+mod fallback;
+#[cfg(feature = "jmap")]
+mod jmap;
+mod lmtp;
+mod local;
+mod mx;
 #[cfg(feature = "pgp")]
 mod pgp;
+#[cfg(feature = "queue")]
+mod queue;
 mod rand;
+mod raw;
+#[cfg(feature = "submit")]
+mod submit;
+#[cfg(feature = "testing")]
+#[cfg_attr(docsrs, doc(cfg(feature = "testing")))]
+pub mod testing;
+mod util;
 
-#[cfg(feature = "pgp")]
 use std::borrow::Cow;
 use std::marker::PhantomData;
 use std::path::Path;
 use std::str;
+#[cfg(feature = "queue")]
+use std::time::Duration;
 
+use anyhow::ensure;
 use anyhow::Context as _;
 use anyhow::Error;
 use anyhow::Result;
 
+use lettre::address::Envelope;
 use lettre::message::header::ContentDisposition;
 use lettre::message::header::ContentType;
+use lettre::message::Mailbox;
 use lettre::message::MaybeString;
 use lettre::message::MultiPart;
 use lettre::message::SinglePart;
 use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::client::Certificate;
+use lettre::transport::smtp::client::Tls;
+use lettre::transport::smtp::client::TlsParameters;
+use lettre::Address;
 use lettre::AsyncSmtpTransport;
 use lettre::AsyncTransport;
 use lettre::Message;
 use lettre::Tokio1Executor;
 
+#[cfg(any(feature = "queue", feature = "submit"))]
+use serde::Deserialize;
+#[cfg(any(feature = "queue", feature = "submit"))]
+use serde::Serialize;
+
+use tokio::fs::read;
+
+pub use crate::address_book::AddressBook;
+#[cfg(feature = "config")]
+#[cfg_attr(docsrs, doc(cfg(feature = "config")))]
+pub use crate::config::layered_config;
+#[cfg(feature = "config")]
+#[cfg_attr(docsrs, doc(cfg(feature = "config")))]
+pub use crate::config::migrate_config;
+#[cfg(feature = "config")]
+#[cfg_attr(docsrs, doc(cfg(feature = "config")))]
+pub use crate::config::parse_config;
+#[cfg(feature = "config")]
+#[cfg_attr(docsrs, doc(cfg(feature = "config")))]
+pub use crate::config::serialize_config;
 #[cfg(feature = "config")]
 #[cfg_attr(docsrs, doc(cfg(feature = "config")))]
 pub use crate::config::system_config;
 #[cfg(feature = "config")]
 #[cfg_attr(docsrs, doc(cfg(feature = "config")))]
 pub use crate::config::system_config_path;
+#[cfg(feature = "config")]
+#[cfg_attr(docsrs, doc(cfg(feature = "config")))]
+pub use crate::config::user_config_path;
 pub use crate::config::Account;
 #[cfg(feature = "config")]
 #[cfg_attr(docsrs, doc(cfg(feature = "config")))]
+pub use crate::config::AccountReport;
+#[cfg(feature = "config")]
+#[cfg_attr(docsrs, doc(cfg(feature = "config")))]
 pub use crate::config::Config;
+pub use crate::config::ErrorNotification;
+pub use crate::config::FallbackSink;
+#[cfg(feature = "jmap")]
+#[cfg_attr(docsrs, doc(cfg(feature = "jmap")))]
+pub use crate::config::JmapAccount;
+pub use crate::config::LocalAccount;
+pub use crate::config::MailboxFormat;
+pub use crate::config::MxAccount;
+pub use crate::config::NotifyWhen;
+#[cfg(feature = "config")]
+#[cfg_attr(docsrs, doc(cfg(feature = "config")))]
+pub use crate::config::Profile;
+pub use crate::config::SmtpAccount;
 pub use crate::config::SmtpMode;
-
+#[cfg(feature = "config")]
+#[cfg_attr(docsrs, doc(cfg(feature = "config")))]
+pub use crate::config::ValidationReport;
+#[cfg(feature = "config")]
+#[cfg_attr(docsrs, doc(cfg(feature = "config")))]
+pub use crate::config::CONFIG_VERSION;
+pub use crate::error::Errors;
+pub use crate::error::PartialDelivery;
+
+#[cfg(feature = "queue")]
+#[cfg_attr(docsrs, doc(cfg(feature = "queue")))]
+pub use crate::queue::FlushReport;
+#[cfg(feature = "queue")]
+#[cfg_attr(docsrs, doc(cfg(feature = "queue")))]
+pub use crate::queue::Queue;
+#[cfg(feature = "queue")]
+#[cfg_attr(docsrs, doc(cfg(feature = "queue")))]
+pub use crate::queue::QueuedEmail;
+
+#[cfg(feature = "submit")]
+#[cfg_attr(docsrs, doc(cfg(feature = "submit")))]
+pub use crate::submit::submit;
+#[cfg(feature = "submit")]
+#[cfg_attr(docsrs, doc(cfg(feature = "submit")))]
+pub use crate::submit::Submission;
+#[cfg(feature = "submit")]
+#[cfg_attr(docsrs, doc(cfg(feature = "submit")))]
+pub use crate::submit::SubmissionRequest;
+
+use crate::address_book::dedup_recipients;
 #[cfg(feature = "pgp")]
 use crate::pgp::encrypt;
 use crate::rand::RandExt as _;
@@ -95,6 +208,22 @@ mod log {
 }
 
 
+/// A file attached to an email.
+#[derive(Clone, Debug)]
+#[cfg_attr(any(feature = "queue", feature = "submit"), derive(Deserialize, Serialize))]
+pub struct Attachment<'input> {
+  /// The file name of the attachment.
+  pub name: Cow<'input, str>,
+  /// The content type of the attachment; defaults to
+  /// `application/octet-stream` if not provided.
+  #[cfg_attr(any(feature = "queue", feature = "submit"), serde(default))]
+  pub content_type: Option<Cow<'input, str>>,
+  /// The contents of the attachment.
+  #[cfg_attr(any(feature = "queue", feature = "submit"), serde(with = "crate::util::base64"))]
+  pub data: Cow<'input, [u8]>,
+}
+
+
 /// A type capturing options for capturing a screenshot.
 #[derive(Clone, Debug, Default)]
 pub struct EmailOpts<'input> {
@@ -108,6 +237,39 @@ pub struct EmailOpts<'input> {
   #[cfg(feature = "pgp")]
   #[cfg_attr(docsrs, doc(cfg(feature = "pgp")))]
   pub pgp_keybox: Option<Cow<'input, Path>>,
+  /// The files to attach to the email.
+  pub attachments: Vec<Attachment<'input>>,
+  /// Configuration of the notification sent when an attempt at sending
+  /// the email failed.
+  pub error_notification: ErrorNotification<'input>,
+  /// The sinks to store the email in if it could not be sent with any
+  /// of the accounts.
+  ///
+  /// Sinks are tried in order until the email was stored successfully
+  /// in one of them. Note that sending is still reported as failed in
+  /// this case.
+  pub fallback: Vec<FallbackSink<'input>>,
+  /// The directory of a [`Queue`] to add the email to if it could not
+  /// be sent with any of the accounts.
+  ///
+  /// Successfully queuing the email counts as success and takes
+  /// precedence over [`fallback`][Self::fallback] sinks, which are
+  /// only used if queuing failed. Complete messages, as sent by
+  /// [`send_raw_email`], are never queued.
+  #[cfg(feature = "queue")]
+  #[cfg_attr(docsrs, doc(cfg(feature = "queue")))]
+  pub queue: Option<Cow<'input, Path>>,
+  /// The time after which an email added to the
+  /// [`queue`][Self::queue] expires and is dropped instead of being
+  /// sent.
+  #[cfg(feature = "queue")]
+  #[cfg_attr(docsrs, doc(cfg(feature = "queue")))]
+  pub queue_expiry: Option<Duration>,
+  /// A prefix to add to the subject of the email, separated by a
+  /// space.
+  ///
+  /// The prefix is not applied to complete messages sent as-is.
+  pub subject_prefix: Option<Cow<'input, str>>,
   /// The type is non-exhaustive and open to extension.
   #[doc(hidden)]
   pub _phantom: PhantomData<&'input ()>,
@@ -124,23 +286,72 @@ where
 }
 
 
-#[cfg_attr(feature = "tracing", log::instrument(skip_all, err, fields(subject = subject, from = %account.from)))]
-async fn try_send_email<R, S>(
-  account: &Account<'_>,
+/// Add the provided attachments to a multipart message.
+fn attach(mut parts: MultiPart, attachments: &[Attachment<'_>]) -> Result<MultiPart> {
+  for attachment in attachments {
+    let content_type = attachment
+      .content_type
+      .as_deref()
+      .unwrap_or("application/octet-stream");
+    let content_type = ContentType::parse(content_type).with_context(|| {
+      format!(
+        "failed to parse content type `{content_type}` of attachment `{}`",
+        attachment.name
+      )
+    })?;
+    let part = lettre::message::Attachment::new(attachment.name.to_string())
+      .body(attachment.data.to_vec(), content_type);
+    parts = parts.singlepart(part);
+  }
+  Ok(parts)
+}
+
+
+/// Create the PGP/MIME (RFC 3156) parts wrapping an encrypted message.
+fn encrypted_parts(message: &[u8]) -> Result<MultiPart> {
+  // We always ASCII armor the message, so we do not expect it to ever
+  // be *not* a valid UTF-8 string.
+  let message =
+    str::from_utf8(message).context("PGP encrypted message is not a valid UTF-8 string")?;
+
+  let parts = MultiPart::encrypted("application/pgp-encrypted".to_owned())
+    .singlepart(
+      SinglePart::builder()
+        .header(
+          ContentType::parse("application/pgp-encrypted")
+            .context("failed to parse 'application/pgp-encrypted' content type header")?,
+        )
+        .body(String::from("Version: 1")),
+    )
+    .singlepart(
+      SinglePart::builder()
+        .header(
+          ContentType::parse(r#"application/octet-stream; name="encrypted.asc""#)
+            .context("failed to parse 'application/octet-stream' content type header")?,
+        )
+        .header(ContentDisposition::inline_with_name("encrypted.asc"))
+        .body(message.to_string()),
+    );
+  Ok(parts)
+}
+
+
+/// Build the email to send.
+fn build_email<R, S>(
+  from: &str,
   subject: &str,
   message: &[u8],
   content_type: Option<&str>,
   recipients: R,
   opts: &EmailOpts<'_>,
-) -> Result<()>
+) -> Result<Message>
 where
   R: Iterator<Item = S> + Clone,
   S: AsRef<str>,
 {
-  let from = account
-    .from
+  let from = from
     .parse()
-    .with_context(|| format!("failed to parse 'From' specification: `{}`", account.from))?;
+    .with_context(|| format!("failed to parse 'From' specification: `{from}`"))?;
   let content_type = content_type
     .map(|content_type| {
       ContentType::parse(content_type)
@@ -153,6 +364,14 @@ where
   let EmailOpts {
     #[cfg(feature = "pgp")]
     pgp_keybox,
+    attachments,
+    error_notification: _,
+    fallback: _,
+    #[cfg(feature = "queue")]
+    queue: _,
+    #[cfg(feature = "queue")]
+    queue_expiry: _,
+    subject_prefix: _,
     _phantom: PhantomData,
   } = opts;
 
@@ -168,58 +387,19 @@ where
     email = email.to(to);
   }
 
-  let creds = Credentials::new(account.user.to_string(), account.password.to_string());
-
-  let mailer = match account.smtp_mode {
-    SmtpMode::Unencrypted => {
-      AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(account.smtp_host.to_string())
-        .credentials(creds)
-        .build()
-    },
-    SmtpMode::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&account.smtp_host)
-      .context("failed to create TLS SMTP mailer")?
-      .credentials(creds)
-      .build(),
-    SmtpMode::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&account.smtp_host)
-      .context("failed to create STARTTLS SMTP mailer")?
-      .credentials(creds)
-      .build(),
-  };
-
   let email = if let Some(keybox) = pgp_keybox {
     let inner = MultiPart::mixed().singlepart(
       SinglePart::builder()
         .header(content_type)
         .body(message.to_vec()),
     );
+    let inner = attach(inner, attachments)?;
 
     // TODO: Ideally we'd also sign the message, but that's a different
     //       pandora's box and not as important at this point.
     let message =
       encrypt(&inner.formatted(), keybox, recipients).context("failed to encrypt message")?;
-    // We always ASCII armor the message, so we do not expect it to ever
-    // be *not* a valid UTF-8 string.
-    let message =
-      str::from_utf8(&message).context("PGP encrypted message is not a valid UTF-8 string")?;
-
-    let parts = MultiPart::encrypted("application/pgp-encrypted".to_owned())
-      .singlepart(
-        SinglePart::builder()
-          .header(
-            ContentType::parse("application/pgp-encrypted")
-              .context("failed to parse 'application/pgp-encrypted' content type header")?,
-          )
-          .body(String::from("Version: 1")),
-      )
-      .singlepart(
-        SinglePart::builder()
-          .header(
-            ContentType::parse(r#"application/octet-stream; name="encrypted.asc""#)
-              .context("failed to parse 'application/octet-stream' content type header")?,
-          )
-          .header(ContentDisposition::inline_with_name("encrypted.asc"))
-          .body(message.to_string()),
-      );
+    let parts = encrypted_parts(&message)?;
 
     email
       .multipart(parts)
@@ -235,37 +415,325 @@ where
       MaybeString::Binary(message.to_vec())
     };
 
-    email
-      .header(content_type)
-      .body(body)
-      .context("failed to create email message")?
+    if attachments.is_empty() {
+      email
+        .header(content_type)
+        .body(body)
+        .context("failed to create email message")?
+    } else {
+      let parts =
+        MultiPart::mixed().singlepart(SinglePart::builder().header(content_type).body(body));
+      let parts = attach(parts, attachments)?;
+      email
+        .multipart(parts)
+        .context("failed to create email message")?
+    }
+  };
+
+  Ok(email)
+}
+
+
+/// Add the subject prefix configured in `opts`, if any, to `subject`.
+fn prefix_subject<'sub>(subject: &'sub str, opts: &EmailOpts<'_>) -> Cow<'sub, str> {
+  match &opts.subject_prefix {
+    Some(prefix) => Cow::Owned(format!("{prefix} {subject}")),
+    None => Cow::Borrowed(subject),
+  }
+}
+
+
+/// The content of an email to send.
+#[derive(Clone, Copy, Debug)]
+enum Content<'msg> {
+  /// An email to compose from its constituents.
+  Composed {
+    subject: &'msg str,
+    message: &'msg [u8],
+    content_type: Option<&'msg str>,
+  },
+  /// A complete RFC 5322 message to send as-is.
+  Raw(&'msg [u8]),
+}
+
+impl Content<'_> {
+  /// Retrieve the subject of the email.
+  fn subject(&self) -> Cow<'_, str> {
+    match self {
+      Self::Composed { subject, .. } => Cow::Borrowed(subject),
+      Self::Raw(message) => Cow::Owned(raw::subject(message).unwrap_or_default()),
+    }
+  }
+}
+
+
+/// Prepare an email for sending via an account with the given "From"
+/// identifier, returning its envelope and formatted representation.
+fn prepare_email<R, S>(
+  from: &str,
+  content: Content<'_>,
+  recipients: R,
+  opts: &EmailOpts<'_>,
+) -> Result<(Envelope, Vec<u8>)>
+where
+  R: Iterator<Item = S> + Clone,
+  S: AsRef<str>,
+{
+  match content {
+    Content::Composed {
+      subject,
+      message,
+      content_type,
+    } => {
+      let email = build_email(from, subject, message, content_type, recipients, opts)?;
+      Ok((email.envelope().clone(), email.formatted()))
+    },
+    Content::Raw(message) => {
+      #[cfg(feature = "pgp")]
+      let pgp_keybox = opts.pgp_keybox.as_deref();
+      #[cfg(not(feature = "pgp"))]
+      let pgp_keybox = None;
+
+      raw::prepare(from, message, recipients, pgp_keybox, |inner, keybox, to| {
+        let encrypted = encrypt(inner, keybox, to)?;
+        Ok(encrypted_parts(&encrypted)?.formatted())
+      })
+    },
+  }
+}
+
+
+/// Create the transport for sending emails via the SMTP server
+/// described by `account`.
+///
+/// # Panics
+/// This function panics if `account` is configured for LMTP.
+async fn smtp_transport(account: &SmtpAccount<'_>) -> Result<AsyncSmtpTransport<Tokio1Executor>> {
+  let creds = Credentials::new(account.user.to_string(), account.password.to_string());
+
+  let mut builder = match account.smtp_mode {
+    SmtpMode::Lmtp => unreachable!(),
+    SmtpMode::Unencrypted => {
+      AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(account.smtp_host.to_string())
+    },
+    SmtpMode::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&account.smtp_host)
+      .context("failed to create TLS SMTP mailer")?,
+    SmtpMode::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&account.smtp_host)
+      .context("failed to create STARTTLS SMTP mailer")?,
   };
 
-  log::trace!(email = %String::from_utf8_lossy(&email.formatted()));
+  if let Some(port) = account.smtp_port {
+    builder = builder.port(port);
+  }
+
+  if let Some(ca_file) = &account.smtp_ca_file {
+    let pem = read(ca_file)
+      .await
+      .with_context(|| format!("failed to read CA certificate `{}`", ca_file.display()))?;
+    let cert = Certificate::from_pem(&pem)
+      .with_context(|| format!("failed to parse CA certificate `{}`", ca_file.display()))?;
+    let params = TlsParameters::builder(account.smtp_host.to_string())
+      .add_root_certificate(cert)
+      .build()
+      .context("failed to create TLS parameters")?;
+
+    builder = match account.smtp_mode {
+      SmtpMode::Tls => builder.tls(Tls::Wrapper(params)),
+      SmtpMode::StartTls => builder.tls(Tls::Required(params)),
+      SmtpMode::Unencrypted | SmtpMode::Lmtp => builder,
+    };
+  }
+
+  Ok(builder.credentials(creds).build())
+}
 
+
+/// Send an email via the SMTP server described by `account`.
+async fn send_smtp(account: &SmtpAccount<'_>, envelope: &Envelope, email: &[u8]) -> Result<()> {
+  if let SmtpMode::Lmtp = account.smtp_mode {
+    let () = lmtp::send(&account.smtp_host, account.smtp_port, envelope, email)
+      .await
+      .with_context(|| format!("failed to send email via LMTP to {}", account.smtp_host))?;
+    return Ok(())
+  }
+
+  let mailer = smtp_transport(account).await?;
   let _mailer = mailer
-    .send(email)
+    .send_raw(envelope, email)
     .await
     .with_context(|| format!("failed to send email via {}", account.smtp_host))?;
+  Ok(())
+}
+
+
+/// Check that `account` is usable for sending emails to `recipients`,
+/// without sending anything.
+///
+/// Depending on the type of account, this connects (and authenticates)
+/// to the server, checks that the local mailbox is writable, or looks
+/// up the mail exchangers of the recipients' domains.
+pub async fn check_account<R, S>(account: &Account<'_>, recipients: R) -> Result<()>
+where
+  R: IntoIterator<Item = S>,
+  S: AsRef<str>,
+{
+  let recipients = recipients
+    .into_iter()
+    .map(|recipient| {
+      let recipient = recipient.as_ref();
+      recipient
+        .parse::<Mailbox>()
+        .map(|mailbox| mailbox.email)
+        .with_context(|| format!("failed to parse 'To' specification: `{recipient}`"))
+    })
+    .collect::<Result<Vec<_>>>()?;
+
+  match account {
+    Account::Smtp(account) if matches!(account.smtp_mode, SmtpMode::Lmtp) => {
+      lmtp::check(&account.smtp_host, account.smtp_port).await
+    },
+    Account::Smtp(account) => {
+      let mailer = smtp_transport(account).await?;
+      let connected = mailer
+        .test_connection()
+        .await
+        .with_context(|| format!("failed to connect to {}", account.smtp_host))?;
+      ensure!(connected, "connection to {} is unusable", account.smtp_host);
+      Ok(())
+    },
+    Account::Local(account) => local::check(account),
+    #[cfg(feature = "jmap")]
+    Account::Jmap(account) => jmap::check(account)
+      .await
+      .with_context(|| format!("failed to connect to JMAP server {}", account.jmap_url)),
+    Account::Mx(account) => mx::check(account, &recipients).await,
+  }
+}
+
+
+/// Try sending an email via `account`.
+///
+/// If `pending` is provided, the email is only delivered to the
+/// recipients contained in it, instead of to all `recipients`.
+#[cfg_attr(feature = "tracing", log::instrument(skip_all, err, fields(subject = %content.subject(), from = %account.from())))]
+async fn try_send_email<R, S>(
+  account: &Account<'_>,
+  content: Content<'_>,
+  recipients: R,
+  pending: Option<&[Address]>,
+  opts: &EmailOpts<'_>,
+) -> Result<()>
+where
+  R: Iterator<Item = S> + Clone,
+  S: AsRef<str>,
+{
+  let (mut envelope, email) = prepare_email(account.from(), content, recipients, opts)?;
+  if let Some(pending) = pending {
+    envelope = Envelope::new(envelope.from().cloned(), pending.to_vec())
+      .context("failed to create envelope for pending recipients")?;
+  }
+
+  log::trace!(email = %String::from_utf8_lossy(&email));
+
+  match account {
+    Account::Smtp(account) => send_smtp(account, &envelope, &email).await?,
+    Account::Local(account) => local::deliver(account, &envelope, &email)
+      .await
+      .with_context(|| format!("failed to deliver email to `{}`", account.mailbox.display()))?,
+    #[cfg(feature = "jmap")]
+    Account::Jmap(account) => jmap::send(account, &envelope, &email)
+      .await
+      .with_context(|| format!("failed to send email via JMAP server {}", account.jmap_url))?,
+    Account::Mx(account) => mx::send(account, &envelope, &email)
+      .await
+      .context("failed to deliver email to mail exchangers")?,
+  }
 
   log::debug!("email sent successfully");
   Ok(())
 }
 
 
-/// Send an email using the provided inputs.
-///
-/// This function attempts to send an email using one of the accounts
-/// provided. Accounts are chosen at random and on send failure another
-/// one is tried until one succeeded or all failed sending. In addition,
-/// in case of a send failure attempts are made to inform recipients
-/// about that via an additional email outlining the error encountered
-/// with a different account.
-pub async fn send_email<'acc, A, R, I, S>(
-  accounts: A,
+/// Send a notification about `err` having occurred while sending an
+/// email with the given subject.
+async fn notify<R, S>(
+  account: &Account<'_>,
   subject: &str,
-  message: &[u8],
-  content_type: Option<&str>,
+  err: &Error,
+  recipients: R,
+  opts: &EmailOpts<'_>,
+) -> Result<()>
+where
+  R: Iterator<Item = S> + Clone,
+  S: AsRef<str>,
+{
+  let notification = &opts.error_notification;
+  let notification_subject = notification.render_subject(subject);
+  let body = notification.render_body(subject, &format!("{err:?}"));
+  // Notifications are not the original email, so they should not carry
+  // its attachments and are only encrypted if so desired.
+  let opts = EmailOpts {
+    #[cfg(feature = "pgp")]
+    pgp_keybox: opts
+      .pgp_keybox
+      .clone()
+      .filter(|_keybox| notification.encrypt),
+    ..Default::default()
+  };
+
+  let content = Content::Composed {
+    subject: &notification_subject,
+    message: body.as_bytes(),
+    content_type: None,
+  };
+
+  if notification.recipients.is_empty() {
+    try_send_email(account, content, recipients, None, &opts).await
+  } else {
+    try_send_email(account, content, notification.recipients.iter(), None, &opts).await
+  }
+}
+
+
+/// Store an email that could not be sent in the first of the fallback
+/// sinks that accepts it.
+async fn store_fallback<R, S>(
+  accounts: &[&Account<'_>],
+  content: Content<'_>,
+  recipients: R,
+  opts: &EmailOpts<'_>,
+) -> Result<()>
+where
+  R: Iterator<Item = S> + Clone,
+  S: AsRef<str>,
+{
+  // SANITY: We only ever end up with a failure if at least one account
+  //         was tried.
+  let account = accounts.first().unwrap();
+  let (envelope, formatted) = prepare_email(account.from(), content, recipients, opts)
+    .context("failed to build email for fallback sinks")?;
+
+  let mut overall_result = Result::<_, Error>::Ok(());
+  for sink in opts.fallback.iter() {
+    match fallback::store(sink, &envelope, &formatted).await {
+      Ok(()) => {
+        log::warn!("stored unsent email in fallback sink {sink:?}");
+        return Ok(())
+      },
+      Err(err) => {
+        let err = err.context(format!("failed to store email in fallback sink {sink:?}"));
+        overall_result = Err(Errors::combine(overall_result, err));
+      },
+    }
+  }
+  overall_result
+}
+
+
+/// Send an email, trying the provided accounts in random order.
+async fn send<'acc, A, R, I, S>(
+  accounts: A,
+  content: Content<'_>,
   recipients: R,
   opts: &EmailOpts<'_>,
 ) -> Result<()>
@@ -279,44 +747,255 @@ where
   let rng = Rng::new();
   let () = rng.shuffle(&mut accounts);
 
-  let recipients = recipients.into_iter();
-
+  let subject = content.subject();
+  let recipients = recipients
+    .into_iter()
+    .map(|recipient| recipient.as_ref().to_string())
+    .collect::<Vec<_>>();
+  // Complete messages may leave it to us to derive the recipients. We
+  // need them not only for sending the message itself, but also for
+  // error notifications and fallback sinks.
+  let recipients = match content {
+    Content::Raw(message) if recipients.is_empty() => raw::recipients(message)?
+      .iter()
+      .map(Address::to_string)
+      .collect(),
+    Content::Raw(..) | Content::Composed { .. } => recipients,
+  };
+  let recipients = dedup_recipients(recipients);
+  let recipients = recipients.iter();
+  let notification = &opts.error_notification;
+  let notify_when = |when: &[NotifyWhen]| notification.enabled && when.contains(&notification.when);
+
+  // The recipients the email still has to be delivered to, if it got
+  // delivered to some of them already.
+  let mut pending = None::<Vec<Address>>;
   let mut overall_result = Result::<_, Error>::Ok(());
-  for account in accounts {
+  for account in accounts.iter() {
     if let Err(err) = &overall_result {
-      // There isn't really anything that we could do about potential
-      // errors here, so just ignore them.
-      let _result = try_send_email(
-        account,
-        "email error",
-        format!("{err:?}").as_bytes(),
-        None,
-        recipients.clone(),
-        opts,
-      )
-      .await;
+      if notify_when(&[NotifyWhen::Immediately]) {
+        // There isn't really anything that we could do about potential
+        // errors here, so just ignore them.
+        let _result = notify(account, &subject, err, recipients.clone(), opts).await;
+      }
     }
 
     let result = try_send_email(
       account,
-      subject,
-      message,
-      content_type,
+      content,
       recipients.clone(),
+      pending.as_deref(),
       opts,
     )
     .await;
     match result {
-      Ok(()) => return Ok(()),
+      Ok(()) => {
+        if let Err(err) = &overall_result {
+          if notify_when(&[NotifyWhen::OnSuccess, NotifyWhen::Finally]) {
+            let _result = notify(account, &subject, err, recipients.clone(), opts).await;
+          }
+        }
+        return Ok(())
+      },
       Err(err) => {
-        if let Err(overall_err) = overall_result {
-          overall_result = Err(overall_err.context(err));
-        } else {
-          overall_result = Err(err);
+        if let Some(partial) = err.downcast_ref::<PartialDelivery>() {
+          pending = Some(partial.failed().to_vec());
         }
+
+        overall_result = Err(Errors::combine(overall_result, err));
       },
     }
   }
 
-  overall_result
+  if let Err(err) = &overall_result {
+    if notify_when(&[NotifyWhen::OnFailure, NotifyWhen::Finally]) {
+      // All accounts failed sending the email itself, but the
+      // notification may still get through with one of them, e.g.,
+      // because it is sent to different recipients.
+      for account in accounts.iter() {
+        if notify(account, &subject, err, recipients.clone(), opts)
+          .await
+          .is_ok()
+        {
+          break
+        }
+      }
+    }
+  }
+
+  // Complete messages cannot be queued, as queued emails are composed
+  // when they are sent.
+  #[cfg(feature = "queue")]
+  if let (Some(_queue), Content::Raw(..), Err(_err)) = (&opts.queue, content, &overall_result) {
+    log::warn!("not queuing unsent message, as only composed emails can be queued");
+  }
+  #[cfg(feature = "queue")]
+  if let (
+    Some(queue),
+    Content::Composed {
+      subject,
+      message,
+      content_type,
+    },
+  ) = (&opts.queue, content)
+  {
+    if let Err(err) = overall_result {
+      let queue = Queue::new(queue.as_ref()).with_expiry(opts.queue_expiry);
+      // Recipients the email got delivered to already must not receive
+      // it again once the queue is flushed.
+      let recipients = match &pending {
+        Some(pending) => pending.iter().map(Address::to_string).collect(),
+        None => recipients.clone().cloned().collect::<Vec<_>>(),
+      };
+      let result = queue.insert(
+        subject,
+        message,
+        content_type,
+        recipients.iter(),
+        &opts.attachments,
+        Some(&err),
+      )
+      .await;
+      match result {
+        Ok(_id) => {
+          log::warn!("queued unsent email as {_id} in `{}`", queue.path().display());
+          return Ok(())
+        },
+        Err(queue_err) => overall_result = Err(Errors::combine(Err(err), queue_err)),
+      }
+    }
+  }
+
+  if let Err(err) = overall_result {
+    if opts.fallback.is_empty() {
+      return Err(err)
+    }
+
+    match store_fallback(&accounts, content, recipients, opts).await {
+      Ok(()) => Err(err),
+      Err(fallback_err) => Err(Errors::combine(Err(err), fallback_err)),
+    }
+  } else {
+    overall_result
+  }
+}
+
+
+/// Send an email using the provided inputs.
+///
+/// This function attempts to send an email using one of the accounts
+/// provided. Accounts are chosen at random and on send failure another
+/// one is tried until one succeeded or all failed sending. In addition,
+/// in case of a send failure attempts are made to inform recipients
+/// about that via an additional email outlining the error encountered
+/// with a different account. This notification can be configured via
+/// [`EmailOpts::error_notification`]. If an account delivered the email
+/// to some of the recipients only (see [`PartialDelivery`]), only the
+/// remaining ones are retried. If all accounts failed, the email
+/// is added to the queue configured via `EmailOpts::queue`, if any, or
+/// stored in one of the [`EmailOpts::fallback`] sinks.
+///
+/// If multiple accounts failed, the errors of all of them are reported
+/// by means of [`Errors`].
+pub async fn send_email<'acc, A, R, I, S>(
+  accounts: A,
+  subject: &str,
+  message: &[u8],
+  content_type: Option<&str>,
+  recipients: R,
+  opts: &EmailOpts<'_>,
+) -> Result<()>
+where
+  A: IntoIterator<Item = &'acc Account<'acc>>,
+  R: IntoIterator<IntoIter = I>,
+  I: Iterator<Item = S> + Clone,
+  S: AsRef<str>,
+{
+  let subject = prefix_subject(subject, opts);
+  send(
+    accounts,
+    Content::Composed {
+      subject: &subject,
+      message,
+      content_type,
+    },
+    recipients,
+    opts,
+  )
+  .await
+}
+
+
+/// Build the email that [`send_email`] would send via `account`,
+/// without sending it.
+///
+/// The email is returned in its fully formatted form, as it would be
+/// transmitted, including PGP encryption and attachments as configured
+/// in `opts`.
+pub fn preview_email<R, I, S>(
+  account: &Account<'_>,
+  subject: &str,
+  message: &[u8],
+  content_type: Option<&str>,
+  recipients: R,
+  opts: &EmailOpts<'_>,
+) -> Result<Vec<u8>>
+where
+  R: IntoIterator<IntoIter = I>,
+  I: Iterator<Item = S> + Clone,
+  S: AsRef<str>,
+{
+  let subject = prefix_subject(subject, opts);
+  let content = Content::Composed {
+    subject: &subject,
+    message,
+    content_type,
+  };
+  let (_envelope, email) = prepare_email(account.from(), content, recipients.into_iter(), opts)?;
+  Ok(email)
+}
+
+
+/// Send a complete RFC 5322 message as-is.
+///
+/// The message is sent using one of the provided accounts, with the
+/// same failover, error notification, and fallback logic as employed by
+/// [`send_email`]. Unlike emails sent via the latter, messages are
+/// never queued. Recipients are derived from the message's `To`, `Cc`,
+/// and `Bcc` header fields if none are provided. `Bcc` fields are
+/// removed before sending. If PGP encryption is configured, the content
+/// of the message is encrypted, retaining all other header fields.
+/// Attachments in `opts` are ignored.
+pub async fn send_raw_email<'acc, A, R, I, S>(
+  accounts: A,
+  message: &[u8],
+  recipients: R,
+  opts: &EmailOpts<'_>,
+) -> Result<()>
+where
+  A: IntoIterator<Item = &'acc Account<'acc>>,
+  R: IntoIterator<IntoIter = I>,
+  I: Iterator<Item = S> + Clone,
+  S: AsRef<str>,
+{
+  send(accounts, Content::Raw(message), recipients, opts).await
+}
+
+
+/// Build the message that [`send_raw_email`] would send via `account`,
+/// without sending it.
+pub fn preview_raw_email<R, I, S>(
+  account: &Account<'_>,
+  message: &[u8],
+  recipients: R,
+  opts: &EmailOpts<'_>,
+) -> Result<Vec<u8>>
+where
+  R: IntoIterator<IntoIter = I>,
+  I: Iterator<Item = S> + Clone,
+  S: AsRef<str>,
+{
+  let (_envelope, email) =
+    prepare_email(account.from(), Content::Raw(message), recipients.into_iter(), opts)?;
+  Ok(email)
 }
@@ -0,0 +1,406 @@
+// Copyright (C) 2024 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context as _;
+use anyhow::Result;
+
+use lettre::address::Envelope;
+
+use tokio::io::AsyncBufRead;
+use tokio::io::AsyncBufReadExt as _;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt as _;
+use tokio::io::BufStream;
+use tokio::net::TcpStream;
+use tokio::net::UnixStream;
+use tokio::time::timeout;
+
+use crate::log;
+use crate::util::hostname;
+use crate::PartialDelivery;
+
+
+/// The default port LMTP servers listen on.
+const LMTP_PORT: u16 = 24;
+/// The timeout applied to an entire LMTP session.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+
+/// A reply as sent by an LMTP server.
+#[derive(Debug)]
+struct Reply {
+  /// The reply code.
+  code: u16,
+  /// The (potentially multi-line) text accompanying the code.
+  text: String,
+}
+
+impl Reply {
+  fn is_positive(&self) -> bool {
+    (200..400).contains(&self.code)
+  }
+}
+
+
+/// A connection to an LMTP server, via TCP or a Unix domain socket.
+trait Connection: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T> Connection for T where T: AsyncRead + AsyncWrite + Send + Unpin {}
+
+
+async fn read_reply<S>(stream: &mut S) -> Result<Reply>
+where
+  S: AsyncBufRead + Unpin,
+{
+  let mut text = String::new();
+  loop {
+    let mut line = String::new();
+    let count = stream
+      .read_line(&mut line)
+      .await
+      .context("failed to read reply from LMTP server")?;
+    if count == 0 {
+      bail!("LMTP server closed connection unexpectedly");
+    }
+
+    let line = line.trim_end();
+    let code = line
+      .get(..3)
+      .and_then(|code| code.parse::<u16>().ok())
+      .with_context(|| format!("received malformed LMTP reply: `{line}`"))?;
+    if !text.is_empty() {
+      let () = text.push('\n');
+    }
+    let () = text.push_str(line.get(4..).unwrap_or_default());
+
+    if line.as_bytes().get(3) != Some(&b'-') {
+      break Ok(Reply { code, text })
+    }
+  }
+}
+
+async fn command<S>(stream: &mut S, command: &str) -> Result<Reply>
+where
+  S: AsyncBufRead + AsyncWrite + Unpin,
+{
+  let () = stream
+    .write_all(format!("{command}\r\n").as_bytes())
+    .await
+    .context("failed to send command to LMTP server")?;
+  let () = stream
+    .flush()
+    .await
+    .context("failed to send command to LMTP server")?;
+  read_reply(stream).await
+}
+
+async fn expect<S>(stream: &mut S, cmd: &str) -> Result<Reply>
+where
+  S: AsyncBufRead + AsyncWrite + Unpin,
+{
+  let reply = command(stream, cmd).await?;
+  if !reply.is_positive() {
+    bail!(
+      "LMTP server rejected `{cmd}`: {} {}",
+      reply.code,
+      reply.text
+    );
+  }
+  Ok(reply)
+}
+
+
+/// Convert a message into its on-the-wire representation, i.e., with
+/// CRLF line endings, dot-stuffed, and terminated by `<CRLF>.<CRLF>`.
+fn encode_data(message: &[u8]) -> Vec<u8> {
+  let mut data = Vec::with_capacity(message.len() + 8);
+  let mut lines = message.split(|byte| *byte == b'\n').peekable();
+
+  while let Some(line) = lines.next() {
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    if lines.peek().is_none() && line.is_empty() {
+      break
+    }
+
+    if line.starts_with(b".") {
+      let () = data.push(b'.');
+    }
+    let () = data.extend_from_slice(line);
+    let () = data.extend_from_slice(b"\r\n");
+  }
+  let () = data.extend_from_slice(b".\r\n");
+  data
+}
+
+
+/// Wait for the server's greeting and introduce ourselves.
+async fn greet<S>(stream: &mut S) -> Result<()>
+where
+  S: AsyncBufRead + AsyncWrite + Unpin,
+{
+  let greeting = read_reply(stream).await?;
+  if greeting.code != 220 {
+    bail!(
+      "LMTP server sent unexpected greeting: {} {}",
+      greeting.code,
+      greeting.text
+    );
+  }
+
+  let _reply = expect(stream, &format!("LHLO {}", hostname())).await?;
+  Ok(())
+}
+
+
+/// Run an LMTP session over the provided stream, delivering `message`
+/// to all recipients in `envelope`.
+async fn deliver<S>(stream: &mut S, envelope: &Envelope, message: &[u8]) -> Result<()>
+where
+  S: AsyncBufRead + AsyncWrite + Unpin,
+{
+  let () = greet(stream).await?;
+  let from = envelope
+    .from()
+    .map(ToString::to_string)
+    .unwrap_or_default();
+  let _reply = expect(stream, &format!("MAIL FROM:<{from}>")).await?;
+
+  let mut accepted = Vec::new();
+  let mut delivered = Vec::new();
+  let mut failed = Vec::new();
+  let mut failures = Vec::new();
+  for recipient in envelope.to() {
+    let reply = command(stream, &format!("RCPT TO:<{recipient}>")).await?;
+    if reply.is_positive() {
+      let () = accepted.push(recipient);
+    } else {
+      let () = failed.push(recipient.clone());
+      let () = failures.push(format!("{recipient}: {} {}", reply.code, reply.text));
+    }
+  }
+
+  if !accepted.is_empty() {
+    let _reply = expect(stream, "DATA").await?;
+    let () = stream
+      .write_all(&encode_data(message))
+      .await
+      .context("failed to send message data to LMTP server")?;
+    let () = stream
+      .flush()
+      .await
+      .context("failed to send message data to LMTP server")?;
+
+    // In contrast to SMTP, an LMTP server reports one status for each
+    // accepted recipient after the data have been transferred.
+    for recipient in accepted {
+      let reply = read_reply(stream).await?;
+      if reply.is_positive() {
+        log::debug!(recipient = %recipient, "message delivered via LMTP");
+        let () = delivered.push(recipient.clone());
+      } else {
+        let () = failed.push(recipient.clone());
+        let () = failures.push(format!("{recipient}: {} {}", reply.code, reply.text));
+      }
+    }
+  }
+
+  // We are done at this point and do not care about the server's
+  // response to our request to close the session.
+  let _result = command(stream, "QUIT").await;
+
+  if !failures.is_empty() {
+    let err = anyhow!(
+      "LMTP server failed to deliver message to recipient(s):\n{}",
+      failures.join("\n")
+    );
+    // Recipients the message got delivered to already must not receive
+    // it again when retrying, so make sure to report them.
+    if delivered.is_empty() {
+      return Err(err)
+    } else {
+      return Err(PartialDelivery::new(delivered, failed, err).into())
+    }
+  }
+  Ok(())
+}
+
+
+/// Split an LMTP "host" specification into host and port.
+fn parse_host(host: &str) -> Result<(&str, u16)> {
+  match host.rsplit_once(':') {
+    Some((name, port)) if !name.contains(':') || name.ends_with(']') => {
+      let port = port
+        .parse()
+        .with_context(|| format!("failed to parse port in `{host}`"))?;
+      let name = name.trim_start_matches('[').trim_end_matches(']');
+      Ok((name, port))
+    },
+    _ => Ok((host, LMTP_PORT)),
+  }
+}
+
+
+/// Connect to the LMTP server at `host`.
+///
+/// `host` may either be a `host[:port]` specification or the absolute
+/// path to a Unix domain socket. If provided, `port` takes precedence
+/// over any port contained in `host`.
+async fn connect(host: &str, port: Option<u16>) -> Result<BufStream<Box<dyn Connection>>> {
+  let stream = if host.starts_with('/') {
+    let stream = UnixStream::connect(Path::new(host))
+      .await
+      .with_context(|| format!("failed to connect to LMTP socket `{host}`"))?;
+    Box::new(stream) as Box<dyn Connection>
+  } else {
+    let (name, default_port) = parse_host(host)?;
+    let port = port.unwrap_or(default_port);
+    let stream = TcpStream::connect((name, port))
+      .await
+      .with_context(|| format!("failed to connect to LMTP server `{host}`"))?;
+    Box::new(stream) as Box<dyn Connection>
+  };
+  Ok(BufStream::new(stream))
+}
+
+
+/// Deliver an already formatted email to the LMTP server at `host`.
+///
+/// See [`connect`] for the supported `host` and `port` values.
+pub(crate) async fn send(
+  host: &str,
+  port: Option<u16>,
+  envelope: &Envelope,
+  message: &[u8],
+) -> Result<()> {
+  let session = async {
+    let mut stream = connect(host, port).await?;
+    deliver(&mut stream, envelope, message).await
+  };
+
+  timeout(TIMEOUT, session)
+    .await
+    .with_context(|| format!("LMTP session with `{host}` timed out"))?
+}
+
+
+/// Check that the LMTP server at `host` is reachable and greets us
+/// properly, without delivering anything.
+pub(crate) async fn check(host: &str, port: Option<u16>) -> Result<()> {
+  let session = async {
+    let mut stream = connect(host, port).await?;
+    let () = greet(&mut stream)
+      .await
+      .with_context(|| format!("LMTP server `{host}` rejected session"))?;
+    let _result = command(&mut stream, "QUIT").await;
+    Ok(())
+  };
+
+  timeout(TIMEOUT, session)
+    .await
+    .with_context(|| format!("LMTP session with `{host}` timed out"))?
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use tokio::io::duplex;
+  use tokio::io::AsyncReadExt as _;
+  use tokio::io::BufReader;
+  use tokio::spawn;
+
+
+  /// Check that we can parse LMTP host specifications.
+  #[test]
+  fn host_parsing() {
+    assert_eq!(parse_host("localhost").unwrap(), ("localhost", 24));
+    assert_eq!(parse_host("localhost:2424").unwrap(), ("localhost", 2424));
+    assert_eq!(parse_host("[::1]:25").unwrap(), ("::1", 25));
+    assert_eq!(parse_host("::1").unwrap(), ("::1", 24));
+    assert!(parse_host("localhost:port").is_err());
+  }
+
+  /// Check that message data are properly dot-stuffed and terminated.
+  #[test]
+  fn data_encoding() {
+    assert_eq!(encode_data(b"a\n.b\r\n..c"), b"a\r\n..b\r\n...c\r\n.\r\n");
+    assert_eq!(encode_data(b"a\r\n"), b"a\r\n.\r\n");
+  }
+
+  /// Check that per-recipient statuses reported after `DATA` are
+  /// honored.
+  #[tokio::test]
+  async fn per_recipient_status() {
+    let (client, server) = duplex(4096);
+    let server = spawn(async move {
+      let mut server = BufReader::new(server);
+      let replies = [
+        "220 lmtp ready\r\n",
+        "250-lmtp\r\n250 PIPELINING\r\n",
+        "250 ok\r\n",
+        "250 ok\r\n",
+        "550 no such user\r\n",
+        "250 ok\r\n",
+        "354 go ahead\r\n",
+      ];
+      for reply in replies {
+        if !reply.starts_with("220") {
+          let mut line = String::new();
+          let _count = server.read_line(&mut line).await.unwrap();
+        }
+        let () = server.get_mut().write_all(reply.as_bytes()).await.unwrap();
+      }
+
+      let mut data = Vec::new();
+      while !data.ends_with(b"\r\n.\r\n") {
+        let mut byte = [0];
+        let _count = server.read_exact(&mut byte).await.unwrap();
+        let () = data.push(byte[0]);
+      }
+
+      let () = server
+        .get_mut()
+        .write_all(b"250 ok\r\n452 mailbox full\r\n221 bye\r\n")
+        .await
+        .unwrap();
+      data
+    });
+
+    let envelope = Envelope::new(
+      Some("sender@example.com".parse().unwrap()),
+      vec![
+        "a@example.com".parse().unwrap(),
+        "b@example.com".parse().unwrap(),
+        "c@example.com".parse().unwrap(),
+      ],
+    )
+    .unwrap();
+
+    let err = deliver(&mut BufStream::new(client), &envelope, b"body")
+      .await
+      .unwrap_err();
+    let partial = err.downcast_ref::<PartialDelivery>().unwrap();
+    assert_eq!(partial.delivered(), ["a@example.com".parse().unwrap()]);
+    assert_eq!(
+      partial.failed(),
+      [
+        "b@example.com".parse().unwrap(),
+        "c@example.com".parse().unwrap()
+      ]
+    );
+
+    let err = format!("{err:#}");
+    assert!(err.contains("b@example.com: 550 no such user"), "{err}");
+    assert!(err.contains("c@example.com: 452 mailbox full"), "{err}");
+
+    let data = server.await.unwrap();
+    assert_eq!(data, b"body\r\n.\r\n");
+  }
+}
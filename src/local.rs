@@ -0,0 +1,303 @@
+// Copyright (C) 2024 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::fs::create_dir_all;
+use std::fs::rename;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Write as _;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use anyhow::ensure;
+use anyhow::Context as _;
+use anyhow::Result;
+
+use lettre::address::Envelope;
+
+use tokio::task::spawn_blocking;
+
+use crate::util::hostname;
+use crate::LocalAccount;
+use crate::MailboxFormat;
+
+
+/// A per-process counter used for making Maildir file names unique.
+static DELIVERIES: AtomicU64 = AtomicU64::new(0);
+
+
+/// Format the given time stamp (seconds since the Unix epoch) in the
+/// format produced by C's `asctime`, e.g., `Thu Jan  1 00:00:00 1970`.
+fn asctime(secs: u64) -> String {
+  const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+  const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+  ];
+
+  let days = secs / 86400;
+  let rem = secs % 86400;
+  let (hour, min, sec) = (rem / 3600, rem % 3600 / 60, rem % 60);
+
+  // Conversion of days since the epoch into a civil date, based on
+  // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+  let z = days + 719468;
+  let era = z / 146097;
+  let doe = z - era * 146097;
+  let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = doy - (153 * mp + 2) / 5 + 1;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 };
+  let year = yoe + era * 400 + u64::from(month <= 2);
+
+  format!(
+    "{} {} {day:>2} {hour:02}:{min:02}:{sec:02} {year}",
+    DAYS[(days % 7) as usize],
+    MONTHS[(month - 1) as usize],
+  )
+}
+
+
+/// Convert a message into the representation used inside an mbox file,
+/// which is using bare linefeed line endings and has all lines matching
+/// `^>*From ` escaped with an additional `>` (the "mboxrd" flavor).
+fn mbox_escape(message: &[u8]) -> Vec<u8> {
+  let mut escaped = Vec::with_capacity(message.len() + 2);
+  let mut lines = message.split(|byte| *byte == b'\n').peekable();
+
+  while let Some(line) = lines.next() {
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let is_last = lines.peek().is_none();
+    if is_last && line.is_empty() {
+      break
+    }
+
+    let unquoted = line
+      .iter()
+      .position(|byte| *byte != b'>')
+      .map(|idx| &line[idx..])
+      .unwrap_or_default();
+    if unquoted.starts_with(b"From ") {
+      let () = escaped.push(b'>');
+    }
+    let () = escaped.extend_from_slice(line);
+    let () = escaped.push(b'\n');
+  }
+  escaped
+}
+
+
+fn deliver_maildir(maildir: &Path, message: &[u8]) -> Result<()> {
+  for dir in ["tmp", "new", "cur"] {
+    let dir = maildir.join(dir);
+    let () = create_dir_all(&dir)
+      .with_context(|| format!("failed to create Maildir directory `{}`", dir.display()))?;
+  }
+
+  // SANITY: `UNIX_EPOCH` is earlier than *any* other `SystemTime`.
+  let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+  let host = hostname().replace('/', r"\057").replace(':', r"\072");
+  let name = format!(
+    "{}.M{}P{}Q{}.{host}",
+    now.as_secs(),
+    now.subsec_micros(),
+    process::id(),
+    DELIVERIES.fetch_add(1, Ordering::Relaxed),
+  );
+
+  let tmp = maildir.join("tmp").join(&name);
+  let mut file = OpenOptions::new()
+    .write(true)
+    .create_new(true)
+    .open(&tmp)
+    .with_context(|| format!("failed to create `{}`", tmp.display()))?;
+  let () = file
+    .write_all(message)
+    .with_context(|| format!("failed to write message to `{}`", tmp.display()))?;
+  let () = file
+    .sync_all()
+    .with_context(|| format!("failed to sync `{}`", tmp.display()))?;
+
+  let new = maildir.join("new").join(&name);
+  let () = rename(&tmp, &new)
+    .with_context(|| format!("failed to move `{}` to `{}`", tmp.display(), new.display()))?;
+  Ok(())
+}
+
+
+fn deliver_mbox(mbox: &Path, sender: &str, time: u64, message: &[u8]) -> Result<()> {
+  let mut file = OpenOptions::new()
+    .append(true)
+    .create(true)
+    .open(mbox)
+    .with_context(|| format!("failed to open mbox `{}`", mbox.display()))?;
+  // Lock the file for the duration of the write, so that concurrent
+  // deliveries do not interleave. The lock is released when the file is
+  // closed.
+  let () = File::lock(&file).with_context(|| format!("failed to lock `{}`", mbox.display()))?;
+
+  let mut data = format!("From {sender} {}\n", asctime(time)).into_bytes();
+  let () = data.extend_from_slice(&mbox_escape(message));
+  let () = data.push(b'\n');
+
+  let () = file
+    .write_all(&data)
+    .with_context(|| format!("failed to append message to `{}`", mbox.display()))?;
+  let () = file
+    .sync_all()
+    .with_context(|| format!("failed to sync `{}`", mbox.display()))?;
+  Ok(())
+}
+
+
+/// Deliver an already formatted email into the mailbox referenced by
+/// `account`.
+pub(crate) async fn deliver(
+  account: &LocalAccount<'_>,
+  envelope: &Envelope,
+  message: &[u8],
+) -> Result<()> {
+  let path = account.mailbox.to_path_buf();
+  let format = account.mailbox_format;
+  let sender = envelope
+    .from()
+    .map(ToString::to_string)
+    .unwrap_or_else(|| "MAILER-DAEMON".to_string());
+  let message = message.to_vec();
+
+  spawn_blocking(move || match format {
+    MailboxFormat::Maildir => deliver_maildir(&path, &message),
+    MailboxFormat::Mbox => {
+      // SANITY: `UNIX_EPOCH` is earlier than *any* other `SystemTime`.
+      let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+      deliver_mbox(&path, &sender, now, &message)
+    },
+  })
+  .await
+  .context("local delivery task panicked")?
+}
+
+
+/// Check that messages can be delivered to the mailbox of `account`,
+/// without delivering anything.
+pub(crate) fn check(account: &LocalAccount<'_>) -> Result<()> {
+  let path = &account.mailbox;
+  match account.mailbox_format {
+    MailboxFormat::Maildir => {
+      // Missing directories get created on delivery, so the closest
+      // existing ancestor just has to be a directory.
+      let existing = path
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or(Path::new("."));
+      ensure!(
+        existing.is_dir(),
+        "`{}` is not a directory",
+        existing.display()
+      );
+    },
+    MailboxFormat::Mbox => {
+      if path.exists() {
+        let _file = OpenOptions::new()
+          .append(true)
+          .open(path)
+          .with_context(|| format!("failed to open mbox `{}`", path.display()))?;
+      } else {
+        let parent = path
+          .parent()
+          .filter(|parent| !parent.as_os_str().is_empty())
+          .unwrap_or(Path::new("."));
+        ensure!(
+          parent.is_dir(),
+          "directory `{}` of mbox does not exist",
+          parent.display()
+        );
+      }
+    },
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::fs::read;
+  use std::fs::read_dir;
+
+  use tempfile::tempdir;
+
+
+  /// Check that we format time stamps the way `asctime` does.
+  #[test]
+  fn asctime_formatting() {
+    assert_eq!(asctime(0), "Thu Jan  1 00:00:00 1970");
+    assert_eq!(asctime(951782400), "Tue Feb 29 00:00:00 2000");
+    assert_eq!(asctime(1709251199), "Thu Feb 29 23:59:59 2024");
+  }
+
+  /// Check that we properly escape `From ` lines for mbox storage.
+  #[test]
+  fn mbox_from_escaping() {
+    let message = b"Subject: test\r\n\r\nFrom here\r\n>From there\r\nnot From\r\n";
+    let escaped = mbox_escape(message);
+    assert_eq!(
+      escaped,
+      b"Subject: test\n\n>From here\n>>From there\nnot From\n"
+    );
+
+    // A missing trailing newline gets added.
+    assert_eq!(mbox_escape(b"body"), b"body\n");
+  }
+
+  /// Check that we can deliver messages into a Maildir.
+  #[test]
+  fn maildir_delivery() {
+    let dir = tempdir().unwrap();
+    let maildir = dir.path().join("mail");
+
+    let () = deliver_maildir(&maildir, b"first").unwrap();
+    let () = deliver_maildir(&maildir, b"second").unwrap();
+
+    assert_eq!(read_dir(maildir.join("tmp")).unwrap().count(), 0);
+    assert_eq!(read_dir(maildir.join("cur")).unwrap().count(), 0);
+
+    let mut messages = read_dir(maildir.join("new"))
+      .unwrap()
+      .map(|entry| read(entry.unwrap().path()).unwrap())
+      .collect::<Vec<_>>();
+    let () = messages.sort();
+    assert_eq!(messages, [b"first".to_vec(), b"second".to_vec()]);
+  }
+
+  /// Check that we can append messages to an mbox file.
+  #[test]
+  fn mbox_delivery() {
+    let dir = tempdir().unwrap();
+    let mbox = dir.path().join("mbox");
+
+    let () = deliver_mbox(&mbox, "a@example.com", 0, b"Subject: 1\r\n\r\nFrom me\r\n").unwrap();
+    let () = deliver_mbox(&mbox, "b@example.com", 60, b"Subject: 2\r\n\r\nhi").unwrap();
+
+    let expected = "\
+From a@example.com Thu Jan  1 00:00:00 1970
+Subject: 1
+
+>From me
+
+From b@example.com Thu Jan  1 00:01:00 1970
+Subject: 2
+
+hi
+
+";
+    assert_eq!(String::from_utf8(read(&mbox).unwrap()).unwrap(), expected);
+  }
+}
@@ -0,0 +1,225 @@
+// Copyright (C) 2024 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context as _;
+use anyhow::Error;
+use anyhow::Result;
+
+use lettre::address::Envelope;
+use lettre::transport::smtp::client::Tls;
+use lettre::transport::smtp::client::TlsParameters;
+use lettre::transport::smtp::extension::ClientId;
+use lettre::Address;
+use lettre::AsyncSmtpTransport;
+use lettre::AsyncTransport as _;
+use lettre::Tokio1Executor;
+
+use crate::dns::lookup_mx;
+use crate::dns::parse_resolver;
+use crate::dns::MxLookup;
+use crate::log;
+use crate::util::hostname;
+use crate::MxAccount;
+use crate::PartialDelivery;
+
+
+/// The default SMTP port used by mail exchangers.
+const SMTP_PORT: u16 = 25;
+/// The timeout for SMTP operations.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+
+/// Group recipients by (lower cased) domain.
+fn group_by_domain(recipients: &[Address]) -> BTreeMap<String, Vec<Address>> {
+  recipients
+    .iter()
+    .fold(BTreeMap::<_, Vec<_>>::new(), |mut groups, recipient| {
+      let domain = recipient.domain().to_ascii_lowercase();
+      let () = groups.entry(domain).or_default().push(recipient.clone());
+      groups
+    })
+}
+
+
+/// Determine the hosts to try for delivery to `domain`, in order.
+async fn mail_exchangers(resolver: SocketAddr, domain: &str) -> Result<Vec<String>> {
+  match lookup_mx(resolver, domain).await? {
+    MxLookup::NoDomain => bail!("domain `{domain}` does not exist"),
+    // Without any MX records, the domain itself acts as an implicit
+    // mail exchanger (RFC 5321, section 5.1).
+    MxLookup::Records(records) if records.is_empty() => Ok(vec![domain.to_string()]),
+    MxLookup::Records(records) => {
+      // A "null MX" record signals that the domain does not accept
+      // email (RFC 7505).
+      if records.len() == 1 && records[0].exchange.is_empty() {
+        bail!("domain `{domain}` does not accept email");
+      }
+      Ok(records.into_iter().map(|mx| mx.exchange).collect())
+    },
+  }
+}
+
+
+/// Deliver a message to a single mail exchanger, using TLS if offered.
+async fn deliver(host: &str, port: u16, envelope: &Envelope, message: &[u8]) -> Result<()> {
+  // Mail exchangers commonly present certificates not matching their
+  // host name and MTAs generally do not verify them when using TLS
+  // opportunistically. We follow suit, as the alternative would be to
+  // not encrypt at all.
+  let tls = TlsParameters::builder(host.to_string())
+    .dangerous_accept_invalid_certs(true)
+    .dangerous_accept_invalid_hostnames(true)
+    .build()
+    .context("failed to create TLS parameters")?;
+  let mailer = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)
+    .port(port)
+    .tls(Tls::Opportunistic(tls))
+    .hello_name(ClientId::Domain(hostname()))
+    .timeout(Some(TIMEOUT))
+    .build();
+
+  let _response = mailer.send_raw(envelope, message).await?;
+  Ok(())
+}
+
+
+/// Deliver a message to all recipients of a single domain.
+async fn deliver_domain(
+  account: &MxAccount<'_>,
+  resolver: SocketAddr,
+  domain: &str,
+  envelope: &Envelope,
+  message: &[u8],
+) -> Result<()> {
+  let hosts = mail_exchangers(resolver, domain).await?;
+  let port = account.mx_port.unwrap_or(SMTP_PORT);
+
+  let mut errors = Vec::new();
+  for host in hosts {
+    match deliver(&host, port, envelope, message).await {
+      Ok(()) => {
+        log::debug!(domain = domain, host = host, "email delivered");
+        return Ok(())
+      },
+      Err(err) => {
+        log::debug!(domain = domain, host = host, "delivery failed: {err:#}");
+        let () = errors.push(err.context(format!("failed to deliver email to `{host}`")));
+      },
+    }
+  }
+
+  let err = errors
+    .into_iter()
+    .reduce(|overall, err| overall.context(err))
+    .unwrap_or_else(|| anyhow!("no mail exchanger found"));
+  Err(err)
+}
+
+
+/// Deliver an already formatted email directly to the mail exchangers
+/// of all recipients' domains.
+pub(crate) async fn send(
+  account: &MxAccount<'_>,
+  envelope: &Envelope,
+  message: &[u8],
+) -> Result<()> {
+  let resolver = parse_resolver(&account.mx_resolver)?;
+  let mut delivered = Vec::new();
+  let mut failed = Vec::new();
+  let mut failures = Vec::<(String, Error)>::new();
+
+  for (domain, recipients) in group_by_domain(envelope.to()) {
+    let envelope = Envelope::new(envelope.from().cloned(), recipients.clone())
+      .context("failed to create per-domain envelope")?;
+    let result = deliver_domain(account, resolver, &domain, &envelope, message).await;
+    match result {
+      Ok(()) => delivered.extend(recipients),
+      Err(err) => {
+        let () = failed.extend(recipients);
+        let () = failures.push((domain, err));
+      },
+    }
+  }
+
+  if !failures.is_empty() {
+    let failures = failures
+      .into_iter()
+      .map(|(domain, err)| format!("{domain}: {err:#}"))
+      .collect::<Vec<_>>()
+      .join("\n");
+    let err = anyhow!("failed to deliver email to domain(s):\n{failures}");
+    // Domains the email got delivered to already must not receive it
+    // again when retrying, so make sure to report them.
+    if delivered.is_empty() {
+      return Err(err)
+    } else {
+      return Err(PartialDelivery::new(delivered, failed, err).into())
+    }
+  }
+  Ok(())
+}
+
+
+/// Check that mail exchangers can be determined for the domains of all
+/// recipients, without delivering anything.
+pub(crate) async fn check(account: &MxAccount<'_>, recipients: &[Address]) -> Result<()> {
+  let resolver = parse_resolver(&account.mx_resolver)?;
+  let mut failures = Vec::<String>::new();
+
+  for domain in group_by_domain(recipients).into_keys() {
+    if let Err(err) = mail_exchangers(resolver, &domain).await {
+      let () = failures.push(format!("{domain}: {err:#}"));
+    }
+  }
+
+  if !failures.is_empty() {
+    bail!(
+      "failed to determine mail exchangers of domain(s): {}",
+      failures.join("; ")
+    )
+  }
+  Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+
+  /// Check that we group recipients by their domain.
+  #[test]
+  fn domain_grouping() {
+    let recipients = ["a@example.com", "b@Example.COM", "c@example.org"]
+      .into_iter()
+      .map(|addr| addr.parse().unwrap())
+      .collect::<Vec<Address>>();
+
+    let groups = group_by_domain(&recipients);
+    let groups = groups
+      .iter()
+      .map(|(domain, addrs)| {
+        (
+          domain.as_str(),
+          addrs.iter().map(Address::to_string).collect::<Vec<_>>(),
+        )
+      })
+      .collect::<Vec<_>>();
+    assert_eq!(
+      groups,
+      [
+        (
+          "example.com",
+          vec!["a@example.com".to_string(), "b@Example.COM".to_string()]
+        ),
+        ("example.org", vec!["c@example.org".to_string()]),
+      ]
+    );
+  }
+}
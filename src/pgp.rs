@@ -24,7 +24,7 @@ use sequoia_openpgp::serialize::stream::Recipient;
 use sequoia_openpgp::types::KeyFlags;
 use sequoia_openpgp::Cert;
 
-fn parse_keybox(keybox: &Path) -> Result<Certs> {
+fn parse_keybox(keybox: &Path) -> Result<Certs<'_>> {
   let keyring = Certs::empty();
   let f = File::open(keybox)
     .with_context(|| format!("failed to open keyring file `{}`", keybox.display()))?;
@@ -67,21 +67,13 @@ where
   Ok(certs)
 }
 
-pub(crate) fn encrypt<R, S>(message: &[u8], keybox: &Path, recipients: R) -> Result<Vec<u8>>
-where
-  R: IntoIterator<Item = S>,
-  S: AsRef<str>,
-{
-  let mut recipients = recipients.into_iter().peekable();
-  if recipients.peek().is_none() {
-    return Err(anyhow!("no recipients given"));
-  }
-
-  let keyring = parse_keybox(keybox)?;
-  let certs = find_recipient_certs(&keyring, recipients)?;
-
+/// Select the usable encryption keys of the provided certificates,
+/// reporting an error if a certificate does not have any.
+fn encryption_keys<'cert>(
+  certs: &'cert [Cert],
+  policy: &'cert StandardPolicy,
+) -> Result<Vec<Recipient<'cert>>> {
   let mode = KeyFlags::empty().set_transport_encryption();
-  let policy = StandardPolicy::default();
 
   // Build a vector of recipients to hand to Encryptor.
   let mut recipient_subkeys = Vec::<Recipient>::new();
@@ -89,7 +81,7 @@ where
     let mut count = 0;
     for key in cert
       .keys()
-      .with_policy(&policy, None)
+      .with_policy(policy, None)
       .alive()
       .revoked(false)
       .key_flags(&mode)
@@ -104,7 +96,7 @@ where
       let mut expired_keys = Vec::new();
       for ka in cert
         .keys()
-        .with_policy(&policy, None)
+        .with_policy(policy, None)
         .revoked(false)
         .key_flags(&mode)
         .supported()
@@ -131,6 +123,48 @@ where
       }
     }
   }
+  Ok(recipient_subkeys)
+}
+
+/// Check that the keybox contains certificates with usable encryption
+/// keys for each of the recipients.
+#[cfg(feature = "config")]
+pub(crate) fn check<R, S>(keybox: &Path, recipients: R) -> Result<()>
+where
+  R: IntoIterator<Item = S>,
+  S: AsRef<str>,
+{
+  let keyring = parse_keybox(keybox)?;
+  let policy = StandardPolicy::default();
+
+  for recipient in recipients {
+    let recipient = recipient.as_ref();
+    let certs = find_recipient_certs(&keyring, [recipient])
+      .with_context(|| format!("no certificate found for recipient `{recipient}`"))?;
+    if certs.is_empty() {
+      return Err(anyhow!("no certificate found for recipient `{recipient}`"));
+    }
+    let _keys = encryption_keys(&certs, &policy)
+      .with_context(|| format!("no usable encryption key for recipient `{recipient}`"))?;
+  }
+  Ok(())
+}
+
+pub(crate) fn encrypt<R, S>(message: &[u8], keybox: &Path, recipients: R) -> Result<Vec<u8>>
+where
+  R: IntoIterator<Item = S>,
+  S: AsRef<str>,
+{
+  let mut recipients = recipients.into_iter().peekable();
+  if recipients.peek().is_none() {
+    return Err(anyhow!("no recipients given"));
+  }
+
+  let keyring = parse_keybox(keybox)?;
+  let certs = find_recipient_certs(&keyring, recipients)?;
+
+  let policy = StandardPolicy::default();
+  let recipient_subkeys = encryption_keys(&certs, &policy)?;
 
   let mut buffer = Vec::new();
   let out_msg = Message::new(&mut buffer);
@@ -0,0 +1,637 @@
+// Copyright (C) 2024 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::borrow::Cow;
+use std::fs::read;
+use std::fs::read_dir;
+use std::fs::remove_file;
+use std::fs::rename;
+use std::fs::DirBuilder;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::fs::TryLockError;
+use std::io::ErrorKind;
+use std::io::Write as _;
+use std::os::unix::fs::DirBuilderExt as _;
+use std::os::unix::fs::OpenOptionsExt as _;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use anyhow::ensure;
+use anyhow::Context as _;
+use anyhow::Error;
+use anyhow::Result;
+
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::from_slice as from_json;
+use serde_json::to_vec_pretty as to_json;
+
+use tokio::task::spawn_blocking;
+
+use crate::log;
+use crate::rand::Rng;
+use crate::send_email;
+use crate::Account;
+use crate::Attachment;
+use crate::EmailOpts;
+
+
+/// The extension of files containing the metadata of a queued email.
+const METADATA_EXT: &str = "json";
+/// The extension of files containing the message of a queued email.
+const MESSAGE_EXT: &str = "msg";
+/// The time to wait before retrying after the first failed attempt.
+const MIN_BACKOFF: Duration = Duration::from_secs(5 * 60);
+/// The maximum time to wait between two attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(24 * 60 * 60);
+/// The permissions of the queue directory.
+const DIR_MODE: u32 = 0o700;
+/// The permissions of files in the queue directory.
+const FILE_MODE: u32 = 0o600;
+
+
+/// Calculate the time to wait before the next attempt at sending an
+/// email, given the number of attempts made so far.
+fn backoff(attempts: u32) -> Duration {
+  let factor = 1u32 << attempts.saturating_sub(1).min(16);
+  MIN_BACKOFF.saturating_mul(factor).min(MAX_BACKOFF)
+}
+
+
+/// Metadata about an email residing in a [`Queue`].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct QueuedEmail {
+  /// The ID of the email in the queue.
+  #[serde(skip)]
+  pub id: String,
+  /// The subject of the email.
+  pub subject: String,
+  /// The content type of the email, if not the default one.
+  pub content_type: Option<String>,
+  /// The recipients of the email.
+  pub recipients: Vec<String>,
+  /// The files attached to the email.
+  #[serde(default)]
+  pub attachments: Vec<Attachment<'static>>,
+  /// The time at which the email was queued.
+  pub created: SystemTime,
+  /// The number of attempts made at sending the email.
+  pub attempts: u32,
+  /// The time before which no further attempt should be made.
+  pub next_attempt: SystemTime,
+  /// The error reported by the last failed attempt, if any.
+  pub last_error: Option<String>,
+  /// The time after which the email is dropped from the queue instead
+  /// of being sent, if any.
+  #[serde(default)]
+  pub expires: Option<SystemTime>,
+}
+
+impl QueuedEmail {
+  /// Check whether the email has expired at the given point in time.
+  fn is_expired(&self, now: SystemTime) -> bool {
+    self.expires.is_some_and(|expires| expires <= now)
+  }
+
+  /// Check whether the email is due for another attempt at the given
+  /// point in time.
+  fn is_due(&self, now: SystemTime) -> bool {
+    self.next_attempt <= now
+  }
+}
+
+
+/// The outcome of [`Queue::flush`].
+#[derive(Debug, Default)]
+pub struct FlushReport {
+  /// The IDs of the emails that got sent and removed from the queue.
+  pub sent: Vec<String>,
+  /// The IDs of the emails that failed sending again, along with the
+  /// error encountered.
+  pub failed: Vec<(String, Error)>,
+  /// The IDs of the emails that expired and were removed from the
+  /// queue without being sent.
+  pub expired: Vec<String>,
+}
+
+
+/// Create a new file at `path`, accessible only by its owner.
+fn create_file(path: &Path) -> std::io::Result<File> {
+  OpenOptions::new()
+    .write(true)
+    .create_new(true)
+    .mode(FILE_MODE)
+    .open(path)
+}
+
+
+/// An on-disk queue of emails awaiting (re-)delivery.
+///
+/// Each email is represented by two files in the queue directory: one
+/// containing the message itself and one containing metadata such as
+/// recipients and the time of the next attempt. Emails are locked
+/// while being worked on, so that concurrent users of the same queue
+/// never send an email twice.
+///
+/// Queued emails are stored unencrypted, but the queue directory and
+/// the files in it are only accessible by their owner.
+#[derive(Clone, Debug)]
+pub struct Queue {
+  /// The directory containing the queued emails.
+  dir: PathBuf,
+  /// The time after which emails added to the queue expire.
+  expiry: Option<Duration>,
+}
+
+impl Queue {
+  /// Create a new `Queue` object for the given directory.
+  ///
+  /// The directory is created lazily as emails are queued.
+  pub fn new<P>(dir: P) -> Self
+  where
+    P: Into<PathBuf>,
+  {
+    Self {
+      dir: dir.into(),
+      expiry: None,
+    }
+  }
+
+  /// Set the time after which emails added to the queue from here on
+  /// expire, i.e., are dropped instead of being sent.
+  pub fn with_expiry(mut self, expiry: Option<Duration>) -> Self {
+    self.expiry = expiry;
+    self
+  }
+
+  /// Retrieve the path to the queue directory.
+  #[inline]
+  pub fn path(&self) -> &Path {
+    &self.dir
+  }
+
+  fn file_path(&self, id: &str, extension: &str) -> Result<PathBuf> {
+    ensure!(
+      !id.is_empty() && !id.starts_with('.') && !id.contains('/'),
+      "`{id}` is not a valid queue ID"
+    );
+    Ok(self.dir.join(format!("{id}.{extension}")))
+  }
+
+  /// Run `f` on a thread on which blocking is acceptable.
+  async fn blocking<F, T>(&self, f: F) -> Result<T>
+  where
+    F: FnOnce(&Self) -> Result<T> + Send + 'static,
+    T: Send + 'static,
+  {
+    let queue = self.clone();
+    spawn_blocking(move || f(&queue))
+      .await
+      .context("failed to join blocking queue operation")?
+  }
+
+  fn write_metadata(&self, email: &QueuedEmail) -> Result<()> {
+    let path = self.file_path(&email.id, METADATA_EXT)?;
+    let tmp = self.dir.join(format!(".{}.{METADATA_EXT}", email.id));
+    let data = to_json(email).context("failed to serialize queue metadata")?;
+    // Write to a temporary file first and then move it in place, so
+    // that readers never see partial metadata.
+    let _result = remove_file(&tmp);
+    let () = create_file(&tmp)
+      .and_then(|mut file| file.write_all(&data))
+      .with_context(|| format!("failed to write `{}`", tmp.display()))?;
+    let () = rename(&tmp, &path)
+      .with_context(|| format!("failed to move `{}` to `{}`", tmp.display(), path.display()))?;
+    Ok(())
+  }
+
+  fn read_metadata(&self, id: &str) -> Result<QueuedEmail> {
+    let path = self.file_path(id, METADATA_EXT)?;
+    let data = read(&path).with_context(|| format!("failed to read `{}`", path.display()))?;
+    let mut email = from_json::<QueuedEmail>(&data)
+      .with_context(|| format!("failed to parse `{}` contents as JSON", path.display()))?;
+    email.id = id.to_string();
+    Ok(email)
+  }
+
+  /// Open the message file of the email with the given ID and lock it,
+  /// blocking until the lock is acquired if `block` is set. `None` is
+  /// returned if the email is currently locked by somebody else.
+  fn lock(&self, id: &str, block: bool) -> Result<Option<File>> {
+    let path = self.file_path(id, MESSAGE_EXT)?;
+    let file = File::open(&path)
+      .with_context(|| format!("failed to open queued message `{}`", path.display()))?;
+    if block {
+      let () = file
+        .lock()
+        .with_context(|| format!("failed to lock `{}`", path.display()))?;
+    } else {
+      match file.try_lock() {
+        Ok(()) => (),
+        Err(TryLockError::WouldBlock) => return Ok(None),
+        Err(TryLockError::Error(err)) => {
+          return Err(err).with_context(|| format!("failed to lock `{}`", path.display()))
+        },
+      }
+    }
+    Ok(Some(file))
+  }
+
+  fn remove(&self, id: &str) -> Result<()> {
+    // Remove the metadata first, as it is what makes an email show up
+    // in the queue.
+    for extension in [METADATA_EXT, MESSAGE_EXT] {
+      let path = self.file_path(id, extension)?;
+      let () =
+        remove_file(&path).with_context(|| format!("failed to remove `{}`", path.display()))?;
+    }
+    Ok(())
+  }
+
+  /// Store a new email, assigning it an ID, which is returned.
+  fn store(&self, mut email: QueuedEmail, message: &[u8]) -> Result<String> {
+    let () = DirBuilder::new()
+      .recursive(true)
+      .mode(DIR_MODE)
+      .create(&self.dir)
+      .with_context(|| format!("failed to create queue directory `{}`", self.dir.display()))?;
+
+    // SANITY: `UNIX_EPOCH` is earlier than *any* other `SystemTime`.
+    let secs = email.created.duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let rng = Rng::new();
+
+    let (id, mut file) = loop {
+      let id = format!("{secs:011}-{:08x}", rng.rand_u32());
+      let path = self.file_path(&id, MESSAGE_EXT)?;
+      match create_file(&path) {
+        Ok(file) => break (id, file),
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+        Err(err) => {
+          return Err(err).with_context(|| format!("failed to create `{}`", path.display()))
+        },
+      }
+    };
+
+    let () = file
+      .write_all(message)
+      .and_then(|()| file.sync_all())
+      .with_context(|| format!("failed to write message of queued email {id}"))?;
+
+    email.id = id;
+    let () = self.write_metadata(&email)?;
+    Ok(email.id)
+  }
+
+  pub(crate) async fn insert<R, S>(
+    &self,
+    subject: &str,
+    message: &[u8],
+    content_type: Option<&str>,
+    recipients: R,
+    attachments: &[Attachment<'_>],
+    failure: Option<&Error>,
+  ) -> Result<String>
+  where
+    R: Iterator<Item = S>,
+    S: AsRef<str>,
+  {
+    let now = SystemTime::now();
+    let email = QueuedEmail {
+      id: String::new(),
+      subject: subject.to_string(),
+      content_type: content_type.map(str::to_string),
+      recipients: recipients.map(|r| r.as_ref().to_string()).collect(),
+      attachments: attachments
+        .iter()
+        .map(|attachment| Attachment {
+          name: Cow::Owned(attachment.name.to_string()),
+          content_type: attachment
+            .content_type
+            .as_ref()
+            .map(|content_type| Cow::Owned(content_type.to_string())),
+          data: Cow::Owned(attachment.data.to_vec()),
+        })
+        .collect(),
+      created: now,
+      attempts: u32::from(failure.is_some()),
+      next_attempt: if failure.is_some() {
+        now + backoff(1)
+      } else {
+        now
+      },
+      last_error: failure.map(|err| format!("{err:#}")),
+      expires: self.expiry.map(|expiry| now + expiry),
+    };
+    let message = message.to_vec();
+    self
+      .blocking(move |queue| queue.store(email, &message))
+      .await
+  }
+
+  /// Add an email to the queue, to be sent on the next
+  /// [`flush`][Self::flush].
+  ///
+  /// The ID of the queued email is returned.
+  pub async fn enqueue<R, S>(
+    &self,
+    subject: &str,
+    message: &[u8],
+    content_type: Option<&str>,
+    recipients: R,
+    attachments: &[Attachment<'_>],
+  ) -> Result<String>
+  where
+    R: IntoIterator<Item = S>,
+    S: AsRef<str>,
+  {
+    self.insert(
+      subject,
+      message,
+      content_type,
+      recipients.into_iter(),
+      attachments,
+      None,
+    )
+    .await
+  }
+
+  fn list_blocking(&self) -> Result<Vec<QueuedEmail>> {
+    let entries = match read_dir(&self.dir) {
+      Ok(entries) => entries,
+      Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+      Err(err) => {
+        return Err(err).with_context(|| format!("failed to read `{}`", self.dir.display()))
+      },
+    };
+
+    let mut emails = Vec::new();
+    for entry in entries {
+      let entry =
+        entry.with_context(|| format!("failed to read `{}` entry", self.dir.display()))?;
+      let path = entry.path();
+      if path.extension().and_then(|ext| ext.to_str()) != Some(METADATA_EXT) {
+        continue
+      }
+      let Some(id) = path.file_stem().and_then(|stem| stem.to_str()) else {
+        continue
+      };
+      if id.starts_with('.') {
+        continue
+      }
+
+      match self.read_metadata(id) {
+        Ok(email) => emails.push(email),
+        // The email may have been sent and removed concurrently.
+        Err(_err) if !path.exists() => {
+          log::debug!("ignoring vanished email {id}: {_err:#}");
+        },
+        Err(err) => return Err(err),
+      }
+    }
+
+    let () = emails.sort_by(|x, y| (x.created, &x.id).cmp(&(y.created, &y.id)));
+    Ok(emails)
+  }
+
+  /// List all emails in the queue, oldest first.
+  pub async fn list(&self) -> Result<Vec<QueuedEmail>> {
+    self.blocking(Self::list_blocking).await
+  }
+
+  fn get_blocking(&self, id: &str) -> Result<(QueuedEmail, Vec<u8>)> {
+    let email = self.read_metadata(id)?;
+    let path = self.file_path(id, MESSAGE_EXT)?;
+    let message = read(&path).with_context(|| format!("failed to read `{}`", path.display()))?;
+    Ok((email, message))
+  }
+
+  /// Retrieve the metadata and message of the email with the given ID.
+  pub async fn get(&self, id: &str) -> Result<(QueuedEmail, Vec<u8>)> {
+    let id = id.to_string();
+    self.blocking(move |queue| queue.get_blocking(&id)).await
+  }
+
+  /// Delete the email with the given ID from the queue.
+  ///
+  /// If the email is currently being sent by somebody else, this
+  /// function waits for that attempt to conclude.
+  pub async fn delete(&self, id: &str) -> Result<()> {
+    let id = id.to_string();
+    self
+      .blocking(move |queue| {
+        let _lock = queue.lock(&id, true)?;
+        queue.remove(&id)
+      })
+      .await
+  }
+
+  /// Attempt to send all due emails in the queue, using the provided
+  /// accounts and options.
+  ///
+  /// Emails are due once the time of their next attempt has come. If
+  /// `force` is set, all emails are considered due. Emails that are
+  /// currently being worked on by somebody else are skipped. Emails
+  /// sent successfully are removed from the queue, while the others
+  /// are scheduled for another attempt, backing off exponentially.
+  /// Expired emails are removed without any attempt being made.
+  ///
+  /// Note that any queue, fallback sinks, and subject prefix configured
+  /// in `opts` are ignored.
+  pub async fn flush<'acc>(
+    &self,
+    accounts: &'acc [Account<'acc>],
+    opts: &EmailOpts<'_>,
+    force: bool,
+  ) -> Result<FlushReport> {
+    // Queued emails have their subject prefixed already.
+    let mut opts = EmailOpts {
+      queue: None,
+      fallback: Vec::new(),
+      subject_prefix: None,
+      ..opts.clone()
+    };
+    let mut report = FlushReport::default();
+
+    for email in self.list().await? {
+      let now = SystemTime::now();
+      if !force && !email.is_expired(now) && !email.is_due(now) {
+        continue
+      }
+
+      let id = email.id;
+      let locked = self
+        .blocking(move |queue| {
+          let Some(lock) = queue.lock(&id, false)? else {
+            log::debug!("skipping locked email {id}");
+            return Ok(None)
+          };
+          // Somebody else may have worked on the email before we
+          // acquired the lock. Our data could be stale then.
+          match queue.get_blocking(&id) {
+            Ok((email, message)) => Ok(Some((lock, email, message))),
+            Err(_) if !queue.file_path(&id, METADATA_EXT)?.exists() => Ok(None),
+            Err(err) => Err(err),
+          }
+        })
+        .await?;
+      let Some((_lock, mut email, message)) = locked else {
+        continue
+      };
+
+      if email.is_expired(now) {
+        let id = email.id.clone();
+        let () = self.blocking(move |queue| queue.remove(&id)).await?;
+        log::debug!("dropped expired email {}", email.id);
+        let () = report.expired.push(email.id);
+        continue
+      }
+      if !force && !email.is_due(now) {
+        continue
+      }
+
+      opts.attachments = email.attachments.clone();
+      let result = send_email(
+        accounts,
+        &email.subject,
+        &message,
+        email.content_type.as_deref(),
+        email.recipients.iter(),
+        &opts,
+      )
+      .await;
+
+      match result {
+        Ok(()) => {
+          let id = email.id.clone();
+          let () = self.blocking(move |queue| queue.remove(&id)).await?;
+          log::debug!("sent queued email {}", email.id);
+          let () = report.sent.push(email.id);
+        },
+        Err(err) => {
+          email.attempts = email.attempts.saturating_add(1);
+          email.next_attempt = SystemTime::now() + backoff(email.attempts);
+          email.last_error = Some(format!("{err:#}"));
+          let email = self
+            .blocking(move |queue| queue.write_metadata(&email).map(|()| email))
+            .await?;
+          log::debug!("failed to send queued email {}: {err:#}", email.id);
+          let () = report.failed.push((email.id, err));
+        },
+      }
+    }
+    Ok(report)
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use tempfile::tempdir;
+
+
+  /// Check that we back off exponentially, up to a limit.
+  #[test]
+  fn backoff_calculation() {
+    assert_eq!(backoff(1), MIN_BACKOFF);
+    assert_eq!(backoff(2), MIN_BACKOFF * 2);
+    assert_eq!(backoff(3), MIN_BACKOFF * 4);
+    assert_eq!(backoff(100), MAX_BACKOFF);
+  }
+
+  /// Check that we can add, inspect, list, and delete emails.
+  #[tokio::test]
+  async fn queue_management() {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    let dir = tempdir().unwrap();
+    let queue = Queue::new(dir.path().join("queue"));
+    assert!(queue.list().await.unwrap().is_empty());
+
+    let id1 = queue
+      .enqueue("first", b"body 1", None, ["a@example.com"], &[])
+      .await
+      .unwrap();
+    let err = Error::msg("connection refused");
+    let id2 = queue
+      .insert(
+        "second",
+        b"body 2",
+        Some("text/html"),
+        ["b@example.com"].iter(),
+        &[],
+        Some(&err),
+      )
+      .await
+      .unwrap();
+
+    let mode = |path: &Path| path.metadata().unwrap().permissions().mode() & 0o777;
+    assert_eq!(mode(queue.path()), DIR_MODE);
+    assert_eq!(mode(&queue.file_path(&id1, MESSAGE_EXT).unwrap()), FILE_MODE);
+    assert_eq!(mode(&queue.file_path(&id1, METADATA_EXT).unwrap()), FILE_MODE);
+
+    let emails = queue.list().await.unwrap();
+    assert_eq!(emails.len(), 2);
+    assert_eq!(emails[0].id, id1);
+    assert_eq!(emails[0].attempts, 0);
+    assert_eq!(emails[1].id, id2);
+    assert_eq!(emails[1].attempts, 1);
+    assert!(emails[1].next_attempt > emails[1].created);
+    assert_eq!(emails[1].last_error.as_deref(), Some("connection refused"));
+
+    let (email, message) = queue.get(&id2).await.unwrap();
+    assert_eq!(email.subject, "second");
+    assert_eq!(email.content_type.as_deref(), Some("text/html"));
+    assert_eq!(email.recipients, ["b@example.com"]);
+    assert_eq!(message, b"body 2");
+
+    let () = queue.delete(&id1).await.unwrap();
+    let emails = queue.list().await.unwrap();
+    assert_eq!(emails.len(), 1);
+    assert_eq!(emails[0].id, id2);
+
+    assert!(queue.delete(&id1).await.is_err());
+    assert!(queue.get("../escape").await.is_err());
+  }
+
+  /// Check that expired emails are dropped when flushing.
+  #[tokio::test]
+  async fn expiry() {
+    let dir = tempdir().unwrap();
+    let queue = Queue::new(dir.path()).with_expiry(Some(Duration::ZERO));
+    let id = queue
+      .enqueue("subject", b"body", None, ["a@example.com"], &[])
+      .await
+      .unwrap();
+    let (email, _message) = queue.get(&id).await.unwrap();
+    assert_eq!(email.expires, Some(email.created));
+
+    let report = queue.flush(&[], &EmailOpts::default(), false).await.unwrap();
+    assert_eq!(report.expired, [id]);
+    assert!(report.sent.is_empty());
+    assert!(report.failed.is_empty());
+    assert!(queue.list().await.unwrap().is_empty());
+  }
+
+  /// Check that locked emails are skipped.
+  #[tokio::test]
+  async fn locking() {
+    let dir = tempdir().unwrap();
+    let queue = Queue::new(dir.path());
+    let id = queue
+      .enqueue("subject", b"body", None, ["a@example.com"], &[])
+      .await
+      .unwrap();
+
+    let lock = queue.lock(&id, false).unwrap();
+    assert!(lock.is_some());
+    assert!(queue.lock(&id, false).unwrap().is_none());
+    drop(lock);
+    assert!(queue.lock(&id, false).unwrap().is_some());
+  }
+}
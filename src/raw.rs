@@ -0,0 +1,316 @@
+// Copyright (C) 2024 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// Support for sending already formatted RFC 5322 messages, as opposed
+// to composing them from their constituents.
+
+use std::borrow::Cow;
+use std::path::Path;
+
+use anyhow::ensure;
+use anyhow::Context as _;
+use anyhow::Result;
+
+use lettre::address::Envelope;
+use lettre::message::Mailbox;
+use lettre::message::Mailboxes;
+use lettre::Address;
+
+
+/// Convert bare line feeds into CRLF line endings, as required on the
+/// wire.
+fn normalize_line_endings(message: &[u8]) -> Cow<'_, [u8]> {
+  let bare = message
+    .iter()
+    .enumerate()
+    .any(|(idx, byte)| *byte == b'\n' && (idx == 0 || message[idx - 1] != b'\r'));
+  if !bare {
+    return Cow::Borrowed(message)
+  }
+
+  let mut normalized = Vec::with_capacity(message.len() + message.len() / 32);
+  for (idx, byte) in message.iter().enumerate() {
+    if *byte == b'\n' && (idx == 0 || message[idx - 1] != b'\r') {
+      let () = normalized.push(b'\r');
+    }
+    let () = normalized.push(*byte);
+  }
+  Cow::Owned(normalized)
+}
+
+
+/// A header field of a message, in its raw (folded) form.
+#[derive(Debug)]
+struct Field<'msg> {
+  /// The complete field, including its trailing line ending.
+  raw: &'msg [u8],
+}
+
+impl Field<'_> {
+  /// Retrieve the name of the field.
+  fn name(&self) -> &[u8] {
+    let end = self
+      .raw
+      .iter()
+      .position(|byte| *byte == b':')
+      .unwrap_or(self.raw.len());
+    self.raw[..end].trim_ascii()
+  }
+
+  /// Check whether the field has the given name.
+  fn is(&self, name: &str) -> bool {
+    self.name().eq_ignore_ascii_case(name.as_bytes())
+  }
+
+  /// Check whether the field describes the message's content.
+  fn is_mime(&self) -> bool {
+    let name = self.name();
+    self.is("MIME-Version")
+      || name
+        .get(..8)
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case(b"Content-"))
+  }
+
+  /// Retrieve the unfolded value of the field.
+  fn value(&self) -> String {
+    let start = self
+      .raw
+      .iter()
+      .position(|byte| *byte == b':')
+      .map(|idx| idx + 1)
+      .unwrap_or(self.raw.len());
+    let value = String::from_utf8_lossy(&self.raw[start..]);
+    value.replace("\r\n", "").trim().to_string()
+  }
+}
+
+
+/// Split a message with CRLF line endings into its header fields and
+/// body.
+fn split(message: &[u8]) -> (Vec<Field<'_>>, &[u8]) {
+  let mut fields = Vec::<Field<'_>>::new();
+  let mut offset = 0;
+  let mut start = None;
+
+  for line in message.split_inclusive(|byte| *byte == b'\n') {
+    let continuation = line.starts_with(b" ") || line.starts_with(b"\t");
+    if !continuation {
+      if let Some(start) = start.take() {
+        let () = fields.push(Field {
+          raw: &message[start..offset],
+        });
+      }
+    }
+
+    if line == b"\r\n" {
+      return (fields, &message[offset + line.len()..])
+    }
+    if !continuation {
+      start = Some(offset);
+    }
+    offset += line.len();
+  }
+
+  if let Some(start) = start {
+    let () = fields.push(Field {
+      raw: &message[start..],
+    });
+  }
+  (fields, &message[message.len()..])
+}
+
+
+/// Retrieve the subject of a message, if any.
+pub(crate) fn subject(message: &[u8]) -> Option<String> {
+  let message = normalize_line_endings(message);
+  let (fields, _body) = split(&message);
+  fields
+    .iter()
+    .find(|field| field.is("Subject"))
+    .map(Field::value)
+}
+
+
+/// Collect the addresses contained in the `To`, `Cc`, and `Bcc` header
+/// fields.
+fn header_recipients(fields: &[Field<'_>]) -> Result<Vec<Address>> {
+  let mut to = Vec::new();
+  for field in fields
+    .iter()
+    .filter(|field| field.is("To") || field.is("Cc") || field.is("Bcc"))
+  {
+    let value = field.value();
+    let mailboxes = value.parse::<Mailboxes>().with_context(|| {
+      format!(
+        "failed to parse recipients from '{}' header: `{value}`",
+        String::from_utf8_lossy(field.name())
+      )
+    })?;
+    let () = to.extend(mailboxes.into_iter().map(|mailbox| mailbox.email));
+  }
+  Ok(to)
+}
+
+
+/// Retrieve the recipients of a message, as derived from its `To`,
+/// `Cc`, and `Bcc` header fields.
+pub(crate) fn recipients(message: &[u8]) -> Result<Vec<Address>> {
+  let message = normalize_line_endings(message);
+  let (fields, _body) = split(&message);
+  header_recipients(&fields)
+}
+
+
+/// Prepare a complete message for sending via an account with the
+/// given "From" identifier.
+///
+/// If no recipients are provided, they are derived from the `To`, `Cc`,
+/// and `Bcc` header fields. `Bcc` fields are removed from the message.
+/// If a keybox is provided, the message's content is PGP encrypted in
+/// place, retaining all other header fields.
+pub(crate) fn prepare<R, S, E>(
+  from: &str,
+  message: &[u8],
+  recipients: R,
+  pgp_keybox: Option<&Path>,
+  encrypt: E,
+) -> Result<(Envelope, Vec<u8>)>
+where
+  R: Iterator<Item = S>,
+  S: AsRef<str>,
+  E: FnOnce(&[u8], &Path, &[Address]) -> Result<Vec<u8>>,
+{
+  let from = from
+    .parse::<Mailbox>()
+    .with_context(|| format!("failed to parse 'From' specification: `{from}`"))?;
+  let message = normalize_line_endings(message);
+  let (fields, body) = split(&message);
+
+  let mut to = recipients
+    .map(|recipient| {
+      let recipient = recipient.as_ref();
+      recipient
+        .parse::<Mailbox>()
+        .map(|mailbox| mailbox.email)
+        .with_context(|| format!("failed to parse 'To' specification: `{recipient}`"))
+    })
+    .collect::<Result<Vec<_>>>()?;
+
+  if to.is_empty() {
+    to = header_recipients(&fields)?;
+  }
+  ensure!(!to.is_empty(), "message does not contain any recipients");
+
+  let envelope =
+    Envelope::new(Some(from.email), to.clone()).context("failed to create envelope")?;
+  let fields = fields.into_iter().filter(|field| !field.is("Bcc"));
+
+  let mut prepared = Vec::with_capacity(message.len());
+  if let Some(keybox) = pgp_keybox {
+    let (content, other) = fields.partition::<Vec<_>, _>(Field::is_mime);
+
+    let mut inner = Vec::with_capacity(message.len());
+    for field in content.iter().filter(|field| !field.is("MIME-Version")) {
+      let () = inner.extend_from_slice(field.raw);
+    }
+    let () = inner.extend_from_slice(b"\r\n");
+    let () = inner.extend_from_slice(body);
+
+    let encrypted = encrypt(&inner, keybox, &to).context("failed to encrypt message")?;
+    for field in other {
+      let () = prepared.extend_from_slice(field.raw);
+    }
+    let () = prepared.extend_from_slice(b"MIME-Version: 1.0\r\n");
+    let () = prepared.extend_from_slice(&encrypted);
+  } else {
+    for field in fields {
+      let () = prepared.extend_from_slice(field.raw);
+    }
+    let () = prepared.extend_from_slice(b"\r\n");
+    let () = prepared.extend_from_slice(body);
+  }
+  Ok((envelope, prepared))
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+
+  /// Check that we prepare raw messages as expected.
+  #[test]
+  fn raw_preparation() {
+    let message = b"From: Tool <tool@example.com>\nTo: Jane <jane@example.com>,\n  \
+john@example.com\nBcc: secret@example.com\nSubject: Report\n\nbody\n";
+    let encrypt = |_: &[u8], _: &Path, _: &[Address]| unreachable!();
+    let none: &[&str] = &[];
+
+    let (envelope, prepared) = prepare(
+      "maily@example.com",
+      message,
+      none.iter(),
+      None,
+      encrypt,
+    )
+    .unwrap();
+    assert_eq!(envelope.from().unwrap().to_string(), "maily@example.com");
+    let to = envelope
+      .to()
+      .iter()
+      .map(Address::to_string)
+      .collect::<Vec<_>>();
+    assert_eq!(
+      to,
+      ["jane@example.com", "john@example.com", "secret@example.com"]
+    );
+    assert_eq!(
+      prepared,
+      b"From: Tool <tool@example.com>\r\nTo: Jane <jane@example.com>,\r\n  \
+john@example.com\r\nSubject: Report\r\n\r\nbody\r\n"
+    );
+
+    let (envelope, _prepared) = prepare(
+      "maily@example.com",
+      message,
+      ["other@example.com"].iter(),
+      None,
+      encrypt,
+    )
+    .unwrap();
+    assert_eq!(envelope.to().len(), 1);
+
+    let err = prepare("maily@example.com", b"Subject: x\n\n", none.iter(), None, encrypt)
+      .unwrap_err();
+    assert_eq!(err.to_string(), "message does not contain any recipients");
+
+    assert_eq!(subject(message).as_deref(), Some("Report"));
+  }
+
+  /// Check that the content of a message gets encrypted in place.
+  #[test]
+  fn raw_encryption() {
+    let message = b"From: tool@example.com\r\nTo: jane@example.com\r\nMIME-Version: 1.0\r\n\
+Content-Type: text/html\r\nSubject: Report\r\n\r\n<p>body</p>\r\n";
+    let encrypt = |inner: &[u8], _: &Path, to: &[Address]| {
+      assert_eq!(inner, b"Content-Type: text/html\r\n\r\n<p>body</p>\r\n");
+      assert_eq!(to.len(), 1);
+      Ok(b"Content-Type: multipart/encrypted\r\n\r\n...".to_vec())
+    };
+    let none: &[&str] = &[];
+
+    let (_envelope, prepared) = prepare(
+      "maily@example.com",
+      message,
+      none.iter(),
+      Some(Path::new("keybox")),
+      encrypt,
+    )
+    .unwrap();
+    assert_eq!(
+      prepared,
+      b"From: tool@example.com\r\nTo: jane@example.com\r\nSubject: Report\r\n\
+MIME-Version: 1.0\r\nContent-Type: multipart/encrypted\r\n\r\n..."
+    );
+  }
+}
@@ -0,0 +1,239 @@
+// Copyright (C) 2024 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// A small protocol for submitting emails to a local daemon over a Unix
+// domain socket. Each message is framed by its length, as a 32 bit big
+// endian integer, followed by the JSON encoded payload. The client
+// sends a single `Submission` and the daemon answers with a `Response`
+// once the email got sent (or failed to).
+
+use std::borrow::Cow;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use anyhow::ensure;
+use anyhow::Context as _;
+use anyhow::Result;
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::from_slice as from_json;
+use serde_json::to_vec as to_json;
+
+use tokio::io::AsyncReadExt as _;
+use tokio::io::AsyncWriteExt as _;
+#[cfg(doc)]
+use tokio::net::UnixListener;
+use tokio::net::UnixStream;
+use tokio::time::timeout;
+
+use crate::Attachment;
+
+
+/// The maximum size of a frame we are willing to accept.
+const MAX_FRAME_SIZE: u32 = 64 * 1024 * 1024;
+/// The time we grant a client for transferring its submission.
+const FRAME_TIMEOUT: Duration = Duration::from_secs(30);
+
+
+/// An email submitted to a daemon for sending.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Submission<'input> {
+  /// The subject of the email.
+  pub subject: Cow<'input, str>,
+  /// The body of the email.
+  pub body: Cow<'input, str>,
+  /// The content type of the body; defaults to `text/plain` if not
+  /// provided.
+  #[serde(default)]
+  pub content_type: Option<Cow<'input, str>>,
+  /// The recipients of the email.
+  ///
+  /// If empty, the daemon sends the email to its configured default
+  /// recipients.
+  #[serde(default)]
+  pub recipients: Vec<Cow<'input, str>>,
+  /// The files to attach to the email.
+  #[serde(default)]
+  pub attachments: Vec<Attachment<'input>>,
+}
+
+
+/// The daemon's response to a submission.
+#[derive(Debug, Deserialize, Serialize)]
+struct Response {
+  /// The error encountered sending the email, if any.
+  error: Option<String>,
+}
+
+
+async fn write_frame<T>(stream: &mut UnixStream, value: &T) -> Result<()>
+where
+  T: Serialize,
+{
+  let data = to_json(value).context("failed to serialize frame")?;
+  let len = u32::try_from(data.len())
+    .ok()
+    .filter(|len| *len <= MAX_FRAME_SIZE)
+    .context("frame is too large")?;
+
+  let () = stream
+    .write_all(&len.to_be_bytes())
+    .await
+    .context("failed to write frame length")?;
+  let () = stream
+    .write_all(&data)
+    .await
+    .context("failed to write frame")?;
+  Ok(())
+}
+
+
+async fn read_frame<T>(stream: &mut UnixStream) -> Result<T>
+where
+  T: DeserializeOwned,
+{
+  let len = stream
+    .read_u32()
+    .await
+    .context("failed to read frame length")?;
+  ensure!(
+    len <= MAX_FRAME_SIZE,
+    "frame of {len} bytes exceeds maximum size"
+  );
+
+  // Grow the buffer only as data arrive, instead of trusting the
+  // announced length up front.
+  let mut data = Vec::new();
+  let count = (&mut *stream)
+    .take(u64::from(len))
+    .read_to_end(&mut data)
+    .await
+    .context("failed to read frame")?;
+  ensure!(count == len as usize, "frame is truncated");
+  let value = from_json(&data).context("failed to parse frame contents as JSON")?;
+  Ok(value)
+}
+
+
+/// Submit an email to the daemon listening on `socket`, waiting for it
+/// to be sent.
+///
+/// An error is reported if the daemon failed to send the email.
+pub async fn submit(socket: &Path, submission: &Submission<'_>) -> Result<()> {
+  let mut stream = UnixStream::connect(socket)
+    .await
+    .with_context(|| format!("failed to connect to `{}`", socket.display()))?;
+  let () = write_frame(&mut stream, submission)
+    .await
+    .context("failed to send submission")?;
+  // The daemon only answers once it is done sending, which may take a
+  // while. Hence, we do not impose a timeout here.
+  let response = read_frame::<Response>(&mut stream)
+    .await
+    .context("failed to receive response")?;
+
+  match response.error {
+    None => Ok(()),
+    Some(err) => Err(anyhow!(err).context("daemon failed to send email")),
+  }
+}
+
+
+/// A submission received by a daemon, awaiting a reply.
+#[derive(Debug)]
+pub struct SubmissionRequest {
+  /// The submitted email.
+  pub submission: Submission<'static>,
+  /// The connection to the client.
+  stream: UnixStream,
+}
+
+impl SubmissionRequest {
+  /// Receive a submission from a client connected via `stream`, as
+  /// accepted from a [`UnixListener`].
+  pub async fn receive(mut stream: UnixStream) -> Result<Self> {
+    // Make sure that misbehaving clients cannot hog resources
+    // indefinitely.
+    let submission = timeout(FRAME_TIMEOUT, read_frame(&mut stream))
+      .await
+      .context("timed out receiving submission")?
+      .context("failed to receive submission")?;
+
+    let slf = Self { submission, stream };
+    Ok(slf)
+  }
+
+  /// Inform the client about the outcome of sending the submitted
+  /// email.
+  pub async fn reply(mut self, result: &Result<()>) -> Result<()> {
+    let response = Response {
+      error: result.as_ref().err().map(|err| format!("{err:#}")),
+    };
+    write_frame(&mut self.stream, &response)
+      .await
+      .context("failed to send response")
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use tempfile::tempdir;
+
+  use tokio::net::UnixListener;
+  use tokio::spawn;
+
+
+  /// Check that submissions and their outcome are transferred
+  /// correctly.
+  #[tokio::test]
+  async fn submission_roundtrip() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("socket");
+    let listener = UnixListener::bind(&path).unwrap();
+
+    let server = spawn(async move {
+      let mut subjects = Vec::new();
+      for result in [Ok(()), Err(anyhow!("connection refused"))] {
+        let (stream, _addr) = listener.accept().await.unwrap();
+        let request = SubmissionRequest::receive(stream).await.unwrap();
+        let () = subjects.push(request.submission.subject.to_string());
+        assert_eq!(request.submission.recipients, ["a@example.com"]);
+        assert_eq!(request.submission.attachments[0].data.as_ref(), b"\0\x01\x02");
+        let () = request.reply(&result).await.unwrap();
+      }
+      subjects
+    });
+
+    let submission = Submission {
+      subject: Cow::Borrowed("first"),
+      body: Cow::Borrowed("body"),
+      recipients: vec![Cow::Borrowed("a@example.com")],
+      attachments: vec![Attachment {
+        name: Cow::Borrowed("data.bin"),
+        content_type: None,
+        data: Cow::Borrowed(b"\0\x01\x02"),
+      }],
+      ..Default::default()
+    };
+    let () = submit(&path, &submission).await.unwrap();
+
+    let submission = Submission {
+      subject: Cow::Borrowed("second"),
+      ..submission
+    };
+    let err = submit(&path, &submission).await.unwrap_err();
+    assert_eq!(
+      format!("{err:#}"),
+      "daemon failed to send email: connection refused"
+    );
+
+    let subjects = server.await.unwrap();
+    assert_eq!(subjects, ["first", "second"]);
+  }
+}
@@ -0,0 +1,621 @@
+// Copyright (C) 2024 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Utilities for testing code sending emails using this crate.
+//!
+//! The centerpiece is [`SmtpServer`], an in-process fake SMTP server
+//! that records all the emails it receives and can be instructed to
+//! fail at specific phases of an SMTP session.
+
+use std::borrow::Cow;
+use std::env::temp_dir;
+use std::fs::remove_file;
+use std::fs::OpenOptions;
+use std::io::ErrorKind;
+use std::io::Write as _;
+use std::mem::take;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use anyhow::Context as _;
+use anyhow::Result;
+
+use native_tls::Identity;
+use native_tls::TlsAcceptor as NativeTlsAcceptor;
+
+use rcgen::CertificateParams;
+use rcgen::KeyPair;
+
+use tokio::io::AsyncBufReadExt as _;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt as _;
+use tokio::io::BufStream;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+use tokio::spawn;
+use tokio::task::JoinHandle;
+use tokio_native_tls::TlsAcceptor;
+
+use crate::rand::Rng;
+use crate::util::decode_base64;
+use crate::Account;
+use crate::SmtpAccount;
+use crate::SmtpMode;
+
+
+/// The user name used by accounts created by [`SmtpServer::account`].
+pub const USER: &str = "user";
+/// The password used by accounts created by [`SmtpServer::account`].
+pub const PASSWORD: &str = "password";
+
+
+/// Write `cert` to a newly created file with a random name in the
+/// system's temporary directory, returning its path.
+fn write_ca_file(cert: &str) -> Result<PathBuf> {
+  let rng = Rng::new();
+  loop {
+    let path = temp_dir().join(format!(
+      "maily-testing-{}-{:08x}.pem",
+      process::id(),
+      rng.rand_u32()
+    ));
+    // Never reuse an existing file (or follow a symbolic link), as
+    // anybody may have created it.
+    let result = OpenOptions::new().write(true).create_new(true).open(&path);
+    let mut file = match result {
+      Ok(file) => file,
+      Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+      Err(err) => {
+        return Err(err).with_context(|| format!("failed to create `{}`", path.display()))
+      },
+    };
+
+    let result = file.write_all(cert.as_bytes());
+    if let Err(err) = result {
+      let _result = remove_file(&path);
+      return Err(err)
+        .with_context(|| format!("failed to write certificate to `{}`", path.display()))
+    }
+    return Ok(path)
+  }
+}
+
+
+/// The transport security offered by an [`SmtpServer`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum Security {
+  /// Plain text SMTP without any encryption.
+  Plain,
+  /// Plain text SMTP with support for upgrading via `STARTTLS`.
+  StartTls,
+  /// SMTP over TLS from the start.
+  Tls,
+}
+
+
+/// A phase of an SMTP session at which an [`SmtpServer`] can be
+/// instructed to fail.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum Phase {
+  /// Close connections right after accepting them, without sending a
+  /// greeting.
+  Connect,
+  /// Reply with an error instead of the greeting.
+  Greeting,
+  /// Reject the `EHLO`/`HELO` command.
+  Ehlo,
+  /// Reject the `STARTTLS` command.
+  StartTls,
+  /// Reject authentication attempts.
+  Auth,
+  /// Reject the `MAIL FROM` command.
+  MailFrom,
+  /// Reject `RCPT TO` commands.
+  RcptTo,
+  /// Reject the `DATA` command.
+  Data,
+  /// Reject the message after its data have been transferred.
+  Message,
+}
+
+
+/// An email as received by an [`SmtpServer`].
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct Received {
+  /// The user that authenticated, if any.
+  pub user: Option<String>,
+  /// Whether the email was transferred over a TLS protected
+  /// connection.
+  pub tls: bool,
+  /// The envelope sender.
+  pub from: String,
+  /// The envelope recipients.
+  pub to: Vec<String>,
+  /// The message data, with dot-stuffing reverted.
+  pub message: Vec<u8>,
+}
+
+impl Received {
+  /// Retrieve the message as a string, lossily converting invalid
+  /// UTF-8 sequences.
+  pub fn message_str(&self) -> Cow<'_, str> {
+    String::from_utf8_lossy(&self.message)
+  }
+}
+
+
+#[derive(Debug, Default)]
+struct State {
+  /// The scripted failure, if any.
+  failure: Option<(Phase, u16, String)>,
+  /// The number of connections accepted so far.
+  connections: usize,
+  /// The emails received so far.
+  received: Vec<Received>,
+}
+
+type SharedState = Arc<Mutex<State>>;
+
+
+/// Check whether a failure was scripted for the given phase, returning
+/// the reply to send instead of the regular one if so.
+fn failure(state: &SharedState, phase: Phase) -> Option<String> {
+  let state = state.lock().unwrap();
+  match &state.failure {
+    Some((p, code, text)) if *p == phase => Some(format!("{code} {text}")),
+    _ => None,
+  }
+}
+
+
+/// Extract the address from a `MAIL FROM:<...>` or `RCPT TO:<...>`
+/// command argument.
+fn parse_path(arg: &str) -> String {
+  let start = arg.find('<').map(|idx| idx + 1).unwrap_or(0);
+  let end = arg[start..]
+    .find('>')
+    .map(|idx| start + idx)
+    .unwrap_or(arg.len());
+  arg[start..end].to_string()
+}
+
+
+/// The per-connection state of an SMTP session.
+#[derive(Debug, Default)]
+struct Session {
+  user: Option<String>,
+  from: Option<String>,
+  to: Vec<String>,
+}
+
+
+async fn reply<S>(stream: &mut BufStream<S>, reply: &str) -> Result<()>
+where
+  S: AsyncRead + AsyncWrite + Unpin,
+{
+  let () = stream.write_all(reply.as_bytes()).await?;
+  let () = stream.write_all(b"\r\n").await?;
+  let () = stream.flush().await?;
+  Ok(())
+}
+
+async fn read_line<S>(stream: &mut BufStream<S>) -> Result<Option<String>>
+where
+  S: AsyncRead + AsyncWrite + Unpin,
+{
+  let mut line = String::new();
+  let count = stream.read_line(&mut line).await?;
+  if count == 0 {
+    Ok(None)
+  } else {
+    Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+  }
+}
+
+async fn read_data<S>(stream: &mut BufStream<S>) -> Result<Vec<u8>>
+where
+  S: AsyncRead + AsyncWrite + Unpin,
+{
+  let mut data = Vec::new();
+  loop {
+    let mut line = Vec::new();
+    let count = stream.read_until(b'\n', &mut line).await?;
+    if count == 0 || line == b".\r\n" {
+      break Ok(data)
+    }
+    let line = line.strip_prefix(b".").unwrap_or(&line);
+    let () = data.extend_from_slice(line);
+  }
+}
+
+
+/// Run an SMTP session over `stream`.
+///
+/// If the client requests an upgrade via `STARTTLS`, the stream is
+/// handed back to the caller for it to perform the upgrade.
+async fn session<S>(
+  stream: S,
+  state: &SharedState,
+  security: Security,
+  tls: bool,
+) -> Result<Option<S>>
+where
+  S: AsyncRead + AsyncWrite + Unpin,
+{
+  let mut stream = BufStream::new(stream);
+  let mut session = Session::default();
+
+  // After an upgrade via STARTTLS no new greeting is sent.
+  if !(tls && security == Security::StartTls) {
+    if let Some(err) = failure(state, Phase::Greeting) {
+      let () = reply(&mut stream, &err).await?;
+      return Ok(None)
+    }
+    let () = reply(&mut stream, "220 localhost ESMTP maily testing server").await?;
+  }
+
+  while let Some(line) = read_line(&mut stream).await? {
+    let (verb, arg) = line.split_once(' ').unwrap_or((&line, ""));
+    let verb = verb.to_ascii_uppercase();
+
+    match verb.as_str() {
+      "EHLO" | "HELO" => {
+        if let Some(err) = failure(state, Phase::Ehlo) {
+          let () = reply(&mut stream, &err).await?;
+          continue
+        }
+        let mut response = "250-localhost\r\n".to_string();
+        if security == Security::StartTls && !tls {
+          let () = response.push_str("250-STARTTLS\r\n");
+        }
+        let () = response.push_str("250-AUTH PLAIN\r\n250 8BITMIME");
+        let () = reply(&mut stream, &response).await?;
+      },
+      "STARTTLS" if security == Security::StartTls && !tls => {
+        if let Some(err) = failure(state, Phase::StartTls) {
+          let () = reply(&mut stream, &err).await?;
+          continue
+        }
+        let () = reply(&mut stream, "220 ready to start TLS").await?;
+        return Ok(Some(stream.into_inner()))
+      },
+      "AUTH" => {
+        let (mechanism, response) = arg.split_once(' ').unwrap_or((arg, ""));
+        if !mechanism.eq_ignore_ascii_case("PLAIN") {
+          let () = reply(&mut stream, "504 unsupported authentication mechanism").await?;
+          continue
+        }
+
+        let response = if response.is_empty() {
+          let () = reply(&mut stream, "334 ").await?;
+          read_line(&mut stream).await?.unwrap_or_default()
+        } else {
+          response.to_string()
+        };
+
+        if let Some(err) = failure(state, Phase::Auth) {
+          let () = reply(&mut stream, &err).await?;
+          continue
+        }
+
+        // The response has the form <authzid>\0<authcid>\0<password>.
+        let decoded = decode_base64(&response);
+        let user = decoded
+          .split(|byte| *byte == 0)
+          .nth(1)
+          .map(|user| String::from_utf8_lossy(user).to_string());
+        session.user = user;
+        let () = reply(&mut stream, "235 authentication successful").await?;
+      },
+      "MAIL" => {
+        if let Some(err) = failure(state, Phase::MailFrom) {
+          let () = reply(&mut stream, &err).await?;
+          continue
+        }
+        session.from = Some(parse_path(arg));
+        session.to.clear();
+        let () = reply(&mut stream, "250 ok").await?;
+      },
+      "RCPT" => {
+        if let Some(err) = failure(state, Phase::RcptTo) {
+          let () = reply(&mut stream, &err).await?;
+          continue
+        }
+        let () = session.to.push(parse_path(arg));
+        let () = reply(&mut stream, "250 ok").await?;
+      },
+      "DATA" => {
+        if let Some(err) = failure(state, Phase::Data) {
+          let () = reply(&mut stream, &err).await?;
+          continue
+        }
+        if session.from.is_none() || session.to.is_empty() {
+          let () = reply(&mut stream, "503 bad sequence of commands").await?;
+          continue
+        }
+
+        let () = reply(&mut stream, "354 end data with <CR><LF>.<CR><LF>").await?;
+        let message = read_data(&mut stream).await?;
+
+        if let Some(err) = failure(state, Phase::Message) {
+          let () = reply(&mut stream, &err).await?;
+          continue
+        }
+
+        let received = Received {
+          user: session.user.clone(),
+          tls,
+          // SANITY: We checked above that a sender is set.
+          from: session.from.take().unwrap(),
+          to: take(&mut session.to),
+          message,
+        };
+        let () = state.lock().unwrap().received.push(received);
+        let () = reply(&mut stream, "250 ok: queued").await?;
+      },
+      "RSET" => {
+        session.from = None;
+        session.to.clear();
+        let () = reply(&mut stream, "250 ok").await?;
+      },
+      "NOOP" => {
+        let () = reply(&mut stream, "250 ok").await?;
+      },
+      "QUIT" => {
+        let () = reply(&mut stream, "221 bye").await?;
+        break
+      },
+      _ => {
+        let () = reply(&mut stream, "502 command not implemented").await?;
+      },
+    }
+  }
+  Ok(None)
+}
+
+
+async fn handle(
+  stream: TcpStream,
+  state: SharedState,
+  security: Security,
+  acceptor: Option<TlsAcceptor>,
+) -> Result<()> {
+  match (security, acceptor) {
+    (Security::Tls, Some(acceptor)) => {
+      let stream = acceptor.accept(stream).await?;
+      let _stream = session(stream, &state, security, true).await?;
+    },
+    (Security::StartTls, Some(acceptor)) => {
+      if let Some(stream) = session(stream, &state, security, false).await? {
+        let stream = acceptor.accept(stream).await?;
+        let _stream = session(stream, &state, security, true).await?;
+      }
+    },
+    _ => {
+      let _stream = session(stream, &state, security, false).await?;
+    },
+  }
+  Ok(())
+}
+
+
+/// An in-process fake SMTP server listening on the loopback interface.
+///
+/// The server accepts any credentials and records all emails it
+/// receives, which can be retrieved via [`SmtpServer::received`].
+/// Using [`SmtpServer::fail`], it can be instructed to reject
+/// operations at a given phase of the SMTP session.
+///
+/// For servers using TLS, a self-signed certificate for `localhost` is
+/// generated, which clients need to trust in order to connect. Accounts
+/// created via [`SmtpServer::account`] take care of that.
+#[derive(Debug)]
+pub struct SmtpServer {
+  addr: SocketAddr,
+  security: Security,
+  state: SharedState,
+  ca_file: Option<PathBuf>,
+  task: JoinHandle<()>,
+}
+
+impl SmtpServer {
+  /// Start a new server offering the given transport security.
+  ///
+  /// The server runs on the current `tokio` runtime until it is
+  /// dropped.
+  pub async fn start(security: Security) -> Result<Self> {
+    let listener = TcpListener::bind("127.0.0.1:0")
+      .await
+      .context("failed to bind SMTP server socket")?;
+    let addr = listener
+      .local_addr()
+      .context("failed to query SMTP server address")?;
+
+    let (acceptor, ca_file) = if security == Security::Plain {
+      (None, None)
+    } else {
+      let key = KeyPair::generate().context("failed to generate TLS key")?;
+      let cert = CertificateParams::new(["localhost".to_string(), "127.0.0.1".to_string()])
+        .context("failed to create certificate parameters")?
+        .self_signed(&key)
+        .context("failed to create self-signed certificate")?;
+      let cert = cert.pem();
+
+      let identity = Identity::from_pkcs8(cert.as_bytes(), key.serialize_pem().as_bytes())
+        .context("failed to create TLS identity")?;
+      let acceptor = NativeTlsAcceptor::new(identity).context("failed to create TLS acceptor")?;
+
+      let ca_file = write_ca_file(&cert)?;
+
+      (Some(TlsAcceptor::from(acceptor)), Some(ca_file))
+    };
+
+    let state = SharedState::default();
+    let task = spawn({
+      let state = state.clone();
+      async move {
+        while let Ok((stream, _addr)) = listener.accept().await {
+          let connect_failure = {
+            let mut state = state.lock().unwrap();
+            state.connections += 1;
+            matches!(state.failure, Some((Phase::Connect, ..)))
+          };
+
+          if connect_failure {
+            drop(stream);
+            continue
+          }
+
+          let state = state.clone();
+          let acceptor = acceptor.clone();
+          // Errors are the client's business, e.g., it may have
+          // disconnected because of a failure we injected.
+          let _handle = spawn(handle(stream, state, security, acceptor));
+        }
+      }
+    });
+
+    let slf = Self {
+      addr,
+      security,
+      state,
+      ca_file,
+      task,
+    };
+    Ok(slf)
+  }
+
+  /// Retrieve the address the server is listening on.
+  #[inline]
+  pub fn addr(&self) -> SocketAddr {
+    self.addr
+  }
+
+  /// Retrieve the path to a file containing the server's PEM encoded
+  /// certificate, if it uses TLS.
+  #[inline]
+  pub fn ca_file(&self) -> Option<&Path> {
+    self.ca_file.as_deref()
+  }
+
+  /// Create an [`Account`] for sending emails via this server.
+  pub fn account(&self, from: &str) -> Account<'static> {
+    let smtp_mode = match self.security {
+      Security::Plain => SmtpMode::Unencrypted,
+      Security::StartTls => SmtpMode::StartTls,
+      Security::Tls => SmtpMode::Tls,
+    };
+
+    Account::Smtp(SmtpAccount {
+      smtp_host: Cow::Borrowed("localhost"),
+      smtp_mode,
+      smtp_port: Some(self.addr.port()),
+      smtp_ca_file: self.ca_file.clone().map(Cow::Owned),
+      from: Cow::Owned(from.to_string()),
+      user: Cow::Borrowed(USER),
+      password: Cow::Borrowed(PASSWORD),
+    })
+  }
+
+  /// Instruct the server to fail at the given phase of all subsequent
+  /// SMTP sessions, replying with the provided code and text.
+  ///
+  /// For [`Phase::Connect`] the code and text are ignored.
+  pub fn fail(&self, phase: Phase, code: u16, text: &str) {
+    self.state.lock().unwrap().failure = Some((phase, code, text.to_string()));
+  }
+
+  /// Instruct the server to no longer fail any operations.
+  pub fn succeed(&self) {
+    self.state.lock().unwrap().failure = None;
+  }
+
+  /// Retrieve the number of connections accepted so far.
+  pub fn connections(&self) -> usize {
+    self.state.lock().unwrap().connections
+  }
+
+  /// Retrieve all emails received so far.
+  pub fn received(&self) -> Vec<Received> {
+    self.state.lock().unwrap().received.clone()
+  }
+}
+
+impl Drop for SmtpServer {
+  fn drop(&mut self) {
+    let () = self.task.abort();
+    if let Some(ca_file) = &self.ca_file {
+      let _result = remove_file(ca_file);
+    }
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use crate::send_email;
+  use crate::EmailOpts;
+
+
+  /// Check that we can send emails to our server in all supported
+  /// security modes.
+  #[tokio::test]
+  async fn roundtrip() {
+    for security in [Security::Plain, Security::StartTls, Security::Tls] {
+      let server = SmtpServer::start(security).await.unwrap();
+      let account = server.account("sender@example.com");
+
+      let () = send_email(
+        [&account],
+        "subject",
+        b"body",
+        None,
+        ["rcpt@example.com"],
+        &EmailOpts::default(),
+      )
+      .await
+      .unwrap();
+
+      let received = server.received();
+      assert_eq!(received.len(), 1, "{security:?}");
+      let received = &received[0];
+      assert_eq!(received.user.as_deref(), Some(USER));
+      assert_eq!(received.tls, security != Security::Plain);
+      assert_eq!(received.from, "sender@example.com");
+      assert_eq!(received.to, ["rcpt@example.com"]);
+      assert!(received.message_str().contains("Subject: subject\r\n"));
+    }
+  }
+
+  /// Check that scripted failures are reported to the client.
+  #[tokio::test]
+  async fn scripted_failure() {
+    let server = SmtpServer::start(Security::Plain).await.unwrap();
+    let account = server.account("sender@example.com");
+    let () = server.fail(Phase::Message, 554, "rejected for testing");
+
+    let err = send_email(
+      [&account],
+      "subject",
+      b"body",
+      None,
+      ["rcpt@example.com"],
+      &EmailOpts::default(),
+    )
+    .await
+    .unwrap_err();
+    assert!(format!("{err:?}").contains("rejected for testing"), "{err:?}");
+    assert!(server.received().is_empty());
+    assert_eq!(server.connections(), 1);
+  }
+}
@@ -0,0 +1,129 @@
+// Copyright (C) 2024 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::fs::read_to_string;
+
+
+/// Retrieve the name of the local host.
+///
+/// If the name cannot be determined, `localhost` is reported instead.
+pub(crate) fn hostname() -> String {
+  ["/proc/sys/kernel/hostname", "/etc/hostname"]
+    .into_iter()
+    .find_map(|path| {
+      let name = read_to_string(path).ok()?;
+      let name = name.trim();
+      (!name.is_empty()).then(|| name.to_string())
+    })
+    .unwrap_or_else(|| "localhost".to_string())
+}
+
+
+/// The alphabet of standard base64 encoding.
+#[cfg(any(feature = "queue", feature = "submit"))]
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+
+/// Encode data as standard, padded base64.
+#[cfg(any(feature = "queue", feature = "submit"))]
+pub(crate) fn encode_base64(data: &[u8]) -> String {
+  let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+  for chunk in data.chunks(3) {
+    let buffer = chunk
+      .iter()
+      .enumerate()
+      .fold(0u32, |buffer, (i, byte)| buffer | u32::from(*byte) << (16 - 8 * i));
+
+    for i in 0..4 {
+      if i <= chunk.len() {
+        let index = (buffer >> (18 - 6 * i)) & 0x3f;
+        let () = encoded.push(char::from(BASE64[index as usize]));
+      } else {
+        let () = encoded.push('=');
+      }
+    }
+  }
+  encoded
+}
+
+
+/// Decode standard base64 data, ignoring any invalid characters.
+#[cfg(any(feature = "queue", feature = "submit", feature = "testing"))]
+pub(crate) fn decode_base64(data: &str) -> Vec<u8> {
+  let mut decoded = Vec::with_capacity(data.len() * 3 / 4);
+  let mut buffer = 0u32;
+  let mut bits = 0;
+
+  for byte in data.bytes() {
+    let value = match byte {
+      b'A'..=b'Z' => byte - b'A',
+      b'a'..=b'z' => byte - b'a' + 26,
+      b'0'..=b'9' => byte - b'0' + 52,
+      b'+' => 62,
+      b'/' => 63,
+      _ => continue,
+    };
+    buffer = buffer << 6 | u32::from(value);
+    bits += 6;
+    if bits >= 8 {
+      bits -= 8;
+      let () = decoded.push((buffer >> bits) as u8);
+    }
+  }
+  decoded
+}
+
+
+/// `serde` support for (de)serializing binary data as base64 strings.
+#[cfg(any(feature = "queue", feature = "submit"))]
+pub(crate) mod base64 {
+  use std::borrow::Cow;
+
+  use serde::Deserialize as _;
+  use serde::Deserializer;
+  use serde::Serializer;
+
+  use super::decode_base64;
+  use super::encode_base64;
+
+
+  pub(crate) fn serialize<S>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error>
+  where
+    S: Serializer,
+  {
+    serializer.serialize_str(&encode_base64(data))
+  }
+
+  pub(crate) fn deserialize<'de, 'a, D>(deserializer: D) -> Result<Cow<'a, [u8]>, D::Error>
+  where
+    D: Deserializer<'de>,
+  {
+    let data = Cow::<str>::deserialize(deserializer)?;
+    Ok(Cow::Owned(decode_base64(&data)))
+  }
+}
+
+
+#[cfg(all(test, any(feature = "queue", feature = "submit", feature = "testing")))]
+mod tests {
+  use super::*;
+
+
+  /// Check that we can encode data as base64.
+  #[cfg(any(feature = "queue", feature = "submit"))]
+  #[test]
+  fn base64_encoding() {
+    assert_eq!(encode_base64(b""), "");
+    assert_eq!(encode_base64(b"f"), "Zg==");
+    assert_eq!(encode_base64(b"fo"), "Zm8=");
+    assert_eq!(encode_base64(b"foo"), "Zm9v");
+    assert_eq!(encode_base64(b"\0user\0password"), "AHVzZXIAcGFzc3dvcmQ=");
+  }
+
+  /// Check that we can decode base64 data.
+  #[test]
+  fn base64_decoding() {
+    assert_eq!(decode_base64("AHVzZXIAcGFzc3dvcmQ="), b"\0user\0password");
+    assert_eq!(decode_base64("Zm9vYg=="), b"foob");
+  }
+}
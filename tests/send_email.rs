@@ -0,0 +1,799 @@
+// Copyright (C) 2024 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! End-to-end tests of email sending against local SMTP servers.
+
+use std::borrow::Cow;
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use maily::check_account;
+use maily::preview_email;
+use maily::preview_raw_email;
+use maily::send_email;
+use maily::send_raw_email;
+use maily::testing::Phase;
+use maily::testing::Security;
+use maily::testing::SmtpServer;
+use maily::Account;
+use maily::Attachment;
+use maily::EmailOpts;
+use maily::ErrorNotification;
+use maily::Errors;
+use maily::FallbackSink;
+use maily::MxAccount;
+use maily::NotifyWhen;
+use maily::PartialDelivery;
+use maily::SmtpAccount;
+use maily::SmtpMode;
+
+use tokio::io::AsyncBufReadExt as _;
+use tokio::io::AsyncWriteExt as _;
+use tokio::io::BufStream;
+use tokio::net::TcpListener;
+use tokio::net::UdpSocket;
+use tokio::spawn;
+use tokio::test;
+
+
+const FROM: &str = "sender@example.com";
+const TO: &str = "rcpt@example.com";
+
+
+/// Create an account for a port on which nobody is listening, so that
+/// connection attempts are refused.
+async fn refusing_account() -> Account<'static> {
+  let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let port = listener.local_addr().unwrap().port();
+  drop(listener);
+
+  Account::Smtp(SmtpAccount {
+    smtp_host: Cow::Borrowed("127.0.0.1"),
+    smtp_mode: SmtpMode::Unencrypted,
+    smtp_port: Some(port),
+    smtp_ca_file: None,
+    from: Cow::Borrowed(FROM),
+    user: Cow::Borrowed("user"),
+    password: Cow::Borrowed("password"),
+  })
+}
+
+
+/// Start a minimal LMTP server that rejects messages for `rejected`
+/// after their data have been transferred, returning an account for it
+/// as well as the number of sessions it has seen.
+async fn lmtp_server(rejected: &'static str) -> (Account<'static>, Arc<AtomicUsize>) {
+  let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let port = listener.local_addr().unwrap().port();
+  let sessions = Arc::new(AtomicUsize::new(0));
+
+  let _handle = spawn({
+    let sessions = sessions.clone();
+    async move {
+      while let Ok((stream, _addr)) = listener.accept().await {
+        let _count = sessions.fetch_add(1, Ordering::Relaxed);
+        let mut stream = BufStream::new(stream);
+        let mut recipients = Vec::new();
+        let mut reply = String::from("220 lmtp ready\r\n");
+
+        loop {
+          let () = stream.write_all(reply.as_bytes()).await.unwrap();
+          let () = stream.flush().await.unwrap();
+
+          let mut line = String::new();
+          let _count = stream.read_line(&mut line).await.unwrap();
+          reply = match line.get(..4).unwrap_or_default() {
+            "RCPT" => {
+              let () = recipients.push(line.contains(rejected));
+              "250 ok\r\n".to_string()
+            },
+            "DATA" => {
+              let () = stream.write_all(b"354 go ahead\r\n").await.unwrap();
+              let () = stream.flush().await.unwrap();
+              while line != ".\r\n" {
+                let () = line.clear();
+                let _count = stream.read_line(&mut line).await.unwrap();
+              }
+              recipients
+                .drain(..)
+                .map(|rejected| {
+                  if rejected {
+                    "550 no such user\r\n"
+                  } else {
+                    "250 ok\r\n"
+                  }
+                })
+                .collect()
+            },
+            "QUIT" | "" => break,
+            _ => "250 ok\r\n".to_string(),
+          };
+        }
+      }
+    }
+  });
+
+  let account = Account::Smtp(SmtpAccount {
+    smtp_host: Cow::Borrowed("127.0.0.1"),
+    smtp_mode: SmtpMode::Lmtp,
+    smtp_port: Some(port),
+    smtp_ca_file: None,
+    from: Cow::Borrowed(FROM),
+    user: Cow::Borrowed(""),
+    password: Cow::Borrowed(""),
+  });
+  (account, sessions)
+}
+
+
+/// Start a minimal DNS server answering MX queries for the domains in
+/// `records` with the associated mail exchanger, an empty one denoting
+/// a "null MX" record. Queries for other domains are answered with
+/// NXDOMAIN.
+async fn dns_server(records: &'static [(&'static str, &'static str)]) -> SocketAddr {
+  let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+  let addr = socket.local_addr().unwrap();
+
+  let _handle = spawn(async move {
+    let mut buffer = [0; 512];
+    while let Ok((count, peer)) = socket.recv_from(&mut buffer).await {
+      let query = &buffer[..count];
+      let mut labels = Vec::new();
+      let mut offset = 12;
+      while query[offset] != 0 {
+        let len = usize::from(query[offset]);
+        let () = labels.push(String::from_utf8_lossy(&query[offset + 1..offset + 1 + len]));
+        offset += 1 + len;
+      }
+      let domain = labels.join(".");
+
+      let mut response = query.to_vec();
+      match records.iter().find(|(name, _exchange)| *name == domain) {
+        Some((_name, exchange)) => {
+          let mut rdata = vec![0, 10];
+          for label in exchange.split('.').filter(|label| !label.is_empty()) {
+            let () = rdata.push(label.len() as u8);
+            let () = rdata.extend_from_slice(label.as_bytes());
+          }
+          let () = rdata.push(0);
+
+          // Flags: response, recursion available; one answer.
+          response[2..4].copy_from_slice(&0x8180u16.to_be_bytes());
+          response[6..8].copy_from_slice(&1u16.to_be_bytes());
+          // Pointer to question name, MX, IN, TTL, length.
+          response.extend_from_slice(&[0xc0, 12, 0, 15, 0, 1, 0, 0, 1, 0]);
+          response.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+          response.extend_from_slice(&rdata);
+        },
+        None => response[2..4].copy_from_slice(&0x8183u16.to_be_bytes()),
+      }
+      let _count = socket.send_to(&response, peer).await.unwrap();
+    }
+  });
+  addr
+}
+
+
+/// Check that when all accounts fail, each of them is tried and the
+/// errors of all of them are reported.
+#[test]
+async fn all_accounts_failing() {
+  let refusing = refusing_account().await;
+  let auth = SmtpServer::start(Security::StartTls).await.unwrap();
+  let () = auth.fail(Phase::Auth, 535, "authentication credentials invalid");
+  let data = SmtpServer::start(Security::Tls).await.unwrap();
+  let () = data.fail(Phase::Data, 451, "try again later");
+  let message = SmtpServer::start(Security::Plain).await.unwrap();
+  let () = message.fail(Phase::Message, 554, "message rejected");
+
+  let accounts = [
+    refusing,
+    auth.account(FROM),
+    data.account(FROM),
+    message.account(FROM),
+  ];
+  let err = send_email(
+    accounts.iter(),
+    "subject",
+    b"body",
+    None,
+    [TO],
+    &EmailOpts::default(),
+  )
+  .await
+  .unwrap_err();
+
+  let errors = err.downcast_ref::<Errors>().unwrap();
+  assert_eq!(errors.errors().len(), accounts.len());
+
+  let err = format!("{err:?}");
+  assert!(err.contains("127.0.0.1"), "{err}");
+  assert!(err.contains("authentication credentials invalid"), "{err}");
+  assert!(err.contains("try again later"), "{err}");
+  assert!(err.contains("message rejected"), "{err}");
+
+  for server in [&auth, &data, &message] {
+    assert!(server.connections() >= 1);
+    assert!(server.received().is_empty());
+  }
+}
+
+
+/// Check that we fail over to a working account and inform recipients
+/// about the failure of the previous one.
+#[test]
+async fn failover_with_error_notification() {
+  // Accounts are tried in random order, so we repeat the attempt until
+  // the failing account happened to be tried first, at which point we
+  // expect an error notification to be sent.
+  for _ in 0..64 {
+    let failing = SmtpServer::start(Security::Plain).await.unwrap();
+    let () = failing.fail(Phase::Message, 554, "message rejected");
+    let working = SmtpServer::start(Security::Plain).await.unwrap();
+
+    let accounts = [failing.account(FROM), working.account(FROM)];
+    let () = send_email(
+      accounts.iter(),
+      "subject",
+      b"body",
+      None,
+      [TO],
+      &EmailOpts::default(),
+    )
+    .await
+    .unwrap();
+
+    let received = working.received();
+    let original = received.last().unwrap();
+    assert!(original.message_str().contains("Subject: subject\r\n"));
+    assert!(original.message_str().ends_with("\r\nbody\r\n"));
+    assert_eq!(original.to, [TO]);
+
+    match received.as_slice() {
+      [_original] => continue,
+      [notification, _original] => {
+        let notification = notification.message_str();
+        assert!(notification.contains("Subject: email error\r\n"));
+        assert!(notification.contains("message rejected"), "{notification}");
+        return
+      },
+      received => panic!("received unexpected number of emails: {received:#?}"),
+    }
+  }
+  panic!("failing account was never tried first");
+}
+
+
+/// Check that error notifications can be disabled.
+#[test]
+async fn disabled_error_notification() {
+  let opts = EmailOpts {
+    error_notification: ErrorNotification {
+      enabled: false,
+      ..Default::default()
+    },
+    ..Default::default()
+  };
+
+  for _ in 0..16 {
+    let failing = SmtpServer::start(Security::Plain).await.unwrap();
+    let () = failing.fail(Phase::RcptTo, 550, "no such user");
+    let working = SmtpServer::start(Security::Plain).await.unwrap();
+
+    let accounts = [failing.account(FROM), working.account(FROM)];
+    let () = send_email(accounts.iter(), "subject", b"body", None, [TO], &opts)
+      .await
+      .unwrap();
+
+    let received = working.received();
+    assert_eq!(received.len(), 1);
+    assert!(received[0].message_str().contains("Subject: subject\r\n"));
+  }
+}
+
+
+/// Check that error notifications can be sent to dedicated recipients
+/// once the original email got sent.
+#[test]
+async fn customized_error_notification() {
+  let opts = EmailOpts {
+    error_notification: ErrorNotification {
+      recipients: vec![Cow::Borrowed("admin@example.com")],
+      subject: Cow::Borrowed("delivery problem"),
+      include_subject: true,
+      body: Cow::Borrowed("Sending '{subject}' failed:\n{error}"),
+      when: NotifyWhen::OnSuccess,
+      ..Default::default()
+    },
+    ..Default::default()
+  };
+
+  for _ in 0..64 {
+    let failing = SmtpServer::start(Security::Plain).await.unwrap();
+    let () = failing.fail(Phase::MailFrom, 451, "temporary failure");
+    let working = SmtpServer::start(Security::Plain).await.unwrap();
+
+    let accounts = [failing.account(FROM), working.account(FROM)];
+    let () = send_email(accounts.iter(), "report", b"body", None, [TO], &opts)
+      .await
+      .unwrap();
+
+    match working.received().as_slice() {
+      [_original] => continue,
+      [original, notification] => {
+        assert_eq!(original.to, [TO]);
+        assert_eq!(notification.to, ["admin@example.com"]);
+
+        let notification = notification.message_str();
+        assert!(notification.contains("Subject: delivery problem: report\r\n"));
+        assert!(notification.contains("Sending 'report' failed:\r\n"));
+        assert!(notification.contains("temporary failure"), "{notification}");
+        return
+      },
+      received => panic!("received unexpected number of emails: {received:#?}"),
+    }
+  }
+  panic!("failing account was never tried first");
+}
+
+
+/// Check that an error notification is sent when all accounts failed
+/// sending the original email, if so desired.
+#[test]
+async fn error_notification_on_failure() {
+  let opts = EmailOpts {
+    error_notification: ErrorNotification {
+      recipients: vec![Cow::Borrowed("admin@example.com")],
+      when: NotifyWhen::OnFailure,
+      ..Default::default()
+    },
+    ..Default::default()
+  };
+
+  let server = SmtpServer::start(Security::Plain).await.unwrap();
+  let () = server.fail(Phase::MailFrom, 451, "temporary failure");
+  let account = server.account(FROM);
+  let _err = send_email([&account], "report", b"body", None, [TO], &opts)
+    .await
+    .unwrap_err();
+  assert!(server.received().is_empty());
+
+  let () = server.succeed();
+  let () = send_email([&account], "report", b"body", None, [TO], &opts)
+    .await
+    .unwrap();
+  // Only the original email should have been sent this time around.
+  assert_eq!(server.received().len(), 1);
+}
+
+
+/// Check that if an email got delivered to some of its recipients only,
+/// only the remaining ones are retried with other accounts.
+#[test]
+async fn partial_delivery_failover() {
+  let opts = EmailOpts {
+    error_notification: ErrorNotification {
+      enabled: false,
+      ..Default::default()
+    },
+    ..Default::default()
+  };
+  let recipients = ["a@example.com", "b@example.com"];
+
+  for _ in 0..64 {
+    let (lmtp, sessions) = lmtp_server("b@example.com").await;
+    let server = SmtpServer::start(Security::Plain).await.unwrap();
+
+    let accounts = [lmtp, server.account(FROM)];
+    let () = send_email(accounts.iter(), "subject", b"body", None, recipients, &opts)
+      .await
+      .unwrap();
+
+    let received = server.received();
+    assert_eq!(received.len(), 1);
+    if sessions.load(Ordering::Relaxed) == 0 {
+      assert_eq!(received[0].to, recipients);
+      continue
+    }
+
+    assert_eq!(received[0].to, ["b@example.com"]);
+    assert!(received[0].message_str().contains("To: a@example.com, b@example.com\r\n"));
+    return
+  }
+  panic!("LMTP account was never tried first");
+}
+
+
+/// Check that emails are delivered directly to the mail exchangers of
+/// the recipients' domains, as reported by a DNS server.
+#[test]
+async fn mx_delivery() {
+  let resolver = dns_server(&[("example.com", "localhost"), ("example.net", "")]).await;
+  let server = SmtpServer::start(Security::Plain).await.unwrap();
+  let account = Account::Mx(MxAccount {
+    mx_resolver: Cow::Owned(resolver.to_string()),
+    mx_port: Some(server.addr().port()),
+    from: Cow::Borrowed(FROM),
+  });
+  let opts = EmailOpts {
+    error_notification: ErrorNotification {
+      enabled: false,
+      ..Default::default()
+    },
+    ..Default::default()
+  };
+
+  let () = send_email([&account], "subject", b"body", None, [TO], &opts)
+    .await
+    .unwrap();
+  assert_eq!(server.received()[0].to, [TO]);
+
+  let err = send_email([&account], "subject", b"body", None, ["a@example.org"], &opts)
+    .await
+    .unwrap_err();
+  let err = format!("{err:#}");
+  assert!(err.contains("domain `example.org` does not exist"), "{err}");
+
+  let err = send_email([&account], "subject", b"body", None, ["a@example.net"], &opts)
+    .await
+    .unwrap_err();
+  let err = format!("{err:#}");
+  assert!(err.contains("domain `example.net` does not accept email"), "{err}");
+
+  let recipients = ["a@example.net", "b@example.com"];
+  let err = send_email([&account], "subject", b"body", None, recipients, &opts)
+    .await
+    .unwrap_err();
+  let partial = err.downcast_ref::<PartialDelivery>().unwrap();
+  assert_eq!(partial.delivered(), ["b@example.com".parse().unwrap()]);
+  assert_eq!(partial.failed(), ["a@example.net".parse().unwrap()]);
+
+  let received = server.received();
+  assert_eq!(received.len(), 2);
+  assert_eq!(received[1].to, ["b@example.com"]);
+}
+
+
+/// Check that emails that could not be sent are stored in the first
+/// working fallback sink.
+#[test]
+async fn fallback_sinks() {
+  use std::fs::read_to_string;
+
+  use tempfile::tempdir;
+
+  let dir = tempdir().unwrap();
+  let file = dir.path().join("file");
+  let mbox = dir.path().join("mbox");
+  let opts = EmailOpts {
+    error_notification: ErrorNotification {
+      enabled: false,
+      ..Default::default()
+    },
+    fallback: vec![
+      FallbackSink::File(Cow::Owned(dir.path().join("missing").join("file"))),
+      FallbackSink::Mbox(Cow::Borrowed(&mbox)),
+      FallbackSink::File(Cow::Borrowed(&file)),
+    ],
+    ..Default::default()
+  };
+
+  let account = refusing_account().await;
+  let err = send_email([&account], "lost", b"body", None, [TO], &opts)
+    .await
+    .unwrap_err();
+  // The email was stored but still not sent, so we should see the
+  // original error reported.
+  assert!(format!("{err:?}").contains("127.0.0.1"), "{err:?}");
+
+  let content = read_to_string(&mbox).unwrap();
+  assert!(content.starts_with(&format!("From {FROM} ")), "{content}");
+  assert!(content.contains("Subject: lost\n"), "{content}");
+  assert!(!file.exists());
+
+  let opts = EmailOpts {
+    fallback: vec![FallbackSink::File(Cow::Owned(
+      dir.path().join("missing").join("file"),
+    ))],
+    ..opts
+  };
+  let err = send_email([&account], "lost", b"body", None, [TO], &opts)
+    .await
+    .unwrap_err();
+  let err = format!("{err:?}");
+  assert!(err.contains("failed to store email in fallback sink"), "{err}");
+}
+
+
+/// Check that emails that could not be sent are queued and can be
+/// sent later on.
+#[cfg(feature = "queue")]
+#[test]
+async fn queue_and_flush() {
+  use maily::Queue;
+
+  use tempfile::tempdir;
+
+  let dir = tempdir().unwrap();
+  let opts = EmailOpts {
+    error_notification: ErrorNotification {
+      enabled: false,
+      ..Default::default()
+    },
+    queue: Some(Cow::Borrowed(dir.path())),
+    ..Default::default()
+  };
+
+  let server = SmtpServer::start(Security::Plain).await.unwrap();
+  let () = server.fail(Phase::Connect, 421, "service not available");
+  let accounts = [server.account(FROM)];
+  // Queuing counts as success.
+  let () = send_email(accounts.iter(), "later", b"body", None, [TO], &opts)
+    .await
+    .unwrap();
+
+  let queue = Queue::new(dir.path());
+  let emails = queue.list().await.unwrap();
+  assert_eq!(emails.len(), 1);
+  assert_eq!(emails[0].subject, "later");
+  assert_eq!(emails[0].attempts, 1);
+  assert!(emails[0].last_error.is_some());
+
+  // The email is not yet due, so nothing should happen.
+  let report = queue.flush(&accounts, &opts, false).await.unwrap();
+  assert!(report.sent.is_empty());
+  assert!(report.failed.is_empty());
+
+  let report = queue.flush(&accounts, &opts, true).await.unwrap();
+  assert_eq!(report.failed.len(), 1);
+  assert_eq!(queue.list().await.unwrap()[0].attempts, 2);
+
+  let () = server.succeed();
+  let report = queue.flush(&accounts, &opts, true).await.unwrap();
+  assert_eq!(report.sent, [emails[0].id.clone()]);
+  assert!(queue.list().await.unwrap().is_empty());
+
+  let received = server.received();
+  assert_eq!(received.len(), 1);
+  assert!(received[0].message_str().contains("Subject: later\r\n"));
+}
+
+
+/// Check that bare linefeed line endings are converted into CRLF ones
+/// and that lines starting with a dot are transferred unharmed.
+#[test]
+async fn line_ending_conversion() {
+  let server = SmtpServer::start(Security::Plain).await.unwrap();
+  let account = server.account(FROM);
+
+  let () = send_email(
+    [&account],
+    "subject",
+    b"line 1\nline 2\n.\n..dots\n",
+    None,
+    [TO],
+    &EmailOpts::default(),
+  )
+  .await
+  .unwrap();
+
+  let received = server.received();
+  let message = received[0].message_str();
+  let (_headers, body) = message.split_once("\r\n\r\n").unwrap();
+  assert!(
+    body.starts_with("line 1\r\nline 2\r\n.\r\n..dots\r\n"),
+    "{body:?}"
+  );
+  assert!(
+    !message.replace("\r\n", "").contains('\n'),
+    "{message:?}"
+  );
+}
+
+
+/// Check that attachments are included in emails.
+#[test]
+async fn attachments() {
+  let server = SmtpServer::start(Security::Plain).await.unwrap();
+  let account = server.account(FROM);
+  let opts = EmailOpts {
+    attachments: vec![Attachment {
+      name: Cow::Borrowed("report.csv"),
+      content_type: Some(Cow::Borrowed("text/csv")),
+      data: Cow::Borrowed(b"a,b\n1,2\n"),
+    }],
+    ..Default::default()
+  };
+
+  let () = send_email([&account], "subject", b"see attached", None, [TO], &opts)
+    .await
+    .unwrap();
+
+  let received = server.received();
+  let message = received[0].message_str();
+  assert!(message.contains("Content-Type: multipart/mixed;"), "{message}");
+  assert!(message.contains("see attached"), "{message}");
+  assert!(message.contains("Content-Type: text/csv"), "{message}");
+  assert!(
+    message.contains(r#"Content-Disposition: attachment; filename="report.csv""#),
+    "{message}"
+  );
+}
+
+
+/// Check that complete messages are sent as-is via the account
+/// failover logic.
+#[test]
+async fn raw_message() {
+  let server = SmtpServer::start(Security::Plain).await.unwrap();
+  let accounts = [refusing_account().await, server.account(FROM)];
+  let message = b"From: Tool <tool@example.com>\nTo: rcpt@example.com\n\
+Bcc: hidden@example.com\nSubject: raw\nX-Custom: kept\n\nbody\n";
+  let opts = EmailOpts {
+    error_notification: ErrorNotification {
+      enabled: false,
+      ..Default::default()
+    },
+    ..Default::default()
+  };
+
+  let () = send_raw_email(accounts.iter(), message, &[] as &[&str], &opts)
+    .await
+    .unwrap();
+
+  let received = server.received();
+  assert_eq!(received.len(), 1);
+  assert_eq!(received[0].from, FROM);
+  assert_eq!(received[0].to, [TO, "hidden@example.com"]);
+  assert_eq!(
+    received[0].message_str().trim_end(),
+    "From: Tool <tool@example.com>\r\nTo: rcpt@example.com\r\nSubject: raw\r\n\
+X-Custom: kept\r\n\r\nbody"
+  );
+}
+
+
+/// Check that failures sending complete messages, the recipients of
+/// which are derived from their headers, are reported via error
+/// notifications as well.
+#[test]
+async fn raw_message_error_notification() {
+  let message = b"To: rcpt@example.com\nSubject: raw\n\nbody\n";
+
+  for _ in 0..64 {
+    let failing = SmtpServer::start(Security::Plain).await.unwrap();
+    let () = failing.fail(Phase::Message, 554, "message rejected");
+    let working = SmtpServer::start(Security::Plain).await.unwrap();
+
+    let accounts = [failing.account(FROM), working.account(FROM)];
+    let () = send_raw_email(accounts.iter(), message, &[] as &[&str], &EmailOpts::default())
+      .await
+      .unwrap();
+
+    match working.received().as_slice() {
+      [_original] => continue,
+      [notification, original] => {
+        assert_eq!(notification.to, [TO]);
+        let notification = notification.message_str();
+        assert!(notification.contains("Subject: email error\r\n"));
+        assert!(notification.contains("To: rcpt@example.com\r\n"));
+        assert!(notification.contains("message rejected"), "{notification}");
+        assert!(original.message_str().contains("Subject: raw\r\n"));
+        return
+      },
+      received => panic!("received unexpected number of emails: {received:#?}"),
+    }
+  }
+  panic!("failing account was never tried first");
+}
+
+
+/// Check that previewing an email does not send anything.
+#[test]
+async fn preview() {
+  let server = SmtpServer::start(Security::Plain).await.unwrap();
+  let account = server.account(FROM);
+
+  let email = preview_email(
+    &account,
+    "subject",
+    b"body",
+    None,
+    [TO],
+    &EmailOpts::default(),
+  )
+  .unwrap();
+  let email = String::from_utf8(email).unwrap();
+  assert!(email.contains(&format!("From: {FROM}\r\n")), "{email}");
+  assert!(email.contains(&format!("To: {TO}\r\n")), "{email}");
+  assert!(email.ends_with("\r\n\r\nbody"), "{email}");
+
+  let opts = EmailOpts {
+    subject_prefix: Some(Cow::Borrowed("[oncall]")),
+    ..Default::default()
+  };
+  let email = preview_email(&account, "subject", b"body", None, [TO], &opts).unwrap();
+  let email = String::from_utf8(email).unwrap();
+  assert!(email.contains("Subject: [oncall] subject\r\n"), "{email}");
+
+  let email = preview_raw_email(
+    &account,
+    b"To: rcpt@example.com\nBcc: hidden@example.com\n\nbody\n",
+    &[] as &[&str],
+    &EmailOpts::default(),
+  )
+  .unwrap();
+  assert_eq!(email, b"To: rcpt@example.com\r\n\r\nbody\r\n");
+
+  assert_eq!(server.connections(), 0);
+}
+
+
+/// Check that accounts can be checked without sending anything.
+#[test]
+async fn account_check() {
+  let server = SmtpServer::start(Security::Plain).await.unwrap();
+  let account = server.account(FROM);
+  let () = check_account(&account, [TO]).await.unwrap();
+  assert!(server.connections() > 0);
+  assert!(server.received().is_empty());
+
+  let account = refusing_account().await;
+  let err = check_account(&account, [TO]).await.unwrap_err();
+  assert_eq!(err.to_string(), "failed to connect to 127.0.0.1");
+
+  let err = check_account(&account, ["not an address"]).await.unwrap_err();
+  assert_eq!(
+    err.to_string(),
+    "failed to parse 'To' specification: `not an address`"
+  );
+}
+
+
+/// Check that PGP encrypted emails have the structure mandated by
+/// RFC 3156.
+#[cfg(feature = "pgp")]
+#[test]
+async fn pgp_structure() {
+  use std::fs::write;
+
+  use sequoia_openpgp::cert::CertBuilder;
+  use sequoia_openpgp::serialize::SerializeInto as _;
+
+  use tempfile::NamedTempFile;
+
+  let (cert, _revocation) = CertBuilder::general_purpose(None, Some(format!("Recipient <{TO}>")))
+    .generate()
+    .unwrap();
+  let keybox = NamedTempFile::new().unwrap();
+  let () = write(keybox.path(), cert.armored().to_vec().unwrap()).unwrap();
+
+  let server = SmtpServer::start(Security::Plain).await.unwrap();
+  let account = server.account(FROM);
+  let opts = EmailOpts {
+    pgp_keybox: Some(Cow::Borrowed(keybox.path())),
+    ..Default::default()
+  };
+
+  let () = send_email([&account], "subject", b"secret", None, [TO], &opts)
+    .await
+    .unwrap();
+
+  let received = server.received();
+  let message = received[0].message_str();
+  assert!(
+    message.contains("Content-Type: multipart/encrypted;"),
+    "{message}"
+  );
+  assert!(
+    message.contains(r#"protocol="application/pgp-encrypted""#),
+    "{message}"
+  );
+  assert!(message.contains("Content-Type: application/pgp-encrypted\r\n"));
+  assert!(message.contains("Version: 1"));
+  assert!(message.contains(r#"Content-Type: application/octet-stream; name="encrypted.asc""#));
+  assert!(message.contains("-----BEGIN PGP MESSAGE-----"));
+  assert!(!message.contains("secret"));
+}